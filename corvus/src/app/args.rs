@@ -15,15 +15,46 @@ pub fn parse() -> Result<Options> {
     settings = &[DeriveDisplayOrder, DisableHelpSubcommand, UnifiedHelpMessage]
 )]
 pub struct Options {
-    /// Location of the agent configuration file
-    #[structopt(short = "c", long, parse(from_os_str), default_value = "corvus.toml")]
-    pub config: PathBuf,
+    /// Location of the agent configuration file, may be repeated with later files taking
+    /// precedence
+    #[structopt(
+        short = "c",
+        long,
+        parse(from_os_str),
+        default_value = "corvus.toml",
+        number_of_values = 1
+    )]
+    pub config: Vec<PathBuf>,
 
-    /// Generate configuration with default values
+    /// Generate an annotated configuration with default values
     #[structopt(short, long)]
     pub generate: bool,
 
+    /// Reload the configuration automatically when the file changes
+    #[structopt(short, long)]
+    pub watch: bool,
+
+    /// Print every MQTT publish to stdout instead of connecting to a broker
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Run each plugin a single time and exit, failing if any plugin fails
+    #[structopt(long)]
+    pub once: bool,
+
     /// Verbosity level of output
     #[structopt(short = "v", long, parse(from_occurrences))]
     pub verbosity: u64,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(rename_all = "kebab_case")]
+pub enum Command {
+    /// Validate the configuration file and exit
+    Check,
+    /// Interactively create a configuration file
+    Init,
 }
@@ -0,0 +1,47 @@
+use super::*;
+use crate::plugins::{self, PluginType};
+
+/// Entry point for programs embedding corvus to add their own plugins, see
+/// [`Corvus::builder`]
+#[derive(Debug)]
+pub struct Corvus;
+
+impl Corvus {
+    pub fn builder() -> CorvusBuilder {
+        CorvusBuilder::default()
+    }
+}
+
+/// Registers a plugin type under a name
+type Registration = fn(&str) -> Result<()>;
+
+/// Collects the plugin types of an embedding program before the configuration is loaded
+#[derive(Debug, Default)]
+pub struct CorvusBuilder {
+    plugins: Vec<(String, Registration)>,
+}
+
+impl CorvusBuilder {
+    /// Make `P` available to plugin definitions as `type = "<name>"`, its options are read
+    /// from the rest of the `definition` table
+    pub fn register_plugin<P: PluginType>(mut self, name: &str) -> Self {
+        self.plugins
+            .push((name.to_string(), plugins::register_plugin::<P>));
+        self
+    }
+
+    /// Register the plugin types, for programs parsing a configuration themselves and
+    /// starting it with [`App::from_configuration`]
+    pub fn register(&self) -> Result<()> {
+        for (name, register) in self.plugins.iter() {
+            register(name).with_context(|| format!("Failed to register plugin type '{}'", name))?;
+        }
+        Ok(())
+    }
+
+    /// Run like the `corvus` binary, with the same arguments and subcommands
+    pub async fn run(self) -> Result<()> {
+        self.register()?;
+        App::new().await?.start().await
+    }
+}
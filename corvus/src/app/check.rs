@@ -0,0 +1,44 @@
+use super::*;
+
+/// Validate configuration files, printing each problem found. Returns the process exit code.
+pub fn run(files: &[PathBuf]) -> i32 {
+    let path = files
+        .iter()
+        .map(|f| f.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if let Ok(sources) = ConfigSources::read(files) {
+        if let Ok(config) = sources.resolve() {
+            for plugin in config.plugins.iter() {
+                println!(
+                    "plugin '{}' from {}",
+                    plugin.name,
+                    plugin
+                        .source
+                        .as_ref()
+                        .map(|p| p.to_string_lossy())
+                        .unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    let diagnostics = Configuration::check(files);
+    for d in diagnostics.iter() {
+        eprintln!("{}", d);
+    }
+
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    let warnings = diagnostics.len() - errors;
+    if errors > 0 {
+        eprintln!(
+            "{}: configuration invalid, {} error(s) and {} warning(s)",
+            path, errors, warnings
+        );
+        1
+    } else {
+        println!("{}: configuration OK, {} warning(s)", path, warnings);
+        0
+    }
+}
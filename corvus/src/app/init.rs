@@ -0,0 +1,86 @@
+use super::*;
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+fn prompt(question: &str, default: &str) -> Result<String> {
+    print!("{} [{}]: ", question, default);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() {
+        default.to_string()
+    } else {
+        answer.to_string()
+    })
+}
+
+fn confirm(question: &str, default: bool) -> Result<bool> {
+    let answer = prompt(question, if default { "Y/n" } else { "y/N" })?;
+    Ok(match answer.to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    })
+}
+
+/// List entries of a directory whose name starts with `prefix`
+fn detect(dir: &str, prefix: &str) -> Vec<String> {
+    let mut result: Vec<String> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .filter(|n| n.starts_with(prefix))
+                .collect()
+        })
+        .unwrap_or_default();
+    result.sort();
+    result
+}
+
+/// Ask for the basic settings and write an annotated configuration file
+pub fn run(file: &Path) -> Result<()> {
+    if file.exists() && !confirm(&format!("{} exists, overwrite?", file.display()), false)? {
+        return Ok(());
+    }
+
+    let mut opts = TemplateOptions::default();
+    opts.location = prompt("Location of this agent", &opts.location)?;
+    opts.mqtt.client_id = format!("corvus-{}", clean_name(&opts.location));
+    opts.mqtt.host = prompt("MQTT broker host", &opts.mqtt.host)?;
+    opts.mqtt.port = prompt("MQTT broker port", &opts.mqtt.port.to_string())?
+        .parse()
+        .context("Invalid port")?;
+
+    let controllers = detect("/sys/class/bluetooth", "hci");
+    if controllers.is_empty() {
+        println!("No bluetooth controllers found");
+    } else {
+        println!("Found bluetooth controllers: {}", controllers.join(", "));
+    }
+    opts.bluetooth = confirm(
+        "Enable bluetooth presence tracking?",
+        !controllers.is_empty(),
+    )?;
+
+    let chips = detect("/dev", "gpiochip");
+    if chips.is_empty() {
+        println!("No GPIO chips found");
+    } else {
+        println!("Found GPIO chips: {}", chips.join(", "));
+        if confirm("Enable a DHT22 temperature/humidity sensor?", false)? {
+            let device = prompt("GPIO chip", &format!("/dev/{}", chips[0]))?;
+            let channel = prompt("GPIO line", "4")?.parse().context("Invalid line")?;
+            opts.dht = Some((device, channel));
+        }
+    }
+
+    opts.command = confirm("Include an example command plugin?", false)?;
+
+    Configuration::generate(file.to_path_buf(), &opts)?;
+    println!("Configuration written to {}", file.display());
+    Ok(())
+}
@@ -1,8 +1,30 @@
 pub use crate::prelude::*;
-use crate::{mqtt::MQTTService, plugins::PluginManager};
-use std::time::Duration;
+use crate::{
+    broker::Broker, http::HttpService, logging, mqtt::MQTTService, plugins::PluginManager,
+    sinks::Sinks, systemd,
+};
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// The MQTT event loop or a service loop idle for half the watchdog timeout counts as wedged,
+/// but never sooner than this. The event loop wakes every 5 second keep alive.
+const MIN_STALL_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long `--once` waits for the broker, both to connect and to receive the results
+const ONCE_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `--once` lets each plugin run, long running plugins collect for this long
+const ONCE_RUN_WINDOW: Duration = Duration::from_secs(15);
 
 mod args;
+mod builder;
+mod check;
+mod init;
+
+pub use builder::{Corvus, CorvusBuilder};
 
 #[derive(Clone, Deref, Debug)]
 pub struct App(Arc<AppServices>);
@@ -10,7 +32,11 @@ pub struct App(Arc<AppServices>);
 #[derive(Debug)]
 pub struct AppServices {
     pub config:          Arc<Configuration>,
+    pub config_paths:    Vec<PathBuf>,
+    pub watch_config:    bool,
+    pub once:            bool,
     pub mqtt:            MQTTService,
+    pub sinks:           Sinks,
     pub plugin_manager:  PluginManager,
     pub cluster_data:    ClusterNodes,
     pub device_registry: DeviceRegistry,
@@ -19,49 +45,75 @@ pub struct AppServices {
 impl App {
     pub async fn new() -> Result<Self> {
         let opts = args::parse()?;
-        simplelog::TermLogger::init(
-            match opts.verbosity {
-                0 => simplelog::LevelFilter::Info,
-                1 => simplelog::LevelFilter::Debug,
-                _ => simplelog::LevelFilter::Trace,
-            },
-            simplelog::ConfigBuilder::new()
-                .add_filter_allow_str("corvus")
-                .set_location_level(simplelog::LevelFilter::Debug)
-                .set_target_level(simplelog::LevelFilter::Error)
-                .set_time_format_str("%D %T")
-                .set_time_to_local(true)
-                .build(),
-            simplelog::TerminalMode::Mixed,
-        )?;
-        if opts.generate {
+        if opts.command.is_some() || opts.generate {
+            logging::init(&Default::default(), opts.verbosity)?;
+        }
+        if let Some(args::Command::Check) = opts.command {
+            std::process::exit(check::run(&opts.config));
+        } else if let Some(args::Command::Init) = opts.command {
+            init::run(&opts.config[0])?;
+            std::process::exit(0);
+        } else if opts.generate {
             info!(
                 "Generating new configuration file at {}",
-                opts.config.to_string_lossy()
+                opts.config[0].to_string_lossy()
             );
-            Configuration::generate_default(opts.config)?;
+            Configuration::generate_default(opts.config[0].clone())?;
             std::process::exit(0);
         } else {
-            info!("Starting Corvus");
-            let config = Configuration::load(opts.config)?;
-            let cluster_data = ClusterNodes::default();
-            let plugin_manager: PluginManager = Default::default();
-            let mqtt_service = MQTTService::new(
-                config.node.location.clone(),
-                config.mqtt.clone(),
-                plugin_manager.clone(),
-            )
-            .await?;
-            Ok(App(Arc::new(AppServices {
-                device_registry: DeviceRegistry::new(mqtt_service.clone(), config.clone()),
-                plugin_manager,
-                mqtt: mqtt_service,
-                cluster_data,
-                config,
-            })))
+            // Logging is configured by the file being loaded, errors loading it go to stderr
+            // and warnings are written once the logger is set up
+            logging::bootstrap()?;
+            let config = match Configuration::load(&opts.config) {
+                Ok(config) => config,
+                Err(e) => {
+                    logging::init(&Default::default(), opts.verbosity)?;
+                    return Err(e);
+                }
+            };
+            logging::init(&config.logging, opts.verbosity)?;
+            Self::create(config, opts.config, opts.watch, opts.once, opts.dry_run).await
         }
     }
 
+    /// Construct an app from an already loaded configuration, without parsing arguments or
+    /// installing a logger. Nothing runs until `start` or `start_services` is called.
+    pub async fn from_configuration(config: Arc<Configuration>) -> Result<Self> {
+        Self::create(config, vec![], false, false, false).await
+    }
+
+    async fn create(
+        config: Arc<Configuration>,
+        config_paths: Vec<PathBuf>,
+        watch_config: bool,
+        once: bool,
+        dry_run: bool,
+    ) -> Result<Self> {
+        info!("Starting Corvus");
+        let cluster_data = ClusterNodes::default();
+        let plugin_manager: PluginManager = Default::default();
+        let mqtt_service = MQTTService::new(
+            config.node.location.clone(),
+            config.mqtt.clone(),
+            plugin_manager.clone(),
+            cluster_data.clone(),
+            dry_run,
+        )
+        .await?;
+        let sinks = Sinks::new(mqtt_service.clone(), &config)?;
+        Ok(App(Arc::new(AppServices {
+            device_registry: DeviceRegistry::new(mqtt_service.clone(), config.clone()),
+            plugin_manager,
+            mqtt: mqtt_service,
+            sinks,
+            cluster_data,
+            config,
+            config_paths,
+            watch_config,
+            once,
+        })))
+    }
+
     async fn init_heartbeats(&self) -> Result<()> {
         let zelf = self.clone();
         start_service(
@@ -73,29 +125,7 @@ impl App {
                 let zelf = zelf.clone();
                 async move { zelf.mqtt.heartbeat().await }
             },
-        )?;
-
-        for svc in self.plugin_manager.lock().await.values().cloned() {
-            let svc = svc.clone();
-            let zelf = self.clone();
-            start_service(
-                Duration::from_secs(10),
-                format!("{} Plugin Heartbeat", svc.name()),
-                false,
-                false,
-                move || {
-                    let svc = svc.clone();
-                    let zelf = zelf.clone();
-                    async move {
-                        svc.heartbeat().await?;
-                        if zelf.mqtt.is_leader().await {
-                            svc.leader_heartbeat(zelf.cluster_data.clone()).await?;
-                        }
-                        Ok(())
-                    }
-                },
-            )?;
-        }
+        );
         Ok(())
     }
 
@@ -111,13 +141,226 @@ impl App {
         self.device_registry.start_service()
     }
 
-    pub async fn start(&self) -> Result<()> {
+    async fn init_broker(&self) -> Result<()> {
+        if self.config.mqtt.embedded_broker {
+            Broker::default().start(&self.config.mqtt).await?;
+        }
+        Ok(())
+    }
+
+    async fn init_http(&self) -> Result<()> {
+        match &self.config.http {
+            Some(http) => HttpService::new(self.clone()).start(http).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Report readiness and state to systemd and ping its watchdog while healthy. Nothing is
+    /// started when not running under a `Type=notify` unit.
+    async fn init_systemd(&self) -> Result<()> {
+        if !systemd::enabled() {
+            return Ok(());
+        }
+        let watchdog = systemd::watchdog_timeout();
+        let stall_timeout = watchdog.map(|w| (w / 2).max(MIN_STALL_TIMEOUT));
+        let period = watchdog
+            .map(|w| w / 2)
+            .unwrap_or_else(|| Duration::from_secs(10));
+        let zelf = self.clone();
+        let last_status = Arc::new(Mutex::new(None));
+        let ready = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        start_service(period, "Systemd Notify".into(), true, false, move || {
+            let zelf = zelf.clone();
+            let last_status = last_status.clone();
+            let ready = ready.clone();
+            async move {
+                let connected = zelf.mqtt.is_connected();
+                if connected && !ready.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                    info!("Notifying systemd of readiness");
+                    systemd::notify("READY=1")?;
+                }
+
+                let status = zelf.status_line().await;
+                let mut last = last_status.lock().await;
+                if last.as_ref() != Some(&status) {
+                    systemd::notify(&format!("STATUS={}", status))?;
+                    *last = Some(status);
+                }
+
+                if let Some(stall_timeout) = stall_timeout {
+                    let stalled = stalled_services(stall_timeout);
+                    if zelf.mqtt.last_poll() > stall_timeout {
+                        warn!("MQTT event loop is stalled, withholding watchdog ping");
+                    } else if !stalled.is_empty() {
+                        warn!(
+                            "Services {:?} are stalled, withholding watchdog ping",
+                            stalled
+                        );
+                    } else {
+                        systemd::notify("WATCHDOG=1")?;
+                    }
+                }
+                Ok(())
+            }
+        });
+        Ok(())
+    }
+
+    async fn status_line(&self) -> String {
+        format!(
+            "{} to MQTT, {}, {} plugins running",
+            if self.mqtt.is_connected() {
+                "Connected"
+            } else {
+                "Not connected"
+            },
+            if self.mqtt.is_leader().await {
+                "cluster leader"
+            } else {
+                "cluster follower"
+            },
+            self.plugin_manager.list_plugins().await.len()
+        )
+    }
+
+    async fn init_config_watcher(&self) -> Result<()> {
+        if !self.watch_config {
+            return Ok(());
+        }
+        let zelf = self.clone();
+        let last_modified = Arc::new(Mutex::new(self.config_modified()));
+        start_service(
+            Duration::from_secs(5),
+            "Configuration Watcher".into(),
+            false,
+            false,
+            move || {
+                let zelf = zelf.clone();
+                let last_modified = last_modified.clone();
+                async move {
+                    let modified = zelf.config_modified();
+                    let mut last = last_modified.lock().await;
+                    if modified != *last {
+                        *last = modified;
+                        info!("Configuration files changed, reloading");
+                        zelf.reload().await?;
+                    }
+                    Ok(())
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Modification times of every file the configuration is currently made of
+    fn config_modified(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        let paths: Vec<PathBuf> = match ConfigSources::read(&self.config_paths) {
+            Ok(sources) => sources.iter().map(|s| s.path.clone()).collect(),
+            Err(_) => self.config_paths.clone(),
+        };
+        paths
+            .into_iter()
+            .map(|p| {
+                let modified = std::fs::metadata(&p).and_then(|m| m.modified()).ok();
+                (p, modified)
+            })
+            .collect()
+    }
+
+    /// Re-read the configuration files and apply plugin and sink changes. Node, MQTT, HTTP and logging
+    /// settings are only read at startup, so changes to them are reported but not applied.
+    pub async fn reload(&self) -> Result<()> {
+        // Failing to tell systemd must neither skip the reload nor leave it reloading
+        let notify = |state| {
+            if let Err(e) = systemd::notify(state) {
+                warn!("{:#}", e);
+            }
+        };
+        notify("RELOADING=1");
+        let result = self.reload_plugins().await;
+        notify("READY=1");
+        result
+    }
+
+    async fn reload_plugins(&self) -> Result<()> {
+        let config = Configuration::load(&self.config_paths)?;
+        if config.node != self.config.node
+            || config.mqtt != self.config.mqtt
+            || config.http != self.config.http
+            || config.logging != self.config.logging
+        {
+            warn!(
+                "Changes to the [node], [mqtt], [http] and [logging] sections require a restart to \
+                 take effect"
+            );
+        }
+        // Sinks only replace the current ones once the plugins were accepted too
+        let sinks = Sinks::load(&config)?;
+        self.plugin_manager.reload(&config, self).await?;
+        self.sinks.replace(sinks);
+        info!("Configuration reloaded");
+        Ok(())
+    }
+
+    /// Connect, run every plugin a single time and wait for the results to be published
+    async fn run_once(&self) -> Result<()> {
+        self.init_broker().await?;
+        self.init_mqtt().await?;
+        let start = std::time::Instant::now();
+        while !self.mqtt.is_connected() {
+            if start.elapsed() > ONCE_CONNECT_TIMEOUT {
+                bail!("Could not connect to the MQTT broker");
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        self.mqtt.heartbeat().await?;
+        self.plugin_manager
+            .run_once(&self.config, self, ONCE_RUN_WINDOW)
+            .await?;
+        self.mqtt.flush(ONCE_CONNECT_TIMEOUT).await
+    }
+
+    /// Start every service in the background and return
+    pub async fn start_services(&self) -> Result<()> {
+        self.init_broker().await?;
         self.init_mqtt().await?;
         self.init_plugins().await?;
         self.init_heartbeats().await?;
         self.init_device_registry().await?;
-        tokio::signal::ctrl_c().await?;
+        self.init_config_watcher().await?;
+        self.init_http().await?;
+        self.init_systemd().await?;
+        Ok(())
+    }
+
+    /// Run until interrupted, reloading the configuration on SIGHUP
+    pub async fn start(&self) -> Result<()> {
+        if self.once {
+            return self.run_once().await;
+        }
+        self.start_services().await?;
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        loop {
+            tokio::select! {
+                res = tokio::signal::ctrl_c() => {
+                    res?;
+                    break;
+                }
+                _ = hangup.recv() => {
+                    info!("SIGHUP received, reloading configuration");
+                    self.reload()
+                        .await
+                        .unwrap_or_else(|e| error!("Configuration rejected: {:?}", e));
+                }
+            }
+        }
         warn!("Signal received, shutting down");
+        self.mqtt
+            .shutdown(Duration::from_secs(5))
+            .await
+            .unwrap_or_else(|e| warn!("Failed to announce shutdown: {:?}", e));
+        systemd::notify("STOPPING=1")?;
         Ok(())
     }
 }
@@ -0,0 +1,449 @@
+use crate::prelude::*;
+use bytes::BytesMut;
+use rumqttc::{
+    mqtt_read, valid_filter, valid_topic, ConnAck, Connect, ConnectReturnCode, LastWill, Packet,
+    PingResp, PubAck, PubComp, PubRec, Publish, QoS, SubAck, SubscribeReturnCodes, UnsubAck,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, error::TrySendError},
+    time::{sleep_until, timeout, Instant},
+};
+
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+/// Time a new connection has to send CONNECT
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Packets queued for a client before further messages to it are dropped
+const SESSION_QUEUE: usize = 1000;
+
+/// Minimal MQTT 3.1.1 broker for deployments without a central one. Supports retained
+/// messages, wildcard subscriptions, QoS 0 and 1 and last wills. Sessions are never
+/// persisted and QoS 1 messages to subscribers are not redelivered, clients have to
+/// resubscribe after reconnecting. There is no authentication.
+#[derive(Clone, Debug, Deref, Default)]
+pub struct Broker(Arc<BrokerData>);
+
+#[derive(Debug, Default)]
+pub struct BrokerData {
+    next_id: AtomicU64,
+    state:   parking_lot::Mutex<BrokerState>,
+}
+
+#[derive(Debug, Default)]
+struct BrokerState {
+    retained: BTreeMap<String, Publish>,
+    sessions: HashMap<String, Session>,
+}
+
+/// Packets to a client. Dropping the sender, as a connection taking over the client id does,
+/// closes the connection once the queue is drained.
+#[derive(Debug)]
+struct Session {
+    id:            u64,
+    client_id:     String,
+    tx:            mpsc::Sender<Packet>,
+    subscriptions: HashMap<String, QoS>,
+    next_pkid:     u16,
+    /// Whether messages are being dropped, so a slow client is only reported once
+    lagging:       bool,
+}
+
+impl Session {
+    fn new(id: u64, client_id: String, tx: mpsc::Sender<Packet>) -> Self {
+        Session {
+            subscriptions: Default::default(),
+            next_pkid: 0,
+            lagging: false,
+            id,
+            client_id,
+            tx,
+        }
+    }
+
+    /// Queue a message for the client, a closed connection or a full queue drops it
+    fn send(&mut self, publish: &Publish, qos: QoS, retain: bool) {
+        let mut publish = publish.clone();
+        publish.qos = qos;
+        publish.retain = retain;
+        publish.dup = false;
+        publish.pkid = if qos == QoS::AtMostOnce {
+            0
+        } else {
+            // Packet ids must not be 0
+            self.next_pkid = self.next_pkid.checked_add(1).unwrap_or(1);
+            self.next_pkid
+        };
+        match self.tx.try_send(Packet::Publish(publish)) {
+            Ok(()) => self.lagging = false,
+            Err(TrySendError::Full(_)) if !self.lagging => {
+                warn!(
+                    "MQTT client '{}' is not keeping up, dropping messages to it",
+                    self.client_id
+                );
+                self.lagging = true;
+            }
+            Err(_) => (),
+        }
+    }
+
+    /// Highest QoS of the subscriptions matching `topic`
+    fn subscribed(&self, topic: &str) -> Option<QoS> {
+        self.subscriptions
+            .iter()
+            .filter(|(filter, _)| topic_matches(topic, filter))
+            .map(|(_, qos)| *qos)
+            .fold(None, |max, qos| match max {
+                Some(max) if max >= qos => Some(max),
+                _ => Some(qos),
+            })
+    }
+}
+
+/// Whether `topic` matches the subscription `filter`. Filters starting with a wildcard don't
+/// match topics starting with `$`, but filters naming them do (MQTT 3.1.1 section 4.7.2).
+/// `rumqttc::matches` never matches `$` topics at all.
+fn topic_matches(topic: &str, filter: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('#') || filter.starts_with('+')) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for f in filter.split('/') {
+        // `valid_filter` only accepts `#` as the last level
+        if f == "#" {
+            return true;
+        }
+        match levels.next() {
+            Some(_) if f == "+" => (),
+            Some(level) if level == f => (),
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+fn min_qos(a: QoS, b: QoS) -> QoS {
+    if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+fn encode_error(e: rumqttc::Error) -> Error {
+    anyhow!("Invalid MQTT packet: {:?}", e)
+}
+
+async fn write_packet(stream: &mut TcpStream, packet: &Packet) -> Result<()> {
+    let mut buf = BytesMut::new();
+    match packet {
+        Packet::ConnAck(p) => p.write(&mut buf),
+        Packet::Publish(p) => p.write(&mut buf),
+        Packet::PubAck(p) => p.write(&mut buf),
+        Packet::PubRec(p) => p.write(&mut buf),
+        Packet::PubComp(p) => p.write(&mut buf),
+        Packet::SubAck(p) => p.write(&mut buf),
+        Packet::UnsubAck(p) => p.write(&mut buf),
+        Packet::PingResp => PingResp.write(&mut buf),
+        p => bail!("The broker does not send {:?}", p),
+    }
+    .map_err(encode_error)?;
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Read the next packet, packets already in `buf` are returned without reading
+async fn read_packet(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<Packet> {
+    loop {
+        match mqtt_read(buf, MAX_PACKET_SIZE) {
+            Ok(packet) => return Ok(packet),
+            Err(rumqttc::Error::InsufficientBytes(_)) => (),
+            Err(e) => return Err(encode_error(e)),
+        }
+        if stream.read_buf(buf).await? == 0 {
+            bail!("Connection closed");
+        }
+    }
+}
+
+fn will_message(will: LastWill) -> Publish {
+    let mut publish = Publish::from_bytes(will.topic, will.qos, will.message);
+    publish.retain = will.retain;
+    publish
+}
+
+impl Broker {
+    /// Listen on every interface at the configured MQTT port, the local client connects to
+    /// it like any other
+    pub async fn start(self, config: &MQTTConfiguration) -> Result<()> {
+        let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Could not bind embedded MQTT broker to {}", addr))?;
+        info!("Embedded MQTT broker listening on {}", addr);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let zelf = self.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = zelf.handle_connection(stream).await {
+                                debug!("MQTT connection from {} failed: {:?}", peer, e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept MQTT connection: {:?}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(4096);
+        let connect = match timeout(CONNECT_TIMEOUT, read_packet(&mut stream, &mut buf)).await?? {
+            Packet::Connect(connect) => connect,
+            p => bail!("Expected CONNECT, got {:?}", p),
+        };
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let client_id = if !connect.client_id.is_empty() {
+            connect.client_id.to_string()
+        } else if connect.clean_session {
+            format!("{}-{}", crate_name!(), id)
+        } else {
+            let connack = ConnAck::new(ConnectReturnCode::BadClientId, false);
+            write_packet(&mut stream, &Packet::ConnAck(connack)).await?;
+            bail!("Empty client id without a clean session");
+        };
+
+        let (tx, mut rx) = mpsc::channel(SESSION_QUEUE);
+        if self
+            .state
+            .lock()
+            .sessions
+            .insert(
+                client_id.to_string(),
+                Session::new(id, client_id.to_string(), tx),
+            )
+            .is_some()
+        {
+            debug!(
+                "Client '{}' reconnected, closing its old connection",
+                client_id
+            );
+        }
+        let connack = ConnAck::new(ConnectReturnCode::Accepted, false);
+        write_packet(&mut stream, &Packet::ConnAck(connack)).await?;
+        debug!("MQTT client '{}' connected", client_id);
+
+        let result = self
+            .serve(&client_id, &connect, &mut stream, &mut buf, &mut rx)
+            .await;
+
+        let mut state = self.state.lock();
+        if matches!(state.sessions.get(&client_id), Some(s) if s.id == id) {
+            state.sessions.remove(&client_id);
+        }
+        drop(state);
+        match result {
+            Ok(_) => debug!("MQTT client '{}' disconnected", client_id),
+            Err(e) => {
+                debug!("MQTT client '{}' connection lost: {:?}", client_id, e);
+                if let Some(will) = connect.last_will {
+                    self.route(will_message(will));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Exchange packets until the client disconnects cleanly, errors are connection losses
+    async fn serve(
+        &self,
+        client_id: &str,
+        connect: &Connect,
+        stream: &mut TcpStream,
+        buf: &mut BytesMut,
+        rx: &mut mpsc::Receiver<Packet>,
+    ) -> Result<()> {
+        // Clients get one and a half keep alive intervals to send anything
+        let keep_alive = Duration::from_millis(connect.keep_alive as u64 * 1500);
+        let mut deadline = Instant::now() + keep_alive;
+        loop {
+            match mqtt_read(buf, MAX_PACKET_SIZE) {
+                Ok(packet) => {
+                    deadline = Instant::now() + keep_alive;
+                    if !self.handle_packet(client_id, stream, packet).await? {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(rumqttc::Error::InsufficientBytes(_)) => (),
+                Err(e) => return Err(encode_error(e)),
+            }
+            tokio::select! {
+                read = stream.read_buf(buf) => {
+                    if read? == 0 {
+                        bail!("Connection closed");
+                    }
+                }
+                outgoing = rx.recv() => match outgoing {
+                    Some(packet) => write_packet(stream, &packet).await?,
+                    None => bail!("Session taken over"),
+                },
+                _ = sleep_until(deadline), if connect.keep_alive > 0 => {
+                    bail!("Keep alive timed out");
+                }
+            }
+        }
+    }
+
+    /// Handle a packet from a client, returns false once it disconnects
+    async fn handle_packet(
+        &self,
+        client_id: &str,
+        stream: &mut TcpStream,
+        packet: Packet,
+    ) -> Result<bool> {
+        trace!("MQTT client '{}' sent {:?}", client_id, packet);
+        match packet {
+            Packet::Publish(publish) => {
+                if !valid_topic(&publish.topic) {
+                    bail!("Invalid topic '{}'", publish.topic);
+                }
+                match publish.qos {
+                    QoS::AtMostOnce => (),
+                    QoS::AtLeastOnce => {
+                        write_packet(stream, &Packet::PubAck(PubAck::new(publish.pkid))).await?
+                    }
+                    // Accepted for compatibility, delivered at most at QoS 1
+                    QoS::ExactlyOnce => {
+                        write_packet(stream, &Packet::PubRec(PubRec::new(publish.pkid))).await?
+                    }
+                }
+                self.route(publish);
+            }
+            Packet::PubRel(rel) => {
+                write_packet(stream, &Packet::PubComp(PubComp::new(rel.pkid))).await?
+            }
+            Packet::PubAck(_) | Packet::PubRec(_) | Packet::PubComp(_) => (),
+            Packet::Subscribe(subscribe) => {
+                let codes = subscribe
+                    .topics
+                    .iter()
+                    .map(|t| {
+                        if valid_filter(&t.topic_path) {
+                            SubscribeReturnCodes::Success(min_qos(t.qos, QoS::AtLeastOnce))
+                        } else {
+                            SubscribeReturnCodes::Failure
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let suback = SubAck::new(subscribe.pkid, codes.clone());
+                write_packet(stream, &Packet::SubAck(suback)).await?;
+
+                let mut state = self.state.lock();
+                let BrokerState { retained, sessions } = &mut *state;
+                if let Some(session) = sessions.get_mut(client_id) {
+                    for (topic, code) in subscribe.topics.iter().zip(codes) {
+                        if let SubscribeReturnCodes::Success(qos) = code {
+                            session
+                                .subscriptions
+                                .insert(topic.topic_path.to_string(), qos);
+                            for publish in retained.values() {
+                                if topic_matches(&publish.topic, &topic.topic_path) {
+                                    session.send(publish, min_qos(publish.qos, qos), true);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                if let Some(session) = self.state.lock().sessions.get_mut(client_id) {
+                    for topic in unsubscribe.topics.iter() {
+                        session.subscriptions.remove(topic);
+                    }
+                }
+                let unsuback = UnsubAck::new(unsubscribe.pkid);
+                write_packet(stream, &Packet::UnsubAck(unsuback)).await?;
+            }
+            Packet::PingReq => write_packet(stream, &Packet::PingResp).await?,
+            Packet::Disconnect => return Ok(false),
+            p => bail!("Unexpected {:?}", p),
+        }
+        Ok(true)
+    }
+
+    /// Store a retained message and deliver it to every matching subscription
+    fn route(&self, publish: Publish) {
+        let mut state = self.state.lock();
+        if publish.retain {
+            if publish.payload.is_empty() {
+                state.retained.remove(&publish.topic);
+            } else {
+                let mut retained = publish.clone();
+                retained.qos = min_qos(retained.qos, QoS::AtLeastOnce);
+                state.retained.insert(publish.topic.to_string(), retained);
+            }
+        }
+        for session in state.sessions.values_mut() {
+            if let Some(qos) = session.subscribed(&publish.topic) {
+                session.send(&publish, min_qos(publish.qos, qos), false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_skip_reserved_topics() {
+        assert!(topic_matches("corvus/leader", "#"));
+        assert!(topic_matches("corvus/leader", "+/leader"));
+        assert!(!topic_matches("$SYS/uptime", "#"));
+        assert!(!topic_matches("$SYS/uptime", "+/uptime"));
+        assert!(topic_matches("$SYS/uptime", "$SYS/#"));
+        assert!(topic_matches("$SYS/uptime", "$SYS/+"));
+        assert!(topic_matches("$SYS/uptime", "$SYS/uptime"));
+
+        assert!(topic_matches("corvus", "corvus/#"));
+        assert!(topic_matches("corvus/nodes/kitchen", "corvus/+/kitchen"));
+        assert!(!topic_matches("corvus/nodes", "corvus/+/kitchen"));
+        assert!(!topic_matches(
+            "corvus/nodes/kitchen/stat",
+            "corvus/+/kitchen"
+        ));
+        assert!(topic_matches("corvus//stat", "corvus/+/stat"));
+    }
+
+    #[test]
+    fn slow_sessions_drop_messages() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut session = Session::new(1, "slow".into(), tx);
+        let publish = Publish::new("corvus/leader", QoS::AtLeastOnce, "kitchen");
+        for _ in 0..5 {
+            session.send(&publish, QoS::AtLeastOnce, false);
+        }
+        assert!(session.lagging);
+
+        let pkid = |packet: Option<Packet>| match packet {
+            Some(Packet::Publish(p)) => p.pkid,
+            p => panic!("Expected a publish, got {:?}", p),
+        };
+        assert_eq!(pkid(rx.try_recv().ok()), 1);
+        assert_eq!(pkid(rx.try_recv().ok()), 2);
+        assert!(rx.try_recv().is_err());
+
+        session.send(&publish, QoS::AtLeastOnce, false);
+        assert!(!session.lagging);
+    }
+}
@@ -0,0 +1,256 @@
+use super::*;
+use toml::Value;
+
+const ENV_PREFIX: &str = "CORVUS_";
+const FILE_PREFIX: &str = "file:";
+
+/// Expand `${VAR}` and `${VAR:-default}` references, `$$` is a literal `$`
+pub(super) fn expand_vars(s: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        let tail = &rest[i + 1..];
+        if let Some(tail) = tail.strip_prefix('$') {
+            out.push('$');
+            rest = tail;
+        } else if let Some(tail) = tail.strip_prefix('{') {
+            let end = tail
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated variable reference in '{}'", s))?;
+            let expr = &tail[..end];
+            let (name, default) = match expr.find(":-") {
+                Some(j) => (&expr[..j], Some(&expr[j + 2..])),
+                None => (expr, None),
+            };
+            let value = match (std::env::var(name), default) {
+                (Ok(v), Some(d)) if v.is_empty() => d.to_string(),
+                (Ok(v), _) => v,
+                (Err(_), Some(d)) => d.to_string(),
+                (Err(_), None) => bail!("Environment variable '{}' is not set", name),
+            };
+            out.push_str(&value);
+            rest = &tail[end + 1..];
+        } else {
+            out.push('$');
+            rest = tail;
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Resolve a single string value, `file:<path>` is replaced by the contents of that file
+fn resolve(s: &str) -> Result<String> {
+    match s.strip_prefix(FILE_PREFIX) {
+        Some(path) => {
+            let path = expand_vars(path)?;
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Could not read secret file {}", path))?;
+            Ok(contents.trim_end().to_string())
+        }
+        None => expand_vars(s),
+    }
+}
+
+fn walk(value: &mut Value, path: &str) -> Result<()> {
+    match value {
+        Value::String(s) => {
+            *s = resolve(s).with_context(|| format!("Failed to resolve '{}'", path))?;
+        }
+        Value::Array(items) => {
+            for (i, v) in items.iter_mut().enumerate() {
+                walk(v, &format!("{}[{}]", path, i))?;
+            }
+        }
+        Value::Table(table) => {
+            // Rhai has `${...}` interpolation of its own, script bodies are kept as written
+            let script = table.get("type").and_then(Value::as_str) == Some("script");
+            for (k, v) in table.iter_mut() {
+                if script && k == "script" {
+                    continue;
+                }
+                if path.is_empty() {
+                    walk(v, k)?;
+                } else {
+                    walk(v, &format!("{}.{}", path, k))?;
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Apply `CORVUS_<SECTION>_<FIELD>` overrides to the `node`, `mqtt`, `http` and `logging` tables.
+/// Values are converted to the type of the field's default so e.g. `CORVUS_MQTT_PORT` stays an
+/// integer, lists are given comma separated.
+pub(super) fn apply_overrides(
+    value: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    let sections = [
+        ("node", Value::try_from(NodeConfiguration::default())?),
+        ("mqtt", Value::try_from(MQTTConfiguration::default())?),
+        ("http", Value::try_from(HttpConfiguration::default())?),
+        ("logging", Value::try_from(LoggingConfiguration::default())?),
+    ];
+    let root = value
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("Configuration must be a table"))?;
+
+    for (var, val) in vars {
+        let key = match var.strip_prefix(ENV_PREFIX) {
+            Some(key) => key.to_lowercase(),
+            None => continue,
+        };
+        for (section, defaults) in sections.iter() {
+            let field = match key.strip_prefix(section).and_then(|k| k.strip_prefix('_')) {
+                Some(field) => field,
+                None => continue,
+            };
+            let typed = match defaults.get(field) {
+                Some(Value::Integer(_)) => Value::Integer(
+                    val.parse()
+                        .with_context(|| format!("{} must be an integer, got '{}'", var, val))?,
+                ),
+                Some(Value::Boolean(_)) => Value::Boolean(
+                    val.parse()
+                        .with_context(|| format!("{} must be true or false, got '{}'", var, val))?,
+                ),
+                Some(Value::Array(_)) => Value::Array(
+                    val.split(',')
+                        .map(|s| s.trim())
+                        .filter(|s| !s.is_empty())
+                        .map(|s| Value::String(s.into()))
+                        .collect(),
+                ),
+                Some(_) => Value::String(val.to_string()),
+                None => {
+                    warn!(
+                        "Ignoring {}, it does not match a field of [{}]",
+                        var, section
+                    );
+                    continue;
+                }
+            };
+            debug!("Overriding {}.{} from {}", section, field, var);
+            root.entry(section.to_string())
+                .or_insert_with(|| Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or_else(|| anyhow!("[{}] must be a table", section))?
+                .insert(field.to_string(), typed);
+        }
+    }
+    Ok(())
+}
+
+/// Expand environment variables and secret files in every string of a parsed document
+pub(super) fn interpolate(value: &mut Value) -> Result<()> {
+    walk(value, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overridden(vars: &[(&str, &str)]) -> Result<Value> {
+        let mut value: Value = toml::from_str("[node]\nlocation = \"kitchen\"")?;
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        apply_overrides(&mut value, vars)?;
+        Ok(value)
+    }
+
+    #[test]
+    fn expands_variables() {
+        std::env::set_var("CORVUS_TEST_EXPAND", "attic");
+        std::env::set_var("CORVUS_TEST_EMPTY", "");
+        std::env::remove_var("CORVUS_TEST_UNSET");
+
+        assert_eq!(expand_vars("${CORVUS_TEST_EXPAND}/x").unwrap(), "attic/x");
+        assert_eq!(
+            expand_vars("$${CORVUS_TEST_EXPAND}").unwrap(),
+            "${CORVUS_TEST_EXPAND}"
+        );
+        assert_eq!(expand_vars("cost: 5$").unwrap(), "cost: 5$");
+        assert_eq!(
+            expand_vars("${CORVUS_TEST_UNSET:-cellar}").unwrap(),
+            "cellar"
+        );
+        assert_eq!(
+            expand_vars("${CORVUS_TEST_EMPTY:-cellar}").unwrap(),
+            "cellar"
+        );
+        assert_eq!(
+            expand_vars("${CORVUS_TEST_EXPAND:-cellar}").unwrap(),
+            "attic"
+        );
+        assert_eq!(expand_vars("${CORVUS_TEST_EMPTY}").unwrap(), "");
+
+        let unset = expand_vars("${CORVUS_TEST_UNSET}").unwrap_err();
+        assert!(unset.to_string().contains("CORVUS_TEST_UNSET"), "{}", unset);
+        let unterminated = expand_vars("${CORVUS_TEST_EXPAND").unwrap_err();
+        assert!(
+            unterminated.to_string().contains("Unterminated"),
+            "{}",
+            unterminated
+        );
+    }
+
+    #[test]
+    fn keeps_script_bodies() {
+        std::env::set_var("CORVUS_TEST_SCRIPT", "attic");
+        let mut value: Value = toml::from_str(
+            r#"
+            [node]
+            location = "${CORVUS_TEST_SCRIPT}"
+            [plugin.definition]
+            type = "script"
+            script = 'print(`value is ${CORVUS_TEST_SCRIPT} ${x}`);'
+            "#,
+        )
+        .unwrap();
+        interpolate(&mut value).unwrap();
+        assert_eq!(value["node"]["location"].as_str(), Some("attic"));
+        assert_eq!(
+            value["plugin"]["definition"]["script"].as_str(),
+            Some("print(`value is ${CORVUS_TEST_SCRIPT} ${x}`);")
+        );
+    }
+
+    #[test]
+    fn overrides_keep_field_types() {
+        let value = overridden(&[
+            ("CORVUS_NODE_LOCATION", "attic"),
+            ("CORVUS_MQTT_PORT", "1884"),
+            ("CORVUS_MQTT_EMBEDDED_BROKER", "true"),
+            ("CORVUS_MQTT_DISCOVERY_FORMAT", "hass, homie,"),
+            ("HOME", "/root"),
+        ])
+        .unwrap();
+        assert_eq!(value["node"]["location"].as_str(), Some("attic"));
+        assert_eq!(value["mqtt"]["port"].as_integer(), Some(1884));
+        assert_eq!(value["mqtt"]["embedded_broker"].as_bool(), Some(true));
+        assert_eq!(
+            value["mqtt"]["discovery_format"],
+            Value::Array(vec!["hass".into(), "homie".into()])
+        );
+    }
+
+    #[test]
+    fn rejects_mistyped_overrides() {
+        assert!(overridden(&[("CORVUS_MQTT_PORT", "high")]).is_err());
+        assert!(overridden(&[("CORVUS_MQTT_EMBEDDED_BROKER", "yes")]).is_err());
+    }
+
+    #[test]
+    fn ignores_unknown_overrides() {
+        let value = overridden(&[
+            ("CORVUS_NODE_COLOR", "red"),
+            ("CORVUS_MQTT_PASSWORD_FILE", "/run/secret"),
+        ])
+        .unwrap();
+        assert!(value["node"].get("color").is_none());
+        assert!(value.get("mqtt").is_none());
+    }
+}
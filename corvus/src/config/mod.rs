@@ -0,0 +1,518 @@
+use crate::*;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::Write, path::PathBuf};
+
+mod interpolate;
+mod sources;
+mod template;
+mod validate;
+
+pub use sources::ConfigSources;
+pub use template::TemplateOptions;
+pub use validate::Diagnostic;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub struct Configuration {
+    /// Additional files to load, relative to this file. See `ConfigSources` for precedence.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    pub node:    Arc<NodeConfiguration>,
+    pub mqtt:    Arc<MQTTConfiguration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http:    Option<Arc<HttpConfiguration>>,
+    #[serde(default)]
+    pub logging: Arc<LoggingConfiguration>,
+    #[serde(rename = "plugin", default)]
+    pub plugins: Vec<Arc<PluginConfiguration>>,
+    #[serde(rename = "sink", default, skip_serializing_if = "Vec::is_empty")]
+    pub sinks:   Vec<Arc<SinkConfiguration>>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            include: vec![],
+            plugins: vec![Default::default()],
+            node:    Default::default(),
+            mqtt:    Default::default(),
+            http:    None,
+            logging: Default::default(),
+            sinks:   vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub struct NodeConfiguration {
+    pub location: String,
+}
+
+impl Default for NodeConfiguration {
+    fn default() -> Self {
+        NodeConfiguration {
+            location: "home".into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case", default)]
+pub struct MQTTConfiguration {
+    pub client_id:        String,
+    pub host:             String,
+    pub port:             u16,
+    pub base_topic:       String,
+    pub discovery_topic:  String,
+    /// Discovery conventions devices are announced in
+    pub discovery_format: Vec<DiscoveryFormat>,
+    /// Root topic of Homie devices
+    pub homie_topic:      String,
+    /// Host a broker on `port` of every interface for other nodes and the local client
+    pub embedded_broker:  bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscoveryFormat {
+    /// Home Assistant MQTT discovery
+    Hass,
+    /// Homie 4 convention
+    Homie,
+}
+
+impl Default for MQTTConfiguration {
+    fn default() -> Self {
+        MQTTConfiguration {
+            client_id:        "corvus".into(),
+            host:             "localhost".into(),
+            port:             1883,
+            base_topic:       "corvus".into(),
+            discovery_topic:  "homeassistant".into(),
+            discovery_format: vec![DiscoveryFormat::Hass],
+            homie_topic:      "homie".into(),
+            embedded_broker:  false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case", default)]
+pub struct HttpConfiguration {
+    /// Address the status API listens on
+    pub bind: String,
+}
+
+impl Default for HttpConfiguration {
+    fn default() -> Self {
+        HttpConfiguration {
+            bind: "127.0.0.1:8080".into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogTarget {
+    Stderr,
+    Syslog,
+    Journald,
+    File,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case", default)]
+pub struct LoggingConfiguration {
+    /// Level for corvus modules without an entry in `modules`
+    pub level:    LevelFilter,
+    pub format:   LogFormat,
+    pub target:   LogTarget,
+    /// Log file for the `file` target
+    pub path:     String,
+    /// Size in bytes at which the log file is rotated
+    pub max_size: u64,
+    /// Number of rotated log files to keep
+    pub keep:     usize,
+    /// Levels per module path, e.g. `"corvus::plugins::dht" = "trace"`
+    pub modules:  BTreeMap<String, LevelFilter>,
+}
+
+impl Default for LoggingConfiguration {
+    fn default() -> Self {
+        LoggingConfiguration {
+            level:    LevelFilter::Info,
+            format:   LogFormat::Text,
+            target:   LogTarget::Stderr,
+            path:     "/var/log/corvus/corvus.log".into(),
+            max_size: 10 * 1024 * 1024,
+            keep:     5,
+            modules:  Default::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields, rename_all = "snake_case", default)]
+pub struct PluginConfiguration {
+    pub name:    String,
+    #[serde(rename = "definition")]
+    pub plugin:  Arc<PluginDefinition>,
+    pub trigger: Arc<TriggerConfiguration>,
+    /// File this plugin was defined in
+    #[serde(skip)]
+    pub source:  Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, tag = "type", rename_all = "snake_case")]
+pub enum PluginOptions {
+    Command {
+        command: String,
+        args:    Vec<String>,
+    },
+    /// Presence tracking of nearby devices, see `plugins::bluetooth`
+    Bluetooth(BluetoothOptions),
+    #[serde(rename = "dht", alias = "d_h_t")]
+    DHT { device: String, channel: u32 },
+    /// A long running helper speaking line delimited JSON, see `plugins::process`
+    Process {
+        command:      String,
+        #[serde(default)]
+        args:         Vec<String>,
+        /// Failed starts in a row after which the helper is given up on
+        #[serde(default = "default_max_restarts")]
+        max_restarts: u32,
+    },
+    /// A Rhai script given inline or as a file, see `plugins::script`
+    Script {
+        #[serde(default)]
+        script:          Option<String>,
+        #[serde(default)]
+        path:            Option<String>,
+        /// Operations after which a run is aborted
+        #[serde(default = "default_max_operations")]
+        max_operations:  u64,
+        /// Seconds a program started with `command` may run before it is killed
+        #[serde(default = "default_command_timeout")]
+        command_timeout: u64,
+    },
+    /// A WebAssembly component, see `plugins::wasm`
+    Wasm {
+        path:       String,
+        /// Fuel for each call into the component, roughly one unit per instruction
+        #[serde(default = "default_fuel")]
+        fuel:       u64,
+        /// Bytes of linear memory the component may grow to
+        #[serde(default = "default_max_memory")]
+        max_memory: usize,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct BluetoothOptions {
+    /// Devices that get entities of their own
+    pub devices:         Vec<BluetoothDevice>,
+    /// Report how many other devices are nearby as a single sensor
+    pub track_unknown:   bool,
+    /// Smoothing of the signal strength each agent reports for a device
+    pub filter:          RssiFilter,
+    /// Calibration added to the signal strengths reported by the agent at a location
+    pub rssi_offsets:    BTreeMap<String, f64>,
+    /// Signal strength at 1m of devices that neither advertise nor configure their own
+    pub tx_power:        i8,
+    /// Path loss exponent of the distance estimate, 2 in free space and more indoors
+    pub path_loss:       f64,
+    /// Decibels another room has to be stronger by for `hysteresis_secs` before a device moves
+    pub hysteresis_db:   f64,
+    pub hysteresis_secs: u64,
+    /// Seconds since any agent last heard a device before it is reported away
+    pub consider_home:   u64,
+}
+
+impl Default for BluetoothOptions {
+    fn default() -> Self {
+        BluetoothOptions {
+            devices:         vec![],
+            track_unknown:   false,
+            filter:          Default::default(),
+            rssi_offsets:    Default::default(),
+            tx_power:        -59,
+            path_loss:       3.0,
+            hysteresis_db:   4.0,
+            hysteresis_secs: 10,
+            consider_home:   180,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, tag = "type", rename_all = "snake_case")]
+pub enum RssiFilter {
+    /// Raw readings
+    None,
+    /// Exponential moving average, `alpha` is the weight of a new reading
+    Exponential {
+        #[serde(default = "default_alpha")]
+        alpha: f64,
+    },
+    /// One dimensional Kalman filter, noises are variances in dB²
+    Kalman {
+        #[serde(default = "default_process_noise")]
+        process_noise:     f64,
+        #[serde(default = "default_measurement_noise")]
+        measurement_noise: f64,
+    },
+}
+
+impl Default for RssiFilter {
+    fn default() -> Self {
+        RssiFilter::Kalman {
+            process_noise:     default_process_noise(),
+            measurement_noise: default_measurement_noise(),
+        }
+    }
+}
+
+fn default_alpha() -> f64 {
+    0.3
+}
+
+fn default_process_noise() -> f64 {
+    0.5
+}
+
+fn default_measurement_noise() -> f64 {
+    8.0
+}
+
+/// A device tracked by the bluetooth plugin
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BluetoothDevice {
+    #[serde(default)]
+    pub mac:      Option<String>,
+    /// Beacon identity as `ibeacon:<uuid>:<major>:<minor>`, `eddystone:<namespace>:<instance>`
+    /// or `altbeacon:<id>`, for beacons that don't keep their address
+    #[serde(default)]
+    pub beacon:   Option<String>,
+    /// Name used for the entities of this device
+    pub name:     String,
+    #[serde(default)]
+    pub icon:     Option<String>,
+    /// Identity resolving key of a device using resolvable private addresses, whose readings
+    /// are then reported under `mac`
+    #[serde(default)]
+    pub irk:      Option<String>,
+    /// Signal strength at 1m, overriding what the device advertises
+    #[serde(default)]
+    pub tx_power: Option<i8>,
+}
+
+impl BluetoothDevice {
+    /// What readings of this device are kept under, the beacon identity when there is one
+    pub fn key(&self) -> Option<String> {
+        self.beacon
+            .as_ref()
+            .or(self.mac.as_ref())
+            .map(|k| k.to_lowercase())
+    }
+}
+
+fn default_max_restarts() -> u32 {
+    5
+}
+
+fn default_max_operations() -> u64 {
+    100_000
+}
+
+fn default_command_timeout() -> u64 {
+    10
+}
+
+fn default_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_max_memory() -> usize {
+    16 * 1024 * 1024
+}
+
+impl Default for PluginOptions {
+    fn default() -> Self {
+        Self::Bluetooth(Default::default())
+    }
+}
+
+impl PluginOptions {
+    /// Values of `type` naming a built in plugin
+    pub const TYPES: &'static [&'static str] = &[
+        "command",
+        "bluetooth",
+        "dht",
+        "d_h_t",
+        "process",
+        "script",
+        "wasm",
+    ];
+
+    /// Source of a script plugin, read from its file if not given inline
+    pub fn script_source(script: &Option<String>, path: &Option<String>) -> Result<String> {
+        match (script, path) {
+            (Some(script), None) => Ok(script.to_string()),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .with_context(|| format!("Could not read script {}", path)),
+            _ => bail!("Exactly one of 'script' and 'path' is required"),
+        }
+    }
+}
+
+/// The `definition` table of a plugin, either a built in plugin or a type registered with
+/// [`CorvusBuilder::register_plugin`] by a program embedding corvus
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum PluginDefinition {
+    Builtin(PluginOptions),
+    /// The whole table, `type` included, checked against the options of the registered type
+    Registered(toml::Value),
+}
+
+impl PluginDefinition {
+    pub fn plugin_type(&self) -> &str {
+        match self {
+            PluginDefinition::Builtin(PluginOptions::Command { .. }) => "command",
+            PluginDefinition::Builtin(PluginOptions::Bluetooth(_)) => "bluetooth",
+            PluginDefinition::Builtin(PluginOptions::DHT { .. }) => "dht",
+            PluginDefinition::Builtin(PluginOptions::Process { .. }) => "process",
+            PluginDefinition::Builtin(PluginOptions::Script { .. }) => "script",
+            PluginDefinition::Builtin(PluginOptions::Wasm { .. }) => "wasm",
+            PluginDefinition::Registered(value) => value
+                .get("type")
+                .and_then(toml::Value::as_str)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Default for PluginDefinition {
+    fn default() -> Self {
+        Self::Builtin(Default::default())
+    }
+}
+
+impl<'de> Deserialize<'de> for PluginDefinition {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let value = toml::Value::deserialize(deserializer)?;
+        let plugin_type = value.get("type").and_then(toml::Value::as_str);
+        match plugin_type {
+            Some(t) if !PluginOptions::TYPES.contains(&t) => {
+                crate::plugins::check_registered(t, &value).map_err(D::Error::custom)?;
+                Ok(PluginDefinition::Registered(value))
+            }
+            _ => PluginOptions::deserialize(value)
+                .map(PluginDefinition::Builtin)
+                .map_err(D::Error::custom),
+        }
+    }
+}
+
+/// An additional destination for device updates, MQTT always receives every update
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub struct SinkConfiguration {
+    pub name:            String,
+    #[serde(rename = "definition")]
+    pub sink:            SinkOptions,
+    /// Glob patterns matched against device names, when empty every device is included
+    #[serde(default)]
+    pub include_devices: Vec<String>,
+    #[serde(default)]
+    pub exclude_devices: Vec<String>,
+    /// Glob patterns matched against the name of the plugin owning a device
+    #[serde(default)]
+    pub include_plugins: Vec<String>,
+    #[serde(default)]
+    pub exclude_plugins: Vec<String>,
+    /// File this sink was defined in
+    #[serde(skip)]
+    pub source:          Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, tag = "type", rename_all = "snake_case")]
+pub enum SinkOptions {
+    /// Line protocol to an HTTP write endpoint or `udp://host:port`
+    #[serde(rename = "influxdb")]
+    InfluxDB {
+        url:         String,
+        #[serde(default)]
+        token:       Option<String>,
+        #[serde(default = "default_measurement")]
+        measurement: String,
+    },
+    File {
+        path:   String,
+        #[serde(default)]
+        format: SinkFileFormat,
+    },
+    Webhook {
+        url:     String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+}
+
+fn default_measurement() -> String {
+    crate_name!().into()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum SinkFileFormat {
+    #[serde(rename = "csv")]
+    CSV,
+    #[default]
+    #[serde(rename = "ndjson")]
+    NDJSON,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, untagged, rename_all = "snake_case")]
+pub enum TriggerConfiguration {
+    Start { on_start: bool },
+    Interval { interval: u64 },
+    MQTT { mqtt_topic: String },
+}
+
+impl Default for TriggerConfiguration {
+    fn default() -> Self {
+        TriggerConfiguration::Start { on_start: true }
+    }
+}
+
+impl Configuration {
+    pub fn load(files: &[PathBuf]) -> Result<Arc<Self>> {
+        let sources = ConfigSources::read(files)?;
+        let config = sources
+            .resolve()
+            .context("Failed to parse configuration!")?;
+        Ok(Arc::new(config))
+    }
+
+    /// Parse a single configuration document, without includes, interpolation or
+    /// environment overrides
+    pub fn from_toml(contents: &str) -> Result<Arc<Self>> {
+        let config = toml::from_str(contents).context("Failed to parse configuration!")?;
+        Ok(Arc::new(config))
+    }
+}
@@ -0,0 +1,300 @@
+use super::{interpolate::*, *};
+use std::{collections::HashSet, path::Path};
+use toml::Value;
+
+const INCLUDE_KEY: &str = "include";
+const PLUGIN_KEY: &str = "plugin";
+const SINK_KEY: &str = "sink";
+/// Arrays of tables that are appended to rather than replaced when merging files
+const LIST_KEYS: &[&str] = &[PLUGIN_KEY, SINK_KEY];
+
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub path:     PathBuf,
+    pub contents: String,
+    value:        Value,
+}
+
+/// All files making up a configuration, in order of increasing precedence.
+///
+/// Every `--config` file is followed by the files its `include` patterns match, sorted by
+/// name. Later files overlay the `node`, `mqtt` and other tables of earlier ones key by key
+/// and append to the `[[plugin]]` and `[[sink]]` lists. `CORVUS_*` environment overrides
+/// are applied last.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSources(Vec<ConfigSource>);
+
+fn parse_error(path: &Path, e: toml::de::Error) -> Error {
+    let message = e.to_string();
+    let message = message.split(" at line ").next().unwrap_or_default();
+    Diagnostic::error(
+        message.to_string(),
+        e.line_col().map(|(l, c)| (l + 1, c + 1)),
+    )
+    .in_file(path)
+    .into()
+}
+
+fn is_pattern(s: &str) -> bool {
+    s.contains(&['*', '?', '['][..])
+}
+
+/// Recursively merge `overlay` into `base`, `[[plugin]]` and `[[sink]]` arrays are appended
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (k, v) in overlay.into_iter() {
+                match (base.get_mut(&k), v) {
+                    (Some(Value::Array(existing)), Value::Array(items))
+                        if LIST_KEYS.contains(&k.as_str()) =>
+                    {
+                        existing.extend(items)
+                    }
+                    (Some(existing), v) => merge(existing, v),
+                    (None, v) => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+impl ConfigSources {
+    pub fn read(files: &[PathBuf]) -> Result<Self> {
+        let mut sources = ConfigSources::default();
+        let mut seen = HashSet::new();
+        for file in files.iter() {
+            sources.read_file(file, &mut seen)?;
+        }
+        Ok(sources)
+    }
+
+    fn read_file(&mut self, file: &Path, seen: &mut HashSet<PathBuf>) -> Result<()> {
+        let contents = std::fs::read_to_string(file).with_context(|| {
+            format!("Could not load configuration from file {}!", file.display())
+        })?;
+        if !seen.insert(file.canonicalize()?) {
+            warn!("Skipping {}, it was already included", file.display());
+            return Ok(());
+        }
+        let value: Value = toml::from_str(&contents).map_err(|e| parse_error(file, e))?;
+        let includes = match value.get(INCLUDE_KEY) {
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|i| match i {
+                    Value::String(s) => expand_vars(s),
+                    _ => bail!("{}: include entries must be strings", file.display()),
+                })
+                .collect::<Result<Vec<String>>>()?,
+            Some(_) => bail!("{}: include must be a list of paths", file.display()),
+            None => vec![],
+        };
+        self.0.push(ConfigSource {
+            path: file.to_path_buf(),
+            contents,
+            value,
+        });
+
+        let base = file.parent().unwrap_or_else(|| Path::new(""));
+        for pattern in includes.iter() {
+            let pattern = base.join(pattern);
+            let pattern = pattern.to_string_lossy();
+            let mut matches = glob::glob(&pattern)
+                .with_context(|| format!("Invalid include pattern '{}'", pattern))?
+                .collect::<std::result::Result<Vec<PathBuf>, _>>()?;
+            if matches.is_empty() && !is_pattern(&pattern) {
+                bail!("Included file {} does not exist", pattern);
+            }
+            matches.sort();
+            for m in matches.iter() {
+                debug!("Including configuration from {}", m.display());
+                self.read_file(m, seen)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &ConfigSource> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&ConfigSource> {
+        self.0.iter().find(|s| s.path == path)
+    }
+
+    /// Merge all sources into the final configuration
+    pub fn resolve(&self) -> Result<Configuration> {
+        self.resolve_with(std::env::vars())
+    }
+
+    fn resolve_with(&self, vars: impl Iterator<Item = (String, String)>) -> Result<Configuration> {
+        let mut merged = Value::Table(Default::default());
+        let mut plugin_sources = vec![];
+        let mut sink_sources = vec![];
+        for source in self.0.iter() {
+            let mut value = source.value.clone();
+            interpolate(&mut value)
+                .with_context(|| format!("Failed to load {}", source.path.display()))?;
+            if let Some(table) = value.as_table_mut() {
+                table.remove(INCLUDE_KEY);
+            }
+            if let Some(Value::Array(plugins)) = value.get(PLUGIN_KEY) {
+                plugin_sources.extend(plugins.iter().map(|_| source.path.clone()));
+            }
+            if let Some(Value::Array(sinks)) = value.get(SINK_KEY) {
+                sink_sources.extend(sinks.iter().map(|_| source.path.clone()));
+            }
+            merge(&mut merged, value);
+        }
+        apply_overrides(&mut merged, vars)?;
+
+        let mut config: Configuration = merged.try_into()?;
+        for (plugin, source) in config.plugins.iter_mut().zip(plugin_sources) {
+            Arc::make_mut(plugin).source = Some(source);
+        }
+        for (sink, source) in config.sinks.iter_mut().zip(sink_sources) {
+            Arc::make_mut(sink).source = Some(source);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory holding `files`, removed again when dropped
+    struct Files(PathBuf);
+
+    impl Files {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "corvus-sources-{}-{}",
+                name,
+                std::process::id()
+            ));
+            for (file, contents) in files.iter() {
+                let path = dir.join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, contents).unwrap();
+            }
+            Files(dir)
+        }
+
+        fn read(&self, file: &str) -> ConfigSources {
+            ConfigSources::read(&[self.0.join(file)]).unwrap()
+        }
+
+        fn names(&self, sources: &ConfigSources) -> Vec<String> {
+            sources
+                .iter()
+                .map(|s| s.path.strip_prefix(&self.0).unwrap().display().to_string())
+                .collect()
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn plugin(name: &str) -> String {
+        format!(
+            "[[plugin]]\nname = \"{}\"\n[plugin.definition]\ntype = \"command\"\n\
+             command = \"true\"\nargs = []\n",
+            name
+        )
+    }
+
+    fn plugins(config: &Configuration) -> Vec<&str> {
+        config.plugins.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn overrides_apply_in_order() {
+        let main = format!(
+            "include = [\"conf.d/*.toml\"]\n\
+             [node]\nlocation = \"kitchen\"\n\
+             [mqtt]\nhost = \"main\"\nport = 1884\nbase_topic = \"main\"\n{}",
+            plugin("first")
+        );
+        let site = format!(
+            "[mqtt]\nhost = \"included\"\nport = 1885\n{}",
+            plugin("second")
+        );
+        let files = Files::new(
+            "precedence",
+            &[("main.toml", &main), ("conf.d/site.toml", &site)],
+        );
+        let sources = files.read("main.toml");
+        assert_eq!(files.names(&sources), ["main.toml", "conf.d/site.toml"]);
+
+        let vars = vec![("CORVUS_MQTT_PORT".to_string(), "1886".to_string())];
+        let config = sources.resolve_with(vars.into_iter()).unwrap();
+        assert_eq!(config.node.location, "kitchen");
+        assert_eq!(config.mqtt.base_topic, "main");
+        assert_eq!(config.mqtt.host, "included");
+        assert_eq!(config.mqtt.port, 1886);
+        assert_eq!(plugins(&config), ["first", "second"]);
+        let source = |i: usize| config.plugins[i].source.clone().unwrap();
+        assert_eq!(source(0), files.0.join("main.toml"));
+        assert_eq!(source(1), files.0.join("conf.d/site.toml"));
+    }
+
+    #[test]
+    fn include_cycles_are_read_once() {
+        let a = format!(
+            "include = [\"b.toml\"]\n[node]\nlocation = \"a\"\n[mqtt]\n{}",
+            plugin("a")
+        );
+        let b = format!(
+            "include = [\"a.toml\"]\n[node]\nlocation = \"b\"\n{}",
+            plugin("b")
+        );
+        let files = Files::new("cycle", &[("a.toml", &a), ("b.toml", &b)]);
+        let sources = files.read("a.toml");
+        assert_eq!(files.names(&sources), ["a.toml", "b.toml"]);
+
+        let config = sources.resolve_with(std::iter::empty()).unwrap();
+        assert_eq!(config.node.location, "b");
+        assert_eq!(plugins(&config), ["a", "b"]);
+    }
+
+    #[test]
+    fn duplicate_includes_are_read_once() {
+        let main = "include = [\"extra/one.toml\", \"extra/*.toml\", \"./extra/one.toml\"]\n\
+                    [node]\nlocation = \"hall\"\n[mqtt]\n";
+        let files = Files::new(
+            "duplicate",
+            &[
+                ("main.toml", main),
+                ("extra/one.toml", &plugin("one")),
+                ("extra/two.toml", &plugin("two")),
+            ],
+        );
+        let sources = files.read("main.toml");
+        assert_eq!(
+            files.names(&sources),
+            ["main.toml", "extra/one.toml", "extra/two.toml"]
+        );
+        let config = sources.resolve_with(std::iter::empty()).unwrap();
+        assert_eq!(plugins(&config), ["one", "two"]);
+    }
+
+    #[test]
+    fn missing_includes_fail_unless_a_pattern() {
+        let files = Files::new(
+            "missing",
+            &[
+                ("glob.toml", "include = [\"conf.d/*.toml\"]\n"),
+                ("file.toml", "include = [\"conf.d/site.toml\"]\n"),
+            ],
+        );
+        assert_eq!(files.names(&files.read("glob.toml")), ["glob.toml"]);
+        let err = ConfigSources::read(&[files.0.join("file.toml")]).unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{}", err);
+    }
+}
@@ -0,0 +1,318 @@
+use super::*;
+
+/// Answers used to fill in the annotated configuration template
+#[derive(Clone, Debug)]
+pub struct TemplateOptions {
+    pub location:  String,
+    pub mqtt:      MQTTConfiguration,
+    pub bluetooth: bool,
+    pub command:   bool,
+    pub dht:       Option<(String, u32)>,
+}
+
+impl Default for TemplateOptions {
+    fn default() -> Self {
+        TemplateOptions {
+            location:  NodeConfiguration::default().location,
+            mqtt:      Default::default(),
+            bluetooth: true,
+            command:   false,
+            dht:       None,
+        }
+    }
+}
+
+/// A TOML string holding `s`, with `$` escaped so it survives interpolation
+fn quote(s: &str) -> String {
+    toml::Value::String(s.replace('$', "$$")).to_string()
+}
+
+/// Comment out every line of a block when it isn't enabled
+fn block(enabled: bool, text: &str) -> String {
+    if enabled {
+        return text.to_string();
+    }
+    text.lines()
+        .map(|l| {
+            if l.is_empty() || l.starts_with('#') {
+                format!("{}\n", l)
+            } else {
+                format!("# {}\n", l)
+            }
+        })
+        .collect()
+}
+
+impl Configuration {
+    /// Render a commented configuration documenting every option, plugin and trigger
+    pub fn template(opts: &TemplateOptions) -> String {
+        let (dht_device, dht_channel) = opts
+            .dht
+            .clone()
+            .unwrap_or_else(|| ("/dev/gpiochip0".into(), 4));
+        let mut out = String::new();
+
+        out.push_str(&format!(
+            r#"# Corvus agent configuration
+#
+# Validate changes with `corvus -c <file> check`, a running agent reloads
+# its plugins on SIGHUP.
+#
+# Any string may reference environment variables as ${{VAR}} or
+# ${{VAR:-default}} ($$ for a literal $), or be read from a file with
+# "file:/path/to/secret". Fields of [node] and [mqtt] can be overridden with
+# CORVUS_<SECTION>_<FIELD> variables, e.g. CORVUS_MQTT_HOST.
+
+# Further files to merge into this one, relative to this file. Each file is
+# applied after the one including it: it overrides [node] and [mqtt] values
+# and adds its plugins and sinks to the lists. Repeated --config flags merge the same way.
+# include = ["conf.d/*.toml"]
+
+[node]
+# Name of the room or area this agent is placed in. Used in entity names,
+# topics and as the room reported for bluetooth presence.
+location = {location}
+
+[mqtt]
+# Client identifier, must be unique per agent on the broker
+client_id = {client_id}
+# Broker host name or address
+host = {host}
+# Broker port
+port = {port}
+# Prefix for all state, attribute and cluster topics
+base_topic = {base_topic}
+# Home Assistant discovery prefix
+discovery_topic = {discovery_topic}
+# Conventions devices are announced in, "hass" and/or "homie". Homie devices
+# are published below homie_topic, one per agent plus one for cluster wide
+# devices, with each plugin as a node.
+# discovery_format = ["hass"]
+# homie_topic = "homie"
+# Run a minimal MQTT broker on `port` of every interface, for deployments
+# without a central one. Other agents and Home Assistant connect to this
+# node, set host = "localhost" here. It has no authentication.
+# embedded_broker = false
+
+# Local status API with /health, /devices, /plugins and /cluster endpoints
+# and Prometheus metrics on /metrics. /health answers 503 while MQTT is down
+# or a service keeps failing.
+# [http]
+# bind = "127.0.0.1:8080"
+
+# Log output, these are the defaults. `format` is "text" or "json", `target`
+# one of "stderr", "syslog", "journald" or "file". Files are rotated once they
+# reach max_size bytes, keeping `keep` old files. `modules` sets levels for
+# individual modules, including other crates which are silent by default.
+# [logging]
+# level = "info"
+# format = "text"
+# target = "stderr"
+# path = "/var/log/corvus/corvus.log"
+# max_size = 10485760
+# keep = 5
+# modules = {{ "corvus::plugins::dht" = "trace" }}
+
+# Sinks receive device updates in addition to MQTT. Each needs a unique name
+# and a [sink.definition]; the include/exclude lists take glob patterns
+# matched against device and plugin names, empty include lists match all.
+#
+# InfluxDB line protocol over HTTP(S) or to a udp://host:port listener:
+# [[sink]]
+# name = "influx"
+# exclude_plugins = ["bluetooth"]
+# [sink.definition]
+# type = "influxdb"
+# url = "http://localhost:8086/api/v2/write?org=home&bucket=corvus"
+# token = "file:/run/secrets/influxdb_token"
+# measurement = "corvus"
+#
+# Local log of every update, format "csv" or "ndjson":
+# [[sink]]
+# name = "history"
+# [sink.definition]
+# type = "file"
+# path = "/var/lib/corvus/updates.ndjson"
+# format = "ndjson"
+#
+# JSON POST of each update:
+# [[sink]]
+# name = "webhook"
+# include_devices = ["*temperature*"]
+# [sink.definition]
+# type = "webhook"
+# url = "https://example.com/hook"
+# headers = {{ Authorization = "Bearer ${{WEBHOOK_TOKEN}}" }}
+
+# Each [[plugin]] needs a unique name, a [plugin.definition] selecting the
+# plugin type and optionally a trigger deciding when the plugin runs:
+#
+#   trigger = {{ on_start = true }}     start with the agent, restart when done (default)
+#   trigger = {{ interval = 60 }}       run every 60 seconds
+#   trigger = {{ mqtt_topic = "..." }}  run on MQTT messages (not supported yet)
+#
+# A long running helper in any language can act as a plugin, exchanging line
+# delimited JSON over its stdin and stdout. It is restarted with a growing delay
+# when it fails and given up on after max_restarts failures in a row:
+# [[plugin]]
+# name = "weather"
+# [plugin.definition]
+# type = "process"
+# command = "/usr/local/bin/weather-helper"
+# args = ["--station", "home"]
+#
+# Small glue can be written as a Rhai script instead, inline or with `path`.
+# Unlike other values, inline scripts are not expanded, `${{...}}` is Rhai's:
+# [[plugin]]
+# name = "attic"
+# trigger = {{ interval = 60 }}
+# [plugin.definition]
+# type = "script"
+# script = '''
+# device("Attic", #{{ class: "temperature", unit: "°C" }});
+# let raw = read_file("/sys/bus/w1/devices/28-0316a2794aff/temperature");
+# publish("Attic", parse_int(raw.trim()) / 1000.0);
+# '''
+#
+# WebAssembly components implementing wit/plugin.wit run unchanged on every
+# 64 bit platform, with fuel and memory limits for each call:
+# [[plugin]]
+# name = "meter"
+# trigger = {{ interval = 30 }}
+# [plugin.definition]
+# type = "wasm"
+# path = "/var/lib/corvus/plugins/meter.wasm"
+# fuel = 10000000
+# max_memory = 16777216
+
+"#,
+            location = quote(&opts.location),
+            client_id = quote(&opts.mqtt.client_id),
+            host = quote(&opts.mqtt.host),
+            port = opts.mqtt.port,
+            base_topic = quote(&opts.mqtt.base_topic),
+            discovery_topic = quote(&opts.mqtt.discovery_topic),
+        ));
+
+        out.push_str(&block(
+            opts.bluetooth,
+            r#"# Bluetooth: reports the signal strength of the listed devices. The cluster
+# leader combines readings from all agents into a location and a home/not_home
+# device tracker per device. Other devices nearby are only counted when
+# track_unknown is set. Phones rotating their address are recognised with
+# their identity resolving key, beacons by their iBeacon, Eddystone or
+# AltBeacon identity. Readings of BTHome, ATC/pvvx, RuuviTag and Govee
+# thermometers in the list are published by whichever agent hears them best.
+[[plugin]]
+name = "bluetooth"
+trigger = { on_start = true }
+
+[plugin.definition]
+type = "bluetooth"
+track_unknown = false
+# devices = [
+#   { mac = "AA:BB:CC:DD:EE:FF", name = "Phone", icon = "mdi:cellphone" },
+#   { mac = "11:22:33:44:55:66", name = "Watch", irk = "ec0234a357c8ad05341010a60a397d9b" },
+#   { beacon = "ibeacon:f7826da6-4fa2-4e98-8024-bc5b71e0893e:1:42", name = "Keys" },
+# ]
+# Signal strengths are smoothed per agent ("kalman", "exponential" or "none")
+# and a device only changes rooms once another one is stronger by
+# hysteresis_db for hysteresis_secs. Distances are estimated from the power
+# at 1m a device advertises, its own tx_power or the default below.
+# filter = { type = "kalman", process_noise = 0.5, measurement_noise = 8.0 }
+# rssi_offsets = { garage = -5.0 }
+# tx_power = -59
+# path_loss = 3.0
+# hysteresis_db = 4.0
+# hysteresis_secs = 10
+# Devices no agent has heard for consider_home seconds are reported as
+# not_home by their device tracker and location.
+# consider_home = 180
+
+"#,
+        ));
+
+        out.push_str(&block(
+            opts.dht.is_some(),
+            &format!(
+                r#"# DHT: reads temperature and humidity from a DHT22 sensor attached to a
+# GPIO line. `device` is the GPIO character device, `channel` the line offset.
+[[plugin]]
+name = "climate"
+trigger = {{ interval = 60 }}
+
+[plugin.definition]
+type = "dht"
+device = {}
+channel = {}
+
+"#,
+                quote(&dht_device),
+                dht_channel
+            ),
+        ));
+
+        out.push_str(&block(
+            opts.command,
+            r#"# Command: runs a program and publishes its trimmed stdout as the state,
+# with the exit status, stdout and stderr as attributes.
+[[plugin]]
+name = "uptime"
+trigger = { interval = 300 }
+
+[plugin.definition]
+type = "command"
+command = "uptime"
+args = ["-p"]
+"#,
+        ));
+        out
+    }
+
+    pub fn generate_default(file: PathBuf) -> Result<()> {
+        Self::generate(file, &Default::default())
+    }
+
+    pub fn generate(file: PathBuf, opts: &TemplateOptions) -> Result<()> {
+        let path = file
+            .clone()
+            .into_os_string()
+            .into_string()
+            .unwrap_or_default();
+        let content = Self::template(opts);
+        let mut f = std::fs::File::create(file)
+            .with_context(|| format!("Could not create file {}!", path))?;
+        f.write_all(content.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_with_all_sections_parses() {
+        let opts = TemplateOptions {
+            location:  r#"Bob's "den" \ $HOME"#.into(),
+            mqtt:      MQTTConfiguration {
+                host: r"broker\".into(),
+                ..Default::default()
+            },
+            bluetooth: true,
+            command:   true,
+            dht:       Some((r#"/dev/"gpio""#.into(), 17)),
+        };
+        let path =
+            std::env::temp_dir().join(format!("corvus-template-{}.toml", std::process::id()));
+        std::fs::write(&path, Configuration::template(&opts)).unwrap();
+        let config = Configuration::load(std::slice::from_ref(&path));
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(config.node.location, opts.location);
+        assert_eq!(config.mqtt.host, opts.mqtt.host);
+        let names: Vec<&str> = config.plugins.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["bluetooth", "climate", "uptime"]);
+    }
+}
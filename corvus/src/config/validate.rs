@@ -0,0 +1,786 @@
+use super::*;
+use linux_embedded_hal::gpio_cdev::Chip;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Display)]
+pub enum Severity {
+    #[display(fmt = "error")]
+    Error,
+    #[display(fmt = "warning")]
+    Warning,
+}
+
+/// A single problem found in a configuration file, positions are 1-based
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message:  String,
+    pub file:     Option<PathBuf>,
+    pub position: Option<(usize, usize)>,
+}
+
+impl std::error::Error for Diagnostic {}
+
+impl Diagnostic {
+    pub(super) fn error(message: String, position: Option<(usize, usize)>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            file: None,
+            message,
+            position,
+        }
+    }
+
+    fn warning(message: String, position: Option<(usize, usize)>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            file: None,
+            message,
+            position,
+        }
+    }
+
+    pub(super) fn in_file(mut self, file: &Path) -> Self {
+        self.file = Some(file.to_path_buf());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        if let Some((line, col)) = self.position {
+            write!(f, "{}:{}:", line, col)?;
+        }
+        write!(f, " {}: {}", self.severity, self.message)
+    }
+}
+
+/// Find the lines assigning `key`, optionally only where the value is `value`
+fn locate_all(contents: &str, key: &str, value: Option<&str>) -> Vec<(usize, usize)> {
+    let mut result = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let mut parts = trimmed.splitn(2, '=');
+        let (k, v) = (parts.next().unwrap_or_default().trim(), parts.next());
+        let matched = match (value, v) {
+            (None, _) => k == key,
+            (Some(value), Some(v)) => k == key && v.trim().trim_matches('"') == value,
+            _ => false,
+        };
+        if matched {
+            result.push((i + 1, line.len() - trimmed.len() + 1));
+        }
+    }
+    result
+}
+
+fn locate(contents: &str, key: &str, value: Option<&str>) -> Option<(usize, usize)> {
+    locate_all(contents, key, value).into_iter().next()
+}
+
+/// Like `locate_all`, but only keys set directly in a `[[<table>]]` entry
+fn locate_entries(
+    contents: &str,
+    table: &str,
+    key: &str,
+    value: Option<&str>,
+) -> Vec<(usize, usize)> {
+    let header = format!("[[{}]]", table);
+    let lines: Vec<&str> = contents.lines().collect();
+    let in_table = |line: usize| {
+        lines[..line]
+            .iter()
+            .map(|l| l.split('#').next().unwrap_or_default().trim())
+            .rfind(|l| l.starts_with('['))
+            .map(|l| l.chars().filter(|c| !c.is_whitespace()).collect::<String>() == header)
+            .unwrap_or_default()
+    };
+    locate_all(contents, key, value)
+        .into_iter()
+        .filter(|(line, _)| in_table(*line))
+        .collect()
+}
+
+/// Like `locate`, but only on the given lines
+fn locate_in(
+    contents: &str,
+    lines: &Range<usize>,
+    key: &str,
+    value: Option<&str>,
+) -> Option<(usize, usize)> {
+    locate_all(contents, key, value)
+        .into_iter()
+        .find(|(line, _)| lines.contains(line))
+}
+
+/// Lines of the `[[<table>]]` entry containing `line`, up to the next entry of the same array.
+/// Without a line to start from nothing can be located.
+fn entry_lines(contents: &str, table: &str, line: Option<usize>) -> Range<usize> {
+    let line = match line {
+        Some(line) => line,
+        None => return 0..0,
+    };
+    let header = format!("[[{}]]", table);
+    let is_header = |l: &&str| {
+        let l = l.split('#').next().unwrap_or_default();
+        l.chars().filter(|c| !c.is_whitespace()).collect::<String>() == header
+    };
+    let lines: Vec<&str> = contents.lines().collect();
+    let start = lines[..line]
+        .iter()
+        .rposition(is_header)
+        .map_or(1, |i| i + 1);
+    let end = lines[line..]
+        .iter()
+        .position(is_header)
+        .map_or(lines.len() + 1, |i| line + i + 1);
+    start..end
+}
+
+fn command_exists(command: &str) -> bool {
+    if command.contains('/') {
+        return Path::new(command).is_file();
+    }
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|p| p.join(command).is_file()))
+        .unwrap_or_default()
+}
+
+/// Six colon separated pairs of hex digits
+fn valid_mac(mac: &str) -> bool {
+    let parts: Vec<&str> = mac.split(':').collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// 128 bits as hex digits
+fn valid_irk(irk: &str) -> bool {
+    valid_hex(irk, 32)
+}
+
+fn valid_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// One of the beacon identities documented on `BluetoothDevice::beacon`
+fn valid_beacon(beacon: &str) -> bool {
+    let parts: Vec<&str> = beacon.split(':').collect();
+    match parts.as_slice() {
+        ["ibeacon", uuid, major, minor] => {
+            let groups: Vec<&str> = uuid.split('-').collect();
+            groups.len() == 5
+                && groups
+                    .iter()
+                    .zip(&[8, 4, 4, 4, 12])
+                    .all(|(g, &l)| valid_hex(g, l))
+                && major.parse::<u16>().is_ok()
+                && minor.parse::<u16>().is_ok()
+        }
+        ["eddystone", namespace, instance] => valid_hex(namespace, 20) && valid_hex(instance, 12),
+        ["altbeacon", id] => valid_hex(id, 40),
+        _ => false,
+    }
+}
+
+impl Configuration {
+    /// Load and validate configuration files without starting anything
+    pub fn check(files: &[PathBuf]) -> Vec<Diagnostic> {
+        let sources = match ConfigSources::read(files) {
+            Ok(s) => s,
+            Err(e) => return vec![Self::to_diagnostic(e)],
+        };
+        let err = match sources.resolve() {
+            Ok(config) => return config.validate(&sources),
+            Err(e) => e,
+        };
+        // Errors from the merged document have no position, parsing the raw contents of each
+        // file again finds the position of the same error if it is local to that file.
+        let message = |m: String| {
+            let m = m.split(" for key ").next().unwrap_or_default();
+            m.split(" at line ").next().unwrap_or_default().to_string()
+        };
+        let merged = message(format!("{:#}", err));
+        for source in sources.iter() {
+            if let Err(e) = toml::from_str::<Configuration>(&source.contents) {
+                if message(e.to_string()) == merged {
+                    return vec![Diagnostic::error(
+                        merged,
+                        e.line_col().map(|(l, c)| (l + 1, c + 1)),
+                    )
+                    .in_file(&source.path)];
+                }
+            }
+        }
+        vec![Self::to_diagnostic(err)]
+    }
+
+    fn to_diagnostic(e: Error) -> Diagnostic {
+        match e.downcast::<Diagnostic>() {
+            Ok(d) => d,
+            Err(e) => Diagnostic::error(format!("{:#}", e), None),
+        }
+    }
+
+    /// Check constraints that deserialization alone can't express. `sources` is only used to
+    /// point diagnostics at the offending line.
+    pub fn validate(&self, sources: &ConfigSources) -> Vec<Diagnostic> {
+        let mut result = vec![];
+        self.validate_mqtt(sources, &mut result);
+        self.validate_http(sources, &mut result);
+        self.validate_logging(sources, &mut result);
+        self.validate_sinks(sources, &mut result);
+
+        let mut names: HashMap<&str, usize> = HashMap::new();
+        let mut occurrences: HashMap<(&Option<PathBuf>, &str), usize> = HashMap::new();
+        let mut dht_lines: HashMap<(String, u32), String> = HashMap::new();
+        let mut bluetooth: Option<String> = None;
+        for plugin in self.plugins.iter() {
+            let start = result.len();
+            let contents = plugin
+                .source
+                .as_ref()
+                .and_then(|p| sources.get(p))
+                .map(|s| s.contents.as_str())
+                .unwrap_or_default();
+            let occurrence = occurrences
+                .entry((&plugin.source, &plugin.name))
+                .or_default();
+            let pos = locate_entries(contents, "plugin", "name", Some(&plugin.name))
+                .get(*occurrence)
+                .cloned();
+            *occurrence += 1;
+            let lines = entry_lines(contents, "plugin", pos.map(|(line, _)| line));
+            let locate = |key: &str, value: Option<&str>| locate_in(contents, &lines, key, value);
+            let seen = names.entry(&plugin.name).or_default();
+            *seen += 1;
+            if plugin.name.is_empty() {
+                result.push(Diagnostic::error(
+                    "Plugin name must not be empty".into(),
+                    pos,
+                ));
+            } else if *seen > 1 {
+                result.push(Diagnostic::error(
+                    format!("Duplicate plugin name '{}'", plugin.name),
+                    pos,
+                ));
+            }
+
+            match &*plugin.trigger {
+                TriggerConfiguration::Interval { interval } if *interval == 0 => {
+                    result.push(Diagnostic::error(
+                        format!("Plugin '{}' has an interval of 0 seconds", plugin.name),
+                        locate("interval", Some("0")).or(pos),
+                    ))
+                }
+                TriggerConfiguration::MQTT { .. } => result.push(Diagnostic::error(
+                    format!(
+                        "Plugin '{}' uses an mqtt_topic trigger, which is not supported yet",
+                        plugin.name
+                    ),
+                    locate("mqtt_topic", None).or(pos),
+                )),
+                _ => (),
+            }
+
+            match &*plugin.plugin {
+                PluginDefinition::Builtin(PluginOptions::Command { command, .. })
+                | PluginDefinition::Builtin(PluginOptions::Process { command, .. }) => {
+                    if !command_exists(command) {
+                        result.push(Diagnostic::error(
+                            format!(
+                                "Plugin '{}' runs '{}', which could not be found",
+                                plugin.name, command
+                            ),
+                            locate("command", Some(command)).or(pos),
+                        ));
+                    }
+                }
+                PluginDefinition::Builtin(PluginOptions::Bluetooth(BluetoothOptions {
+                    devices,
+                    ..
+                })) => {
+                    let mut keys = HashSet::new();
+                    for device in devices.iter() {
+                        let pos = locate("name", Some(&device.name)).or(pos);
+                        let mut error = |msg: String| {
+                            result.push(Diagnostic::error(
+                                format!(
+                                    "Plugin '{}' device '{}' {}",
+                                    plugin.name, device.name, msg
+                                ),
+                                pos,
+                            ))
+                        };
+                        match (&device.mac, &device.beacon) {
+                            (None, None) => error("needs a mac or beacon".into()),
+                            (Some(mac), _) if !valid_mac(mac) => {
+                                error(format!("has an invalid MAC address '{}'", mac))
+                            }
+                            (_, Some(beacon)) if !valid_beacon(beacon) => {
+                                error(format!("has an invalid beacon identity '{}'", beacon))
+                            }
+                            _ => (),
+                        }
+                        match &device.irk {
+                            Some(_) if device.mac.is_none() => {
+                                error("needs the mac its key resolves to".into())
+                            }
+                            Some(irk) if !valid_irk(irk) => {
+                                error("has an invalid key, expected 32 hex digits".into())
+                            }
+                            _ => (),
+                        }
+                        if let Some(key) = device.key() {
+                            if !keys.insert(key) {
+                                error("is listed more than once".into());
+                            }
+                        }
+                    }
+                    if let Some(other) = &bluetooth {
+                        result.push(Diagnostic::warning(
+                            format!(
+                                "Plugins '{}' and '{}' both use the bluetooth controller",
+                                other, plugin.name
+                            ),
+                            pos,
+                        ));
+                    }
+                    bluetooth = Some(plugin.name.to_string());
+                }
+                PluginDefinition::Builtin(PluginOptions::DHT { device, channel }) => {
+                    let pos = locate("device", Some(device)).or(pos);
+                    if let Some(other) =
+                        dht_lines.insert((device.to_string(), *channel), plugin.name.to_string())
+                    {
+                        result.push(Diagnostic::error(
+                            format!(
+                                "Plugins '{}' and '{}' both use line {} of {}",
+                                other, plugin.name, channel, device
+                            ),
+                            pos,
+                        ));
+                    }
+                    Self::validate_gpio_line(&plugin.name, device, *channel, pos, &mut result);
+                }
+                PluginDefinition::Builtin(PluginOptions::Script { script, path, .. }) => {
+                    let pos = path.as_ref().and_then(|p| locate("path", Some(p))).or(pos);
+                    let compiled = PluginOptions::script_source(script, path).and_then(|s| {
+                        rhai::Engine::new()
+                            .compile(&s)
+                            .map_err(|e| anyhow!("{}", e))
+                    });
+                    if let Err(e) = compiled {
+                        result.push(Diagnostic::error(
+                            format!("Plugin '{}' has an invalid script: {:#}", plugin.name, e),
+                            pos,
+                        ));
+                    }
+                }
+                PluginDefinition::Builtin(PluginOptions::Wasm { path, .. }) => {
+                    if !std::path::Path::new(path).is_file() {
+                        result.push(Diagnostic::error(
+                            format!(
+                                "Plugin '{}' loads {}, which does not exist",
+                                plugin.name, path
+                            ),
+                            locate("path", Some(path)).or(pos),
+                        ));
+                    }
+                }
+                // Options of registered types were checked while parsing
+                PluginDefinition::Registered(_) => (),
+            }
+
+            if let Some(source) = &plugin.source {
+                for d in result[start..].iter_mut() {
+                    d.file = Some(source.clone());
+                }
+            }
+        }
+        result
+    }
+
+    /// Report against the file with the highest precedence setting the key
+    fn locate_error(sources: &ConfigSources, message: String, key: &str) -> Diagnostic {
+        for source in sources.iter().rev() {
+            if let Some(pos) = locate(&source.contents, key, None) {
+                return Diagnostic::error(message, Some(pos)).in_file(&source.path);
+            }
+        }
+        Diagnostic::error(message, None)
+    }
+
+    fn validate_http(&self, sources: &ConfigSources, result: &mut Vec<Diagnostic>) {
+        if let Some(http) = &self.http {
+            if http.bind.parse::<SocketAddr>().is_err() {
+                result.push(Self::locate_error(
+                    sources,
+                    format!("HTTP bind '{}' is not a valid address and port", http.bind),
+                    "bind",
+                ));
+            }
+        }
+    }
+
+    fn validate_logging(&self, sources: &ConfigSources, result: &mut Vec<Diagnostic>) {
+        if self.logging.target != LogTarget::File {
+            return;
+        }
+        let dir = Path::new(&self.logging.path)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if !dir.is_dir() {
+            result.push(Self::locate_error(
+                sources,
+                format!("Log directory {} does not exist", dir.display()),
+                "path",
+            ));
+        }
+        if self.logging.max_size == 0 {
+            result.push(Self::locate_error(
+                sources,
+                "Log max_size must not be 0".into(),
+                "max_size",
+            ));
+        }
+    }
+
+    fn validate_sinks(&self, sources: &ConfigSources, result: &mut Vec<Diagnostic>) {
+        let mut names: HashMap<&str, usize> = HashMap::new();
+        let mut occurrences: HashMap<(&Option<PathBuf>, &str), usize> = HashMap::new();
+        for sink in self.sinks.iter() {
+            let start = result.len();
+            let contents = sink
+                .source
+                .as_ref()
+                .and_then(|p| sources.get(p))
+                .map(|s| s.contents.as_str())
+                .unwrap_or_default();
+            let occurrence = occurrences.entry((&sink.source, &sink.name)).or_default();
+            let pos = locate_entries(contents, "sink", "name", Some(&sink.name))
+                .get(*occurrence)
+                .cloned();
+            *occurrence += 1;
+            let lines = entry_lines(contents, "sink", pos.map(|(line, _)| line));
+            let locate = |key: &str, value: Option<&str>| locate_in(contents, &lines, key, value);
+            let seen = names.entry(&sink.name).or_default();
+            *seen += 1;
+            if sink.name.is_empty() {
+                result.push(Diagnostic::error("Sink name must not be empty".into(), pos));
+            } else if *seen > 1 {
+                result.push(Diagnostic::error(
+                    format!("Duplicate sink name '{}'", sink.name),
+                    pos,
+                ));
+            }
+
+            let filters = sink
+                .include_devices
+                .iter()
+                .chain(sink.exclude_devices.iter())
+                .chain(sink.include_plugins.iter())
+                .chain(sink.exclude_plugins.iter());
+            for pattern in filters {
+                if let Err(e) = glob::Pattern::new(pattern) {
+                    result.push(Diagnostic::error(
+                        format!(
+                            "Sink '{}' has an invalid pattern '{}': {}",
+                            sink.name, pattern, e
+                        ),
+                        locate(pattern, None).or(pos),
+                    ));
+                }
+            }
+
+            let url_error = |url: &str, schemes: &[&str]| {
+                let scheme = url.split("://").next().filter(|_| url.contains("://"));
+                match scheme {
+                    Some(scheme) if schemes.contains(&scheme) => None,
+                    _ => Some(Diagnostic::error(
+                        format!(
+                            "Sink '{}' URL '{}' must start with one of {}",
+                            sink.name,
+                            url,
+                            schemes
+                                .iter()
+                                .map(|s| format!("{}://", s))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                        locate("url", Some(url)).or(pos),
+                    )),
+                }
+            };
+            match &sink.sink {
+                SinkOptions::InfluxDB { url, .. } => {
+                    result.extend(url_error(url, &["http", "https", "udp"]))
+                }
+                SinkOptions::Webhook { url, .. } => {
+                    result.extend(url_error(url, &["http", "https"]))
+                }
+                SinkOptions::File { path, .. } => {
+                    let dir = Path::new(path)
+                        .parent()
+                        .filter(|p| !p.as_os_str().is_empty())
+                        .unwrap_or_else(|| Path::new("."));
+                    if !dir.is_dir() {
+                        result.push(Diagnostic::error(
+                            format!(
+                                "Sink '{}' directory {} does not exist",
+                                sink.name,
+                                dir.display()
+                            ),
+                            locate("path", Some(path)).or(pos),
+                        ));
+                    }
+                }
+            }
+
+            if let Some(source) = &sink.source {
+                for d in result[start..].iter_mut() {
+                    d.file = Some(source.clone());
+                }
+            }
+        }
+    }
+
+    fn validate_mqtt(&self, sources: &ConfigSources, result: &mut Vec<Diagnostic>) {
+        let error = |message: String, key: &str| Self::locate_error(sources, message, key);
+        if self.mqtt.host.is_empty() {
+            result.push(error("MQTT host must not be empty".into(), "host"));
+        }
+        if self.mqtt.port == 0 {
+            result.push(error("MQTT port must not be 0".into(), "port"));
+        }
+        for (key, topic) in [
+            ("base_topic", &self.mqtt.base_topic),
+            ("discovery_topic", &self.mqtt.discovery_topic),
+            ("homie_topic", &self.mqtt.homie_topic),
+        ]
+        .iter()
+        {
+            if topic.is_empty() || topic.contains(&['+', '#'][..]) {
+                result.push(error(
+                    format!("MQTT {} '{}' is not a valid topic prefix", key, topic),
+                    key,
+                ));
+            }
+        }
+        let local = ["localhost", "127.0.0.1", "::1"];
+        if self.mqtt.embedded_broker && !local.contains(&self.mqtt.host.as_str()) {
+            let mut warning = error(
+                format!(
+                    "MQTT host '{}' is not local, the agent won't use its embedded broker",
+                    self.mqtt.host
+                ),
+                "host",
+            );
+            warning.severity = Severity::Warning;
+            result.push(warning);
+        }
+        if self.mqtt.discovery_format.is_empty() {
+            let mut warning = error(
+                "MQTT discovery_format is empty, devices will not be discovered".into(),
+                "discovery_format",
+            );
+            warning.severity = Severity::Warning;
+            result.push(warning);
+        }
+    }
+
+    fn validate_gpio_line(
+        name: &str,
+        device: &str,
+        channel: u32,
+        pos: Option<(usize, usize)>,
+        result: &mut Vec<Diagnostic>,
+    ) {
+        let mut chip = match Chip::new(device) {
+            Ok(chip) => chip,
+            Err(e) => {
+                result.push(Diagnostic::error(
+                    format!("Plugin '{}' cannot open GPIO chip {}: {}", name, device, e),
+                    pos,
+                ));
+                return;
+            }
+        };
+        if channel >= chip.num_lines() {
+            result.push(Diagnostic::error(
+                format!(
+                    "Plugin '{}' uses line {} but {} only has {} lines",
+                    name,
+                    channel,
+                    device,
+                    chip.num_lines()
+                ),
+                pos,
+            ));
+            return;
+        }
+        if let Ok(info) = chip.get_line(channel).and_then(|l| l.info()) {
+            if info.is_used() {
+                result.push(Diagnostic::warning(
+                    format!(
+                        "Line {} of {} is currently in use by '{}'",
+                        channel,
+                        device,
+                        info.consumer().unwrap_or("unknown")
+                    ),
+                    pos,
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "[node]\nlocation = \"kitchen\"\n\n[mqtt]\n";
+
+    fn check(name: &str, contents: &str) -> Vec<(String, Option<(usize, usize)>)> {
+        let path = std::env::temp_dir().join(format!("corvus-check-{}.toml", name));
+        std::fs::write(&path, format!("{}{}", HEADER, contents)).unwrap();
+        let diagnostics = Configuration::check(std::slice::from_ref(&path));
+        std::fs::remove_file(&path).unwrap();
+        diagnostics
+            .into_iter()
+            .map(|d| {
+                assert_eq!(d.file.as_ref(), Some(&path), "{}", d);
+                (d.message, d.position)
+            })
+            .collect()
+    }
+
+    /// Nine lines, the first one empty
+    fn command(name: &str, interval: u64, command: &str) -> String {
+        format!(
+            "\n[[plugin]]\nname = \"{}\"\n[plugin.trigger]\ninterval = {}\n\
+             [plugin.definition]\ntype = \"command\"\ncommand = \"{}\"\nargs = []\n",
+            name, interval, command
+        )
+    }
+
+    #[test]
+    fn accepts_valid_configuration() {
+        assert_eq!(check("valid", &command("clock", 60, "sh")), vec![]);
+    }
+
+    #[test]
+    fn points_at_the_failing_plugin() {
+        let contents = format!(
+            "{}{}",
+            command("first", 0, "sh"),
+            command("second", 0, "sh")
+        );
+        assert_eq!(
+            check("interval", &contents),
+            vec![
+                (
+                    "Plugin 'first' has an interval of 0 seconds".into(),
+                    Some((9, 1))
+                ),
+                (
+                    "Plugin 'second' has an interval of 0 seconds".into(),
+                    Some((18, 1))
+                ),
+            ]
+        );
+
+        let contents = format!(
+            "{}{}",
+            command("first", 60, "corvus-missing"),
+            command("second", 60, "corvus-missing")
+        );
+        assert_eq!(
+            check("command", &contents)[1],
+            (
+                "Plugin 'second' runs 'corvus-missing', which could not be found".into(),
+                Some((21, 1))
+            )
+        );
+    }
+
+    #[test]
+    fn points_at_duplicate_names() {
+        let contents = format!(
+            "{}{}",
+            command("clock", 60, "sh"),
+            command("clock", 60, "sh")
+        );
+        assert_eq!(
+            check("duplicate", &contents),
+            vec![("Duplicate plugin name 'clock'".into(), Some((16, 1)))]
+        );
+    }
+
+    #[test]
+    fn points_at_sink_options() {
+        let sink = |name: &str| {
+            format!(
+                "\n[[sink]]\nname = \"{}\"\n[sink.definition]\ntype = \"webhook\"\n\
+                 url = \"ftp://db\"\n",
+                name
+            )
+        };
+        let contents = format!("{}{}", sink("first"), sink("second"));
+        let message = |name: &str| {
+            format!(
+                "Sink '{}' URL 'ftp://db' must start with one of http://, https://",
+                name
+            )
+        };
+        assert_eq!(
+            check("sink", &contents),
+            vec![
+                (message("first"), Some((10, 1))),
+                (message("second"), Some((16, 1)))
+            ]
+        );
+    }
+
+    #[test]
+    fn points_at_sinks_named_like_plugins() {
+        let contents = format!(
+            "{}\n[[sink]]\nname = \"clock\"\n[sink.definition]\ntype = \"webhook\"\n\
+             url = \"ftp://db\"\n\n[[sink]]\nname = \"clock\"\n[sink.definition]\n\
+             type = \"file\"\npath = \"/tmp/corvus-check.csv\"\n",
+            command("clock", 60, "sh")
+        );
+        assert_eq!(
+            check("sink-names", &contents),
+            vec![
+                (
+                    "Sink 'clock' URL 'ftp://db' must start with one of http://, https://".into(),
+                    Some((19, 1))
+                ),
+                ("Duplicate sink name 'clock'".into(), Some((22, 1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn points_at_parse_errors() {
+        let diagnostics = check("parse", "\n[[plugin]]\nname = 3\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].1.map(|(line, _)| line), Some(7));
+    }
+}
@@ -1,6 +1,8 @@
 use crate::prelude::*;
 use std::collections::{HashMap, HashSet};
 
+/// Latest states and attributes of every entity published by the agents of the cluster, by
+/// node and unique ID
 #[derive(Debug, Clone, Default, Deref)]
 pub struct ClusterNodes(SharedRwLock<HashMap<String, NodeEntities>>);
 
@@ -12,7 +14,8 @@ pub struct EntityDataContainer(SharedRwLock<EntityData>);
 
 #[derive(Debug, Clone, Default)]
 pub struct EntityData {
-    pub stat: RollingVec<String>,
+    /// Only the latest state is kept, every agent publishes its states again and again
+    pub stat: Option<String>,
     pub attr: Document,
 }
 
@@ -22,8 +25,8 @@ macro_rules! get_or_insert {
             let lck = $item.read().await;
             (&*lck).get($name).cloned()
         };
-        if item.is_some() {
-            item.unwrap()
+        if let Some(item) = item {
+            item
         } else {
             let mut lck = $item.write().await;
             let i: $type = Default::default();
@@ -37,8 +40,8 @@ impl ClusterNodes {
     pub async fn update_stat(&self, node: &str, entity: &str, stat: String) {
         let e = get_or_insert!(self, node, NodeEntities);
         let dat = get_or_insert!(e, entity, EntityDataContainer);
-        let lck = dat.write().await;
-        lck.stat.add(stat).await;
+        let mut lck = dat.write().await;
+        lck.stat = Some(stat);
     }
 
     pub async fn update_attr(&self, node: &str, entity: &str, attr: Document) {
@@ -58,7 +61,7 @@ impl ClusterNodes {
         let mut result: HashSet<String> = Default::default();
         for (_, v) in lck.iter() {
             let lck = v.read().await;
-            for val in lck.keys().map(|k| k.to_string()).into_iter() {
+            for val in lck.keys().map(|k| k.to_string()) {
                 result.insert(val);
             }
         }
@@ -11,4 +11,5 @@ impl HassIcons {
     pub const BLUETOOTH_WAVE: &'static str = "mdi:bluetooth-audio";
     pub const GARAGE: &'static str = "mdi:garage";
     pub const FLASH: &'static str = "mdi:flash";
+    pub const ACCOUNT: &'static str = "mdi:account";
 }
@@ -1,5 +1,7 @@
 use crate::prelude::*;
-use std::time::{Duration, Instant};
+use std::time::Duration;
+// Follows the paused clock of tokio's test utilities
+use tokio::time::Instant;
 
 #[derive(Debug)]
 struct RollingVecEntry<T> {
@@ -40,27 +42,24 @@ impl<T> RollingVec<T> {
     }
 
     pub async fn flush(&self) {
-        let mut lck = (&*self.entries).lock().await;
+        let mut lck = self.entries.lock().await;
         lck.retain(|i| i.timestamp.elapsed() < self.duration);
     }
 
     pub async fn add(&self, element: T) {
-        let mut lck = (&*self.entries).lock().await;
+        let mut lck = self.entries.lock().await;
         lck.push(RollingVecEntry::new(element));
     }
 
     pub async fn get_latest(&self) -> Option<Arc<T>> {
         self.flush().await;
-        let lck = (&*self.entries).lock().await;
-        match lck.last() {
-            Some(i) => Some(i.entry.clone()),
-            None => None,
-        }
+        let lck = self.entries.lock().await;
+        lck.last().map(|i| i.entry.clone())
     }
 
     pub async fn get_all(&self) -> Vec<Arc<T>> {
         self.flush().await;
-        let lck = (&*self.entries).lock().await;
+        let lck = self.entries.lock().await;
         lck.iter().map(|i| i.entry.clone()).collect()
     }
 }
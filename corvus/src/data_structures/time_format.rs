@@ -1,5 +1,5 @@
 use chrono::{DateTime, Utc};
-use serde::{self, Deserialize, Deserializer, Serializer};
+use serde::Serializer;
 
 pub fn serialize<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -8,13 +8,3 @@ where
     let s = date.to_rfc3339();
     serializer.serialize_str(&s)
 }
-
-pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    DateTime::parse_from_rfc3339(&s)
-        .map_err(serde::de::Error::custom)
-        .map(DateTime::<Utc>::from)
-}
@@ -14,6 +14,7 @@ pub struct DeviceData {
     plugin:              String,
     base_topic:          String,
     unit_of_measurement: Option<String>,
+    icon:                Option<String>,
 }
 
 pub fn clean_name(s: &str) -> String {
@@ -34,6 +35,7 @@ impl DeviceData {
         Self {
             id: clean_name(&display_name),
             unit_of_measurement: None,
+            icon: None,
             cluster_wide: false,
             plugin,
             display_name,
@@ -48,6 +50,12 @@ impl DeviceData {
         self
     }
 
+    /// Override the icon implied by the device type
+    pub fn with_icon(mut self, icon: String) -> Self {
+        self.icon = Some(icon);
+        self
+    }
+
     pub fn into_cluster_device(mut self) -> Self {
         self.cluster_wide = true;
         self
@@ -80,6 +88,7 @@ impl Device {
         ent.payload_available = Some("online".into());
         ent.payload_not_available = Some("offline".into());
         ent.unit_of_measurement = self.unit_of_measurement.clone();
+        ent.source_type = self.typ.source_type();
 
         if !self.cluster_wide() {
             ent.availability_topic = Some(self.avty_topic());
@@ -101,6 +110,10 @@ impl Device {
         &self.plugin
     }
 
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
     pub fn device_type(&self) -> String {
         self.typ.to_string()
     }
@@ -147,8 +160,20 @@ impl Device {
         }
     }
 
-    pub fn icon(&self) -> &'static str {
-        self.typ.icon()
+    pub fn icon(&self) -> &str {
+        self.icon.as_deref().unwrap_or_else(|| self.typ.icon())
+    }
+
+    pub fn unit_of_measurement(&self) -> Option<&str> {
+        self.unit_of_measurement.as_deref()
+    }
+
+    pub fn homie_datatype(&self) -> &'static str {
+        self.typ.homie_datatype()
+    }
+
+    pub fn settable(&self) -> bool {
+        self.typ.settable()
     }
 
     pub fn cluster_wide(&self) -> bool {
@@ -170,9 +195,26 @@ pub enum DeviceType {
     Light,
     #[display(fmt = "thermostat")]
     Thermostat,
+    /// Reports `home` or `not_home`
+    #[display(fmt = "device_tracker")]
+    DeviceTracker(TrackerSourceType),
 }
 
-#[derive(Clone, Debug, Display)]
+#[derive(Clone, Debug, Display, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackerSourceType {
+    #[display(fmt = "gps")]
+    Gps,
+    #[display(fmt = "router")]
+    Router,
+    #[display(fmt = "bluetooth")]
+    Bluetooth,
+    #[display(fmt = "bluetooth_le")]
+    BluetoothLe,
+}
+
+#[derive(Clone, Debug, Display, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SensorDeviceClass {
     #[display(fmt = "none")]
     None,
@@ -202,7 +244,8 @@ pub enum SensorDeviceClass {
     Voltage,
 }
 
-#[derive(Clone, Debug, Display)]
+#[derive(Clone, Debug, Display, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BinarySensorDeviceClass {
     #[display(fmt = "none")]
     None,
@@ -256,13 +299,36 @@ pub enum BinarySensorDeviceClass {
     Window, // on means open, off means closed
 }
 
+fn device_class<T: serde::de::DeserializeOwned>(class: &str) -> Result<T> {
+    serde_json::from_value(serde_json::Value::String(class.into()))
+        .with_context(|| format!("Unknown device class '{}'", class))
+}
+
 impl DeviceType {
+    /// Parse a type and optional device class as written in discovery messages
+    pub fn parse(typ: &str, class: Option<&str>) -> Result<Self> {
+        Ok(match (typ, class) {
+            ("sensor", None) => DeviceType::Sensor(SensorDeviceClass::None),
+            ("sensor", Some(c)) => DeviceType::Sensor(device_class(c)?),
+            ("binary_sensor", None) => DeviceType::BinarySensor(BinarySensorDeviceClass::None),
+            ("binary_sensor", Some(c)) => DeviceType::BinarySensor(device_class(c)?),
+            ("media_player", _) => DeviceType::MediaPlayer,
+            ("switch", _) => DeviceType::Switch,
+            ("light", _) => DeviceType::Light,
+            ("thermostat", _) => DeviceType::Thermostat,
+            ("device_tracker", None) => DeviceType::DeviceTracker(TrackerSourceType::Router),
+            ("device_tracker", Some(s)) => DeviceType::DeviceTracker(device_class(s)?),
+            (other, _) => bail!("Unknown device type '{}'", other),
+        })
+    }
+
     pub fn icon(&self) -> &'static str {
         match self {
             DeviceType::Thermostat => HassIcons::THERMOMETER,
             DeviceType::Light => HassIcons::LIGHT,
             DeviceType::Switch => HassIcons::POWER,
             DeviceType::MediaPlayer => HassIcons::TELEVISION,
+            DeviceType::DeviceTracker(_) => HassIcons::ACCOUNT,
             DeviceType::Sensor(c) => c.icon(),
             DeviceType::BinarySensor(c) => c.icon(),
         }
@@ -275,6 +341,34 @@ impl DeviceType {
             _ => None,
         }
     }
+
+    /// How a device tracker locates its device
+    pub fn source_type(&self) -> Option<String> {
+        match self {
+            DeviceType::DeviceTracker(s) => Some(s.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Payload type of the state in the Homie convention
+    pub fn homie_datatype(&self) -> &'static str {
+        match self {
+            DeviceType::Sensor(SensorDeviceClass::None)
+            | DeviceType::Sensor(SensorDeviceClass::Timestamp)
+            | DeviceType::MediaPlayer
+            | DeviceType::DeviceTracker(_) => "string",
+            DeviceType::Sensor(_) | DeviceType::Thermostat => "float",
+            DeviceType::BinarySensor(_) | DeviceType::Switch | DeviceType::Light => "boolean",
+        }
+    }
+
+    /// Whether the state can be changed over MQTT
+    pub fn settable(&self) -> bool {
+        matches!(
+            self,
+            DeviceType::Switch | DeviceType::Light | DeviceType::Thermostat
+        )
+    }
 }
 
 impl SensorDeviceClass {
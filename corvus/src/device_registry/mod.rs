@@ -77,6 +77,27 @@ impl DeviceRegistry {
         Ok(device)
     }
 
+    /// Drop every device owned by a plugin and remove its discovery entries
+    pub async fn unregister_plugin(&self, plugin: &str) -> Result<()> {
+        let mut reg = self.devices_names.write().await;
+        reg.retain(|_, d| d.plugin() != plugin);
+        drop(reg);
+        let mut reg = self.devices_ids.write().await;
+        let removed: Vec<Device> = reg
+            .values()
+            .filter(|d| d.plugin() == plugin)
+            .cloned()
+            .collect();
+        reg.retain(|_, d| d.plugin() != plugin);
+        drop(reg);
+
+        for device in removed.iter() {
+            debug!("Removing device '{}'", device.display_name());
+            self.mqtt.remove_device(device).await?;
+        }
+        Ok(())
+    }
+
     pub async fn list_devices(&self) -> Result<Vec<Device>> {
         let reg = self.devices_names.read().await;
         Ok(reg.values().cloned().collect())
@@ -0,0 +1,269 @@
+use crate::{metrics, prelude::*};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+/// Services failing this many times in a row mark the agent as unhealthy
+const FAILURE_THRESHOLD: u64 = 3;
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Read-only JSON status API for health checks and monitoring
+#[derive(Clone, Debug)]
+pub struct HttpService {
+    app: App,
+}
+
+struct Response {
+    status:       u16,
+    content_type: &'static str,
+    body:         String,
+}
+
+impl Response {
+    fn json(status: u16, body: &Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: format!("{:#}\n", body),
+        }
+    }
+
+    fn ok(body: Value) -> Self {
+        Self::json(200, &body)
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, &json!({ "error": message }))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+fn status_json(status: &ServiceStatus) -> Value {
+    json!({
+        "running": status.running,
+        "runs": status.runs,
+        "failures": status.failures,
+        "consecutive_failures": status.consecutive_failures,
+        "last_run": status.last_run.map(|t| t.to_rfc3339()),
+        "next_run": status.next_run.map(|t| t.to_rfc3339()),
+        "last_error": status.last_error,
+    })
+}
+
+impl HttpService {
+    pub fn new(app: App) -> Self {
+        HttpService { app }
+    }
+
+    /// Bind the listener and serve requests in the background
+    pub async fn start(self, config: &HttpConfiguration) -> Result<()> {
+        let listener = TcpListener::bind(&config.bind)
+            .await
+            .with_context(|| format!("Could not bind HTTP API to {}", config.bind))?;
+        info!("HTTP API listening on {}", config.bind);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let zelf = self.clone();
+                        tokio::spawn(async move {
+                            match timeout(REQUEST_TIMEOUT, zelf.handle_connection(stream)).await {
+                                Ok(Err(e)) => debug!("HTTP request from {} failed: {:?}", peer, e),
+                                Err(_) => debug!("HTTP request from {} timed out", peer),
+                                Ok(Ok(_)) => (),
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept HTTP connection: {:?}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let mut request = vec![];
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+            if request.len() > MAX_REQUEST_SIZE {
+                break;
+            }
+        }
+        let oversized = request.len() > MAX_REQUEST_SIZE;
+        let request = String::from_utf8_lossy(&request);
+        let parts: Vec<&str> = request
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect();
+        let (method, target) = match parts[..] {
+            [method, target, version] if version.starts_with("HTTP/") => {
+                (Some(method), Some(target))
+            }
+            _ => (None, None),
+        };
+        trace!("HTTP {:?} {:?}", method, target);
+
+        let response = match (method, target) {
+            _ if oversized => Response::error(431, "Request headers too large"),
+            (Some("GET"), Some(target)) | (Some("HEAD"), Some(target))
+                if target.starts_with('/') =>
+            {
+                let path = target.split('?').next().unwrap_or_default();
+                self.route(path.trim_end_matches('/')).await
+            }
+            (Some("GET"), _) | (Some("HEAD"), _) => Response::error(400, "Malformed request"),
+            (Some(_), Some(_)) => Response::error(405, "Only GET requests are supported"),
+            _ => Response::error(400, "Malformed request"),
+        };
+
+        let mut out = format!(
+            "HTTP/1.1 {} {}\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            response.status,
+            response.reason(),
+            response.content_type,
+            response.body.len()
+        );
+        if method != Some("HEAD") {
+            out.push_str(&response.body);
+        }
+        stream.write_all(out.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn route(&self, path: &str) -> Response {
+        let result = match path {
+            "/health" => return self.health().await,
+            "/devices" => self.devices().await,
+            "/plugins" => self.plugins().await,
+            "/cluster" => self.cluster().await,
+            "/metrics" => {
+                return Response {
+                    status:       200,
+                    content_type: "text/plain; version=0.0.4",
+                    body:         metrics::render(),
+                }
+            }
+            _ => return Response::error(404, "Not found"),
+        };
+        result.map(Response::ok).unwrap_or_else(|e| {
+            error!("HTTP API failure for {}: {:?}", path, e);
+            Response::error(500, &format!("{:#}", e))
+        })
+    }
+
+    async fn health(&self) -> Response {
+        let connected = self.app.mqtt.is_connected();
+        let mut failing: Vec<Value> = service_status()
+            .into_iter()
+            .filter(|(_, s)| s.consecutive_failures >= FAILURE_THRESHOLD)
+            .map(|(name, s)| {
+                json!({
+                    "name": name,
+                    "consecutive_failures": s.consecutive_failures,
+                    "last_error": s.last_error,
+                })
+            })
+            .collect();
+        failing.sort_by_key(|s| s["name"].as_str().map(String::from));
+        let healthy = connected && failing.is_empty();
+        Response::json(
+            if healthy { 200 } else { 503 },
+            &json!({
+                "healthy": healthy,
+                "mqtt_connected": connected,
+                "leader": self.app.mqtt.is_leader().await,
+                "failing_services": failing,
+            }),
+        )
+    }
+
+    async fn devices(&self) -> Result<Value> {
+        let mut devices = self.app.device_registry.list_devices().await?;
+        devices.sort_by(|a, b| a.display_name().cmp(b.display_name()));
+        let mut result = vec![];
+        for device in devices.iter() {
+            let last = self.app.mqtt.last_value(device);
+            result.push(json!({
+                "id": device.uniq_id(),
+                "name": device.display_name(),
+                "type": device.device_type(),
+                "plugin": device.plugin(),
+                "state_topic": device.stat_topic(),
+                "value": last.as_ref().map(|l| serde_json::to_value(&l.value)).transpose()?,
+                "attributes": last.as_ref().map(|l| serde_json::to_value(&l.attr)).transpose()?,
+                "updated": last.as_ref().map(|l| l.timestamp.to_rfc3339()),
+            }));
+        }
+        Ok(Value::Array(result))
+    }
+
+    async fn plugins(&self) -> Result<Value> {
+        let mut plugins = self.app.plugin_manager.list_plugins().await;
+        plugins.sort_by(|a, b| a.name().cmp(b.name()));
+        let mut result = vec![];
+        for plugin in plugins.iter() {
+            let config = plugin.config();
+            let definition = serde_json::to_value(&*config.plugin)?;
+            result.push(json!({
+                "name": plugin.name(),
+                "type": definition["type"],
+                "trigger": serde_json::to_value(&*config.trigger)?,
+                "source": config.source.as_ref().map(|s| s.to_string_lossy()),
+                "status": plugin.status().as_ref().map(status_json),
+            }));
+        }
+        Ok(Value::Array(result))
+    }
+
+    async fn cluster(&self) -> Result<Value> {
+        let nodes: serde_json::Map<String, Value> = self
+            .app
+            .mqtt
+            .get_nodes()
+            .await
+            .into_iter()
+            .map(|(name, node)| {
+                (
+                    name,
+                    json!({
+                        "online": node.online,
+                        "last_seen": node.last_seen.to_rfc3339(),
+                    }),
+                )
+            })
+            .collect();
+        Ok(json!({
+            "location": self.app.config.node.location,
+            "sid": self.app.mqtt.get_sid().await?,
+            "leader": self.app.mqtt.get_leader().await?,
+            "is_leader": self.app.mqtt.is_leader().await,
+            "nodes": nodes,
+        }))
+    }
+}
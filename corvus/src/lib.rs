@@ -0,0 +1,117 @@
+//! Home agent bridging room-level devices to MQTT. The `corvus` binary is a thin wrapper
+//! around [`App`], which can also be constructed from a [`Configuration`] directly.
+//!
+//! Programs embedding corvus add their own plugins by implementing [`Plugin`] and
+//! [`PluginType`] and registering them under a type name:
+//!
+//! ```no_run
+//! use corvus::*;
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct Options {
+//!     path: String,
+//! }
+//!
+//! /// Reads a value from a file, configured with `type = "file_value"`
+//! #[derive(Debug)]
+//! struct FileValue {
+//!     context: PluginContext,
+//!     path:    String,
+//! }
+//!
+//! impl PluginType for FileValue {
+//!     type Options = Options;
+//!
+//!     fn new(context: PluginContext, options: Options) -> Result<Self> {
+//!         Ok(FileValue {
+//!             context,
+//!             path: options.path,
+//!         })
+//!     }
+//! }
+//!
+//! #[async_trait]
+//! impl Plugin for FileValue {
+//!     async fn heartbeat(&self, name: String) -> Result<()> {
+//!         let registry = &self.context.device_registry;
+//!         let device = registry
+//!             .new_device(name.clone(), DeviceType::Sensor(SensorDeviceClass::None), name)
+//!             .build();
+//!         registry.register(device).await?;
+//!         Ok(())
+//!     }
+//!
+//!     async fn run(&self, name: String) -> Result<()> {
+//!         let value = std::fs::read_to_string(&self.path)?;
+//!         let update = DeviceUpdate {
+//!             device: self.context.device_registry.get_by_name(&name).await,
+//!             value:  value.trim().into(),
+//!             attr:   Document::Unit,
+//!         };
+//!         self.context.sinks.update_device(&update).await
+//!     }
+//!
+//!     async fn leader_heartbeat(&self, _: String, _: ClusterNodes) -> Result<()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<()> {
+//!     Corvus::builder()
+//!         .register_plugin::<FileValue>("file_value")
+//!         .run()
+//!         .await
+//! }
+//! ```
+
+#![allow(clippy::upper_case_acronyms)]
+
+#[macro_use]
+extern crate clap;
+
+#[macro_use]
+extern crate derive_more;
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+extern crate anyhow;
+
+#[macro_use]
+extern crate lazy_static;
+
+mod app;
+mod broker;
+mod config;
+mod data_structures;
+mod device_registry;
+mod http;
+mod logging;
+mod metrics;
+mod mqtt;
+mod plugins;
+mod prelude;
+mod sinks;
+mod systemd;
+mod triggers;
+mod util;
+
+pub use anyhow::{Context, Error, Result};
+pub use app::{App, Corvus, CorvusBuilder};
+pub use async_trait::async_trait;
+pub use broker::Broker;
+pub use config::{Configuration, MQTTConfiguration};
+pub use data_structures::{ClusterNodes, RollingVec};
+pub use device_registry::{
+    BinarySensorDeviceClass, Device, DeviceData, DeviceRegistry, DeviceType, DeviceUpdate,
+    SensorDeviceClass, TrackerSourceType,
+};
+pub use mqtt::MQTTService;
+pub use plugins::{Plugin, PluginContext, PluginType};
+use prelude::*;
+pub use sinks::Sinks;
+use std::time::Duration;
+pub use unstructured::Document;
@@ -0,0 +1,287 @@
+use crate::config::{LogFormat, LoggingConfiguration};
+use anyhow::Result;
+use chrono::Local;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use output::Output;
+use serde_json::json;
+use std::{cell::RefCell, future::Future};
+use tokio::task::JoinHandle;
+
+mod output;
+
+tokio::task_local! {
+    static SERVICE: String;
+    static PLUGIN: String;
+}
+
+thread_local! {
+    /// Context of the task that handed work to this blocking thread
+    static BLOCKING: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+lazy_static! {
+    static ref DISPATCH: Dispatch = Dispatch {
+        logger:  Default::default(),
+        pending: Default::default(),
+    };
+}
+
+/// Run `f` with `name` attached to every message it logs as the service field
+pub async fn with_service<F: Future>(name: String, f: F) -> F::Output {
+    SERVICE.scope(name, f).await
+}
+
+/// Run `f` with `name` attached to every message it logs as the plugin field
+pub async fn with_plugin<F: Future>(name: String, f: F) -> F::Output {
+    PLUGIN.scope(name, f).await
+}
+
+/// `tokio::task::spawn_blocking`, keeping the service and plugin fields of the calling task.
+/// Task-locals aren't visible on the blocking pool.
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let context = Context::current();
+    tokio::task::spawn_blocking(move || {
+        BLOCKING.with(|b| *b.borrow_mut() = Some(context));
+        let result = f();
+        BLOCKING.with(|b| *b.borrow_mut() = None);
+        result
+    })
+}
+
+/// Structured fields of the task a message was logged from
+#[derive(Debug, Default, Clone)]
+struct Context {
+    service: Option<String>,
+    plugin:  Option<String>,
+}
+
+impl Context {
+    fn current() -> Self {
+        let blocking = BLOCKING
+            .try_with(|b| b.borrow().clone())
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        Context {
+            service: SERVICE.try_with(|s| s.clone()).ok().or(blocking.service),
+            plugin:  PLUGIN.try_with(|s| s.clone()).ok().or(blocking.plugin),
+        }
+    }
+}
+
+struct Logger {
+    level:   LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    format:  LogFormat,
+    output:  parking_lot::Mutex<Output>,
+}
+
+impl Logger {
+    /// The most specific module filter matching `target`. Messages from other crates are
+    /// dropped unless a filter names them.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let matches = |module: &str| {
+            target == module
+                || (target.starts_with(module) && target[module.len()..].starts_with("::"))
+        };
+        self.modules
+            .iter()
+            .filter(|(module, _)| matches(module))
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(if matches(crate_name!()) {
+                self.level
+            } else {
+                LevelFilter::Off
+            })
+    }
+
+    fn format(&self, record: &Record, context: &Context, output: &Output) -> String {
+        match self.format {
+            LogFormat::Text => {
+                let tag = match (&context.plugin, &context.service) {
+                    (Some(tag), _) | (None, Some(tag)) => format!("[{}] ", tag),
+                    _ => String::new(),
+                };
+                if !output.timestamped() {
+                    return format!("{}: {}{}", record.target(), tag, record.args());
+                }
+                let level = if output.is_terminal() {
+                    let color = match record.level() {
+                        Level::Error => 31,
+                        Level::Warn => 33,
+                        Level::Info => 34,
+                        Level::Debug => 36,
+                        Level::Trace => 35,
+                    };
+                    format!("\x1b[{}m[{}]\x1b[0m", color, record.level())
+                } else {
+                    format!("[{}]", record.level())
+                };
+                format!(
+                    "{} {} {}: {}{}",
+                    Local::now().format("%D %T"),
+                    level,
+                    record.target(),
+                    tag,
+                    record.args()
+                )
+            }
+            LogFormat::Json => json!({
+                "timestamp": Local::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+                "plugin": context.plugin,
+                "service": context.service,
+                "file": record.file(),
+                "line": record.line(),
+            })
+            .to_string(),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        self.write(record, &Context::current())
+    }
+
+    fn flush(&self) {
+        self.output.lock().flush();
+    }
+}
+
+impl Logger {
+    fn write(&self, record: &Record, context: &Context) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut output = self.output.lock();
+        let line = self.format(record, context, &output);
+        if let Err(e) = output.write(record, context, &line) {
+            eprintln!("Failed to write log message: {:?}", e);
+        }
+    }
+}
+
+/// A message logged before `init`
+struct Pending {
+    level:   Level,
+    target:  String,
+    message: String,
+    file:    Option<String>,
+    line:    Option<u32>,
+    context: Context,
+}
+
+/// The logger registered with `log`. Warnings and errors logged before `init` installs the
+/// configured `Logger` are kept and written once it is in place.
+struct Dispatch {
+    logger:  parking_lot::RwLock<Option<Logger>>,
+    pending: parking_lot::Mutex<Vec<Pending>>,
+}
+
+impl Log for Dispatch {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*self.logger.read() {
+            Some(logger) => logger.enabled(metadata),
+            None => metadata.level() <= Level::Warn,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        match &*self.logger.read() {
+            Some(logger) => logger.log(record),
+            None if record.level() <= Level::Warn => self.pending.lock().push(Pending {
+                level:   record.level(),
+                target:  record.target().to_string(),
+                message: record.args().to_string(),
+                file:    record.file().map(String::from),
+                line:    record.line(),
+                context: Context::current(),
+            }),
+            None => {}
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(logger) = &*self.logger.read() {
+            logger.flush();
+        }
+    }
+}
+
+/// Register the global logger without configuring it yet. Warnings logged until `init` is
+/// called, e.g. while loading the configuration that sets up logging, are held back rather
+/// than lost.
+pub fn bootstrap() -> Result<()> {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    let mut result = Ok(());
+    INSTALL.call_once(|| {
+        result = log::set_logger(&*DISPATCH);
+        log::set_max_level(LevelFilter::Warn);
+    });
+    Ok(result?)
+}
+
+/// Install the global logger. `verbosity` raises the level of corvus modules the same way
+/// the `-v` flag always has.
+pub fn init(config: &LoggingConfiguration, verbosity: u64) -> Result<()> {
+    let level = match verbosity {
+        0 => config.level,
+        1 => config.level.max(LevelFilter::Debug),
+        _ => LevelFilter::Trace,
+    };
+    let modules: Vec<(String, LevelFilter)> = config
+        .modules
+        .iter()
+        .map(|(k, v)| (k.to_string(), *v))
+        .collect();
+    let max = modules.iter().map(|(_, l)| *l).fold(level, Ord::max);
+    let logger = Logger {
+        output: parking_lot::Mutex::new(Output::new(config)?),
+        format: config.format,
+        level,
+        modules,
+    };
+    bootstrap()?;
+    for p in DISPATCH.pending.lock().drain(..) {
+        logger.write(
+            &Record::builder()
+                .level(p.level)
+                .target(&p.target)
+                .args(format_args!("{}", p.message))
+                .file(p.file.as_deref())
+                .line(p.line)
+                .build(),
+            &p.context,
+        );
+    }
+    *DISPATCH.logger.write() = Some(logger);
+    log::set_max_level(max);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn blocking_work_keeps_the_plugin() {
+        let context = with_plugin("fan".into(), async {
+            spawn_blocking(Context::current).await.unwrap()
+        })
+        .await;
+        assert_eq!(context.plugin.as_deref(), Some("fan"));
+        assert_eq!(spawn_blocking(Context::current).await.unwrap().plugin, None);
+    }
+}
@@ -0,0 +1,239 @@
+use super::Context;
+use crate::config::{LogTarget, LoggingConfiguration};
+use anyhow::{Context as _, Result};
+use chrono::Local;
+use log::{Level, Record};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+};
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// Messages are sent with the daemon facility
+const SYSLOG_FACILITY: u8 = 3;
+
+pub(super) enum Output {
+    Stderr { terminal: bool },
+    File(RotatingFile),
+    Syslog(LogSocket),
+    Journald(LogSocket),
+}
+
+/// A datagram socket to a logging daemon, connected again after the daemon restarts
+pub(super) struct LogSocket {
+    path:   &'static str,
+    socket: Option<UnixDatagram>,
+}
+
+pub(super) struct RotatingFile {
+    path:     PathBuf,
+    file:     File,
+    size:     u64,
+    max_size: u64,
+    keep:     usize,
+}
+
+fn connect(path: &str) -> Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .connect(path)
+        .with_context(|| format!("Could not connect to log socket {}", path))?;
+    Ok(socket)
+}
+
+impl LogSocket {
+    fn new(path: &'static str) -> Result<Self> {
+        Ok(LogSocket {
+            path,
+            socket: Some(connect(path)?),
+        })
+    }
+
+    /// Send a message, reconnecting once if the socket failed. Without a connection every
+    /// message tries again.
+    fn send(&mut self, buf: &[u8]) -> Result<()> {
+        if let Some(socket) = &self.socket {
+            if socket.send(buf).is_ok() {
+                return Ok(());
+            }
+        }
+        self.socket = None;
+        let socket = connect(self.path)?;
+        socket.send(buf)?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open log file {}", path.display()))
+}
+
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Append a field in the journald native protocol, values containing newlines are sent
+/// length-prefixed
+fn journal_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+impl Output {
+    pub(super) fn new(config: &LoggingConfiguration) -> Result<Self> {
+        Ok(match config.target {
+            LogTarget::Stderr => Output::Stderr {
+                terminal: unsafe { libc::isatty(libc::STDERR_FILENO) } == 1,
+            },
+            LogTarget::File => {
+                let path = PathBuf::from(&config.path);
+                let file = open(&path)?;
+                Output::File(RotatingFile {
+                    size: file.metadata()?.len(),
+                    max_size: config.max_size,
+                    keep: config.keep,
+                    path,
+                    file,
+                })
+            }
+            LogTarget::Syslog => Output::Syslog(LogSocket::new(SYSLOG_SOCKET)?),
+            LogTarget::Journald => Output::Journald(LogSocket::new(JOURNALD_SOCKET)?),
+        })
+    }
+
+    pub(super) fn is_terminal(&self) -> bool {
+        matches!(self, Output::Stderr { terminal: true })
+    }
+
+    /// Whether lines should carry their own timestamp and level, syslog and journald record
+    /// both separately
+    pub(super) fn timestamped(&self) -> bool {
+        matches!(self, Output::Stderr { .. } | Output::File(_))
+    }
+
+    pub(super) fn write(&mut self, record: &Record, context: &Context, line: &str) -> Result<()> {
+        match self {
+            Output::Stderr { .. } => eprintln!("{}", line),
+            Output::File(file) => file.write(line)?,
+            Output::Syslog(socket) => {
+                let message = format!(
+                    "<{}>{} {}[{}]: {}",
+                    SYSLOG_FACILITY * 8 + priority(record.level()),
+                    Local::now().format("%b %e %T"),
+                    crate_name!(),
+                    std::process::id(),
+                    line
+                );
+                socket.send(message.as_bytes())?;
+            }
+            Output::Journald(socket) => {
+                let mut buf = vec![];
+                journal_field(&mut buf, "MESSAGE", line);
+                journal_field(&mut buf, "PRIORITY", &priority(record.level()).to_string());
+                journal_field(&mut buf, "SYSLOG_IDENTIFIER", crate_name!());
+                journal_field(&mut buf, "CORVUS_TARGET", record.target());
+                if let Some(file) = record.file() {
+                    journal_field(&mut buf, "CODE_FILE", file);
+                }
+                if let Some(line) = record.line() {
+                    journal_field(&mut buf, "CODE_LINE", &line.to_string());
+                }
+                if let Some(plugin) = &context.plugin {
+                    journal_field(&mut buf, "CORVUS_PLUGIN", plugin);
+                }
+                if let Some(service) = &context.service {
+                    journal_field(&mut buf, "CORVUS_SERVICE", service);
+                }
+                socket.send(&buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn flush(&mut self) {
+        if let Output::File(file) = self {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+impl RotatingFile {
+    fn write(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift `corvus.log.N` to `corvus.log.N+1`, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> Result<()> {
+        let numbered = |i: usize| PathBuf::from(format!("{}.{}", self.path.display(), i));
+        if self.keep > 0 {
+            for i in (1..self.keep).rev() {
+                if numbered(i).exists() {
+                    std::fs::rename(numbered(i), numbered(i + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, numbered(1))?;
+        } else {
+            std::fs::remove_file(&self.path)?;
+        }
+        self.file = open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_sockets_reconnect_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("corvus-log-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path: &'static str = Box::leak(path.to_string_lossy().into_owned().into_boxed_str());
+        let received = |daemon: &UnixDatagram| {
+            let mut buf = [0; 16];
+            let len = daemon.recv(&mut buf).unwrap();
+            buf[..len].to_vec()
+        };
+
+        let daemon = UnixDatagram::bind(path).unwrap();
+        let mut socket = LogSocket::new(path).unwrap();
+        socket.send(b"one").unwrap();
+        assert_eq!(received(&daemon), b"one");
+
+        drop(daemon);
+        std::fs::remove_file(path).unwrap();
+        assert!(socket.send(b"lost").is_err());
+
+        let daemon = UnixDatagram::bind(path).unwrap();
+        socket.send(b"two").unwrap();
+        assert_eq!(received(&daemon), b"two");
+        std::fs::remove_file(path).unwrap();
+    }
+}
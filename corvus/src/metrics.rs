@@ -0,0 +1,347 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+lazy_static! {
+    static ref REGISTRY: parking_lot::Mutex<BTreeMap<&'static str, Family>> = Default::default();
+}
+
+const DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+const RETRY_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 3.0, 5.0, 10.0];
+
+pub const MQTT_PUBLISHES: Metric = Metric::counter(
+    "corvus_mqtt_publishes_total",
+    "Messages handed to the MQTT client",
+);
+pub const MQTT_PUBLISH_FAILURES: Metric = Metric::counter(
+    "corvus_mqtt_publish_failures_total",
+    "Messages that could not be handed to the MQTT client",
+);
+pub const MQTT_CONNECTIONS: Metric = Metric::counter(
+    "corvus_mqtt_connections_total",
+    "Connections acknowledged by the broker, increases on every reconnect",
+);
+pub const MQTT_CONNECTED: Metric = Metric::gauge(
+    "corvus_mqtt_connected",
+    "Whether the broker has acknowledged the current connection",
+);
+pub const MQTT_QUEUE_DEPTH: Metric = Metric::gauge(
+    "corvus_mqtt_queue_depth",
+    "Requests waiting to be sent to the broker",
+);
+pub const MQTT_INFLIGHT: Metric = Metric::gauge(
+    "corvus_mqtt_inflight",
+    "Messages sent to the broker and not yet acknowledged",
+);
+pub const SINK_WRITES: Metric = Metric::counter(
+    "corvus_sink_writes_total",
+    "Device updates written to a configured sink",
+);
+pub const SINK_ERRORS: Metric = Metric::counter(
+    "corvus_sink_errors_total",
+    "Device updates a configured sink failed to write",
+);
+pub const SINK_DROPS: Metric = Metric::counter(
+    "corvus_sink_drops_total",
+    "Device updates dropped because a configured sink fell behind",
+);
+pub const SERVICE_RUNS: Metric = Metric::counter(
+    "corvus_service_runs_total",
+    "Completed runs of a background service",
+);
+pub const SERVICE_ERRORS: Metric = Metric::counter(
+    "corvus_service_errors_total",
+    "Runs of a background service that returned an error",
+);
+pub const SERVICE_DURATION: Metric = Metric::histogram(
+    "corvus_service_duration_seconds",
+    "Time taken by a single run of a background service",
+    DURATION_BUCKETS,
+);
+pub const DHT_READ_ATTEMPTS: Metric = Metric::counter(
+    "corvus_dht_read_attempts_total",
+    "Attempts to read the DHT sensor",
+);
+pub const DHT_CHECKSUM_FAILURES: Metric = Metric::counter(
+    "corvus_dht_checksum_failures_total",
+    "DHT reads discarded due to a bad checksum or out of range value",
+);
+pub const DHT_TIMEOUTS: Metric = Metric::counter(
+    "corvus_dht_timeouts_total",
+    "DHT reads that returned too few pulses",
+);
+pub const DHT_RETRIES: Metric = Metric::histogram(
+    "corvus_dht_retries",
+    "Retries needed for a single DHT reading",
+    RETRY_BUCKETS,
+);
+pub const BLUETOOTH_EVENTS: Metric = Metric::counter(
+    "corvus_bluetooth_events_total",
+    "Events received from the bluetooth controller",
+);
+pub const BLUETOOTH_DEVICES: Metric = Metric::gauge(
+    "corvus_bluetooth_devices",
+    "Bluetooth devices seen since the plugin started",
+);
+pub const LEADER_CHANGES: Metric = Metric::counter(
+    "corvus_cluster_leader_changes_total",
+    "Times the cluster leader changed",
+);
+pub const IS_LEADER: Metric = Metric::gauge(
+    "corvus_cluster_is_leader",
+    "Whether this node is the cluster leader",
+);
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram(&'static [f64]),
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug)]
+enum Series {
+    Value(f64),
+    Histogram {
+        buckets: Vec<u64>,
+        sum:     f64,
+        count:   u64,
+    },
+}
+
+#[derive(Debug)]
+struct Family {
+    help:   &'static str,
+    kind:   Kind,
+    series: BTreeMap<Labels, Series>,
+}
+
+/// A metric family, series are created on first use with the labels given when recording
+#[derive(Debug, Clone, Copy)]
+pub struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+}
+
+impl Metric {
+    const fn counter(name: &'static str, help: &'static str) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Counter,
+        }
+    }
+
+    const fn gauge(name: &'static str, help: &'static str) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Gauge,
+        }
+    }
+
+    const fn histogram(name: &'static str, help: &'static str, buckets: &'static [f64]) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Histogram(buckets),
+        }
+    }
+
+    fn with_series(&self, labels: &[(&'static str, &str)], f: impl FnOnce(&mut Series)) {
+        let mut registry = REGISTRY.lock();
+        let family = registry.entry(self.name).or_insert_with(|| Family {
+            help:   self.help,
+            kind:   self.kind,
+            series: Default::default(),
+        });
+        let labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        let kind = self.kind;
+        let series = family.series.entry(labels).or_insert_with(|| match kind {
+            Kind::Histogram(buckets) => Series::Histogram {
+                buckets: vec![0; buckets.len()],
+                sum:     0.0,
+                count:   0,
+            },
+            _ => Series::Value(0.0),
+        });
+        f(series)
+    }
+
+    pub fn inc(&self, labels: &[(&'static str, &str)]) {
+        self.add(labels, 1.0)
+    }
+
+    pub fn add(&self, labels: &[(&'static str, &str)], value: f64) {
+        self.with_series(labels, |s| {
+            if let Series::Value(v) = s {
+                *v += value
+            }
+        })
+    }
+
+    pub fn set(&self, labels: &[(&'static str, &str)], value: f64) {
+        self.with_series(labels, |s| {
+            if let Series::Value(v) = s {
+                *v = value
+            }
+        })
+    }
+
+    pub fn observe(&self, labels: &[(&'static str, &str)], value: f64) {
+        let bounds = match self.kind {
+            Kind::Histogram(bounds) => bounds,
+            _ => return,
+        };
+        self.with_series(labels, |s| {
+            if let Series::Histogram {
+                buckets,
+                sum,
+                count,
+            } = s
+            {
+                for (bucket, bound) in buckets.iter_mut().zip(bounds.iter()) {
+                    if value <= *bound {
+                        *bucket += 1;
+                    }
+                }
+                *sum += value;
+                *count += 1;
+            }
+        })
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_labels(labels: &[(&'static str, String)], extra: Option<(&str, String)>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect();
+    if let Some((k, v)) = extra {
+        pairs.push(format!("{}=\"{}\"", k, v));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// Render every recorded metric in the Prometheus text exposition format
+pub fn render() -> String {
+    let registry = REGISTRY.lock();
+    let mut out = String::new();
+    for (name, family) in registry.iter() {
+        let typ = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram(_) => "histogram",
+        };
+        let _ = writeln!(out, "# HELP {} {}", name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", name, typ);
+        for (labels, series) in family.series.iter() {
+            match (series, family.kind) {
+                (Series::Value(v), _) => {
+                    let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), v);
+                }
+                (
+                    Series::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    },
+                    Kind::Histogram(bounds),
+                ) => {
+                    for (bucket, bound) in buckets.iter().zip(bounds.iter()) {
+                        let le = format_labels(labels, Some(("le", bound.to_string())));
+                        let _ = writeln!(out, "{}_bucket{} {}", name, le, bucket);
+                    }
+                    let le = format_labels(labels, Some(("le", "+Inf".into())));
+                    let _ = writeln!(out, "{}_bucket{} {}", name, le, count);
+                    let labels = format_labels(labels, None);
+                    let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+                    let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+                }
+                _ => (),
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rendered lines mentioning `name`, the registry is shared with every other test
+    fn rendered(name: &str) -> Vec<String> {
+        render()
+            .lines()
+            .filter(|l| l.contains(name))
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn renders_help_type_and_escaped_labels() {
+        const REQUESTS: Metric = Metric::counter("corvus_test_requests_total", "Test requests");
+        const TEMPERATURE: Metric = Metric::gauge("corvus_test_temperature", "Test gauge");
+        REQUESTS.inc(&[("path", r#"/a "quoted" \path"#)]);
+        REQUESTS.add(&[("path", "line\nbreak")], 2.0);
+        TEMPERATURE.set(&[], 21.5);
+        TEMPERATURE.set(&[], -3.0);
+
+        assert_eq!(
+            rendered("corvus_test_requests_total"),
+            [
+                "# HELP corvus_test_requests_total Test requests",
+                "# TYPE corvus_test_requests_total counter",
+                r#"corvus_test_requests_total{path="/a \"quoted\" \\path"} 1"#,
+                r#"corvus_test_requests_total{path="line\nbreak"} 2"#,
+            ]
+        );
+        assert_eq!(
+            rendered("corvus_test_temperature"),
+            [
+                "# HELP corvus_test_temperature Test gauge",
+                "# TYPE corvus_test_temperature gauge",
+                "corvus_test_temperature -3",
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_cumulative_histogram_buckets() {
+        const DURATION: Metric = Metric::histogram(
+            "corvus_test_duration_seconds",
+            "Test histogram",
+            &[0.1, 1.0],
+        );
+        for value in [0.05, 0.5, 0.5, 3.0].iter() {
+            DURATION.observe(&[("service", "mqtt")], *value);
+        }
+        // Not a histogram, ignored
+        DURATION.inc(&[("service", "mqtt")]);
+
+        assert_eq!(
+            rendered("corvus_test_duration_seconds"),
+            [
+                "# HELP corvus_test_duration_seconds Test histogram",
+                "# TYPE corvus_test_duration_seconds histogram",
+                r#"corvus_test_duration_seconds_bucket{service="mqtt",le="0.1"} 1"#,
+                r#"corvus_test_duration_seconds_bucket{service="mqtt",le="1"} 3"#,
+                r#"corvus_test_duration_seconds_bucket{service="mqtt",le="+Inf"} 4"#,
+                r#"corvus_test_duration_seconds_sum{service="mqtt"} 4.05"#,
+                r#"corvus_test_duration_seconds_count{service="mqtt"} 4"#,
+            ]
+        );
+    }
+}
@@ -1,16 +1,26 @@
-use crate::prelude::*;
+use crate::{metrics, prelude::*};
+use chrono::{DateTime, Local};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use std::time::SystemTime;
+use std::{collections::HashMap, time::SystemTime};
 
 #[derive(Deref, Debug, Clone)]
 pub struct ClusterState(SharedRwLock<ClusterStateData>);
 
 #[derive(Debug)]
 pub struct ClusterStateData {
+    #[allow(dead_code)]
     node_name:      String,
     sid:            String,
     current_leader: Option<String>,
     last_timestamp: SystemTime,
+    nodes:          HashMap<String, NodeStatus>,
+}
+
+/// Last known state of a node publishing on the cluster topics
+#[derive(Debug, Clone)]
+pub struct NodeStatus {
+    pub online:    bool,
+    pub last_seen: DateTime<Local>,
 }
 
 impl ClusterState {
@@ -19,6 +29,7 @@ impl ClusterState {
             node_name,
             current_leader: None,
             last_timestamp: SystemTime::now(),
+            nodes: Default::default(),
             sid: String::from_utf8(
                 thread_rng()
                     .sample_iter(&Alphanumeric)
@@ -38,9 +49,11 @@ impl ClusterState {
         let mut s = self.write().await;
         if !matches!(&s.current_leader, Some(cl) if cl == &leader) {
             debug!("Current cluster leader is '{}'", leader);
-            (*s).current_leader = Some(leader);
+            metrics::LEADER_CHANGES.inc(&[]);
+            metrics::IS_LEADER.set(&[], if leader == s.sid { 1.0 } else { 0.0 });
+            s.current_leader = Some(leader);
         }
-        (*s).last_timestamp = SystemTime::now();
+        s.last_timestamp = SystemTime::now();
     }
 
     pub async fn leader_needed(&self) -> Result<bool> {
@@ -57,4 +70,22 @@ impl ClusterState {
         let s = self.read().await;
         Ok(s.sid.clone())
     }
+
+    /// Record activity from a node, `online` is only known from its availability topic
+    pub async fn node_seen(&self, node: &str, online: Option<bool>) {
+        let mut s = self.write().await;
+        let entry = s.nodes.entry(node.to_string()).or_insert(NodeStatus {
+            online:    true,
+            last_seen: Local::now(),
+        });
+        entry.last_seen = Local::now();
+        if let Some(online) = online {
+            entry.online = online;
+        }
+    }
+
+    pub async fn get_nodes(&self) -> HashMap<String, NodeStatus> {
+        let s = self.read().await;
+        s.nodes.clone()
+    }
 }
@@ -0,0 +1,215 @@
+use crate::prelude::*;
+use std::collections::BTreeMap;
+
+/// Version of the convention announced in `$homie`, see https://homieiot.github.io/
+const HOMIE_VERSION: &str = "4.0.0";
+
+/// A retained message to publish
+pub type Message = (String, String);
+
+/// Homie IDs may only contain lowercase letters, digits and single hyphens
+pub fn homie_id(s: &str) -> String {
+    s.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Devices announced in the Homie convention. The devices of an agent form one Homie device
+/// named after its location and cluster wide devices a shared `<base_topic>-cluster` device.
+/// Each plugin is a node of these, each of its devices a property.
+#[derive(Debug)]
+pub struct HomieState {
+    topic:      String,
+    local_id:   String,
+    local_name: String,
+    cluster_id: String,
+    devices:    parking_lot::Mutex<BTreeMap<String, Device>>,
+}
+
+impl HomieState {
+    pub fn new(topic: String, location: &str, base_topic: &str) -> Self {
+        HomieState {
+            local_id: homie_id(&clean_name(location)),
+            local_name: location.to_string(),
+            cluster_id: homie_id(&format!("{}-cluster", base_topic)),
+            devices: Default::default(),
+            topic,
+        }
+    }
+
+    fn device_id(&self, device: &Device) -> &str {
+        if device.cluster_wide() {
+            &self.cluster_id
+        } else {
+            &self.local_id
+        }
+    }
+
+    fn node_topic(&self, device: &Device) -> String {
+        format!(
+            "{}/{}/{}",
+            self.topic,
+            self.device_id(device),
+            homie_id(device.plugin())
+        )
+    }
+
+    pub fn property_topic(&self, device: &Device) -> String {
+        format!("{}/{}", self.node_topic(device), homie_id(device.id()))
+    }
+
+    /// `$state` topic of the Homie device of the agent at `node`, a cleaned location
+    pub fn state_topic(&self, node: &str) -> String {
+        format!("{}/{}/$state", self.topic, homie_id(node))
+    }
+
+    pub fn local_state_topic(&self) -> String {
+        format!("{}/{}/$state", self.topic, self.local_id)
+    }
+
+    /// Filter matching commands for every property of this agent
+    pub fn set_filter(&self) -> String {
+        format!("{}/{}/+/+/set", self.topic, self.local_id)
+    }
+
+    /// The settable device a command on `topic` is addressed to
+    pub fn settable(&self, topic: &str) -> Option<Device> {
+        let property = topic.strip_suffix("/set")?;
+        self.devices
+            .lock()
+            .values()
+            .find(|d| d.settable() && self.property_topic(d) == property)
+            .cloned()
+    }
+
+    /// `$state` of every Homie device this agent has announced
+    pub fn states(&self, state: &str) -> Vec<Message> {
+        let devices = self.devices.lock();
+        let mut ids: Vec<&str> = devices.values().map(|d| self.device_id(d)).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids.into_iter()
+            .map(|id| (format!("{}/{}/$state", self.topic, id), state.to_string()))
+            .collect()
+    }
+
+    /// Announce `device`. Its Homie device passes through `init` only when it is announced for
+    /// the first time, later devices just update the lists of nodes and properties.
+    pub fn add(&self, device: &Device) -> Vec<Message> {
+        let mut devices = self.devices.lock();
+        let id = self.device_id(device);
+        let new = !devices.values().any(|d| self.device_id(d) == id);
+        devices.insert(device.uniq_id(), device.clone());
+        let base = format!("{}/{}", self.topic, id);
+        let mut messages = vec![];
+        if new {
+            messages.push((format!("{}/$state", base), "init".into()));
+        }
+        messages.push((format!("{}/$homie", base), HOMIE_VERSION.into()));
+        let name = if device.cluster_wide() {
+            format!("{} cluster", crate_name!())
+        } else {
+            self.local_name.to_string()
+        };
+        messages.push((format!("{}/$name", base), name));
+        messages.push((format!("{}/$nodes", base), self.nodes(&devices, id)));
+        messages.extend(self.node(&devices, device));
+
+        let property = self.property_topic(device);
+        messages.push((format!("{}/$name", property), device.display_name().into()));
+        messages.push((
+            format!("{}/$datatype", property),
+            device.homie_datatype().into(),
+        ));
+        if let Some(unit) = device.unit_of_measurement() {
+            messages.push((format!("{}/$unit", property), unit.into()));
+        }
+        if device.settable() {
+            messages.push((format!("{}/$settable", property), "true".into()));
+        }
+        if new {
+            messages.push((format!("{}/$state", base), "ready".into()));
+        }
+        messages
+    }
+
+    /// Remove the attributes of `device` and update the lists referencing it
+    pub fn remove(&self, device: &Device) -> Vec<Message> {
+        let mut devices = self.devices.lock();
+        if devices.remove(&device.uniq_id()).is_none() {
+            return vec![];
+        }
+        let id = self.device_id(device);
+        let base = format!("{}/{}", self.topic, id);
+        let property = self.property_topic(device);
+        // Empty retained messages clear the topics
+        let mut messages: Vec<Message> = ["", "/$name", "/$datatype", "/$unit", "/$settable"]
+            .iter()
+            .map(|attr| (format!("{}{}", property, attr), String::new()))
+            .collect();
+        messages.push((format!("{}/$nodes", base), self.nodes(&devices, id)));
+        let node = self.node_topic(device);
+        if devices.values().any(|d| self.node_topic(d) == node) {
+            messages.extend(self.node(&devices, device));
+        } else {
+            for attr in ["$name", "$type", "$properties"].iter() {
+                messages.push((format!("{}/{}", node, attr), String::new()));
+            }
+        }
+        messages
+    }
+
+    /// Comma separated nodes of the Homie device `id`
+    fn nodes(&self, devices: &BTreeMap<String, Device>, id: &str) -> String {
+        let mut nodes: Vec<String> = devices
+            .values()
+            .filter(|d| self.device_id(d) == id)
+            .map(|d| homie_id(d.plugin()))
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes.join(",")
+    }
+
+    /// Attributes of the node `device` belongs to
+    fn node(&self, devices: &BTreeMap<String, Device>, device: &Device) -> Vec<Message> {
+        let node = self.node_topic(device);
+        let mut properties: Vec<String> = devices
+            .values()
+            .filter(|d| self.node_topic(d) == node)
+            .map(|d| homie_id(d.id()))
+            .collect();
+        properties.sort();
+        properties.dedup();
+        vec![
+            (format!("{}/$name", node), device.plugin().into()),
+            (
+                format!("{}/$type", node),
+                format!("{} plugin", crate_name!()),
+            ),
+            (format!("{}/$properties", node), properties.join(",")),
+        ]
+    }
+
+    /// Format a state in the property's datatype, `None` for values with no representation
+    pub fn value(device: &Device, value: &Document) -> Option<String> {
+        let value = serde_json::to_value(value).ok()?;
+        let formatted = match value {
+            serde_json::Value::Null => return None,
+            serde_json::Value::String(s) => s,
+            serde_json::Value::Bool(b) => b.to_string(),
+            serde_json::Value::Number(n) => n.to_string(),
+            v => v.to_string(),
+        };
+        if device.homie_datatype() == "boolean" {
+            return match formatted.to_lowercase().as_str() {
+                "true" | "on" | "1" => Some("true".into()),
+                "false" | "off" | "0" => Some("false".into()),
+                _ => None,
+            };
+        }
+        Some(formatted)
+    }
+}
@@ -1,11 +1,36 @@
-use crate::{plugins::PluginManager, prelude::*, util::StaticService, MQTTConfiguration, Result};
+use crate::{
+    logging, metrics, plugins::PluginManager, prelude::*, util::StaticService, DiscoveryFormat,
+    MQTTConfiguration, Result,
+};
 use async_trait::async_trait;
 use chrono::prelude::*;
 use cluster::ClusterState;
-use rumqttc::{self, AsyncClient, Event, EventLoop, Incoming, LastWill, MqttOptions, QoS};
-use std::{sync::Arc, time::Duration};
+use homie::HomieState;
+use rumqttc::{
+    self, AsyncClient, Event, EventLoop, Incoming, LastWill, MqttOptions, Outgoing, QoS,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc::unbounded_channel, task::JoinHandle};
 
 mod cluster;
+mod homie;
+
+pub use cluster::NodeStatus;
+
+/// The most recent update published for a device
+#[derive(Debug, Clone)]
+pub struct DeviceValue {
+    pub value:     Document,
+    pub attr:      Document,
+    pub timestamp: DateTime<Local>,
+}
 
 #[derive(Clone, Deref)]
 pub struct MQTTService(Arc<MQTTServiceData>);
@@ -16,9 +41,21 @@ pub struct MQTTServiceData {
     nodes_topic:        String,
     leader_topic:       String,
     discovery_topic:    String,
+    hass:               bool,
+    homie:              Option<HomieState>,
     client:             SharedMutex<Option<AsyncClient>>,
+    dry_run:            bool,
+    connected:          AtomicBool,
+    pending:            AtomicUsize,
+    last_poll:          parking_lot::Mutex<Instant>,
+    last_values:        parking_lot::Mutex<HashMap<String, DeviceValue>>,
     cluster:            ClusterState,
+    entities:           ClusterNodes,
     mqtt_options:       MqttOptions,
+    /// A second connection whose will marks the Homie device of this agent as lost, the will
+    /// of the main connection already reports its availability
+    homie_will_options: Option<MqttOptions>,
+    homie_will:         parking_lot::Mutex<Option<(AsyncClient, JoinHandle<()>)>>,
     plugin_manager:     PluginManager,
 }
 
@@ -40,20 +77,53 @@ impl StaticService for MQTTService {
 
     async fn exec_service(zelf: Self) -> Result<()> {
         let mqtt = zelf.clone();
+        if mqtt.dry_run {
+            return mqtt.exec_dry_run().await;
+        }
         let mut eventloop = mqtt.connect().await?;
+        mqtt.connect_homie_will();
         mqtt.publish(&mqtt.availability_topic, "online", true, QoS::AtLeastOnce)
             .await?;
         mqtt.subscribe(&format!("{}#", mqtt.nodes_topic), QoS::AtLeastOnce)
             .await?;
         mqtt.subscribe(&mqtt.leader_topic, QoS::AtLeastOnce).await?;
+        if let Some(homie) = &mqtt.homie {
+            mqtt.subscribe(&homie.set_filter(), QoS::AtLeastOnce)
+                .await?;
+            // Devices announced before a reconnect are still current
+            mqtt.publish_all(homie.states("ready")).await?;
+        }
+
+        // Messages are handled on a task of their own, handling them may publish and only
+        // polling the event loop makes room in the client's request queue
+        let (received, mut incoming) = unbounded_channel::<rumqttc::Publish>();
+        let handler = mqtt.clone();
+        tokio::spawn(logging::with_service(Self::NAME.into(), async move {
+            while let Some(p) = incoming.recv().await {
+                handler
+                    .handle_message(p)
+                    .await
+                    .unwrap_or_else(|e| warn!("Error handling MQTT message: {:?}", e));
+            }
+        }));
 
         // Main loop
         loop {
-            match eventloop.poll().await {
+            let event = eventloop.poll().await;
+            *mqtt.last_poll.lock() = Instant::now();
+            let (queued, inflight) = (eventloop.requests_rx.len(), eventloop.state.inflight());
+            mqtt.pending
+                .store(queued + inflight as usize, Ordering::SeqCst);
+            metrics::MQTT_QUEUE_DEPTH.set(&[], queued as f64);
+            metrics::MQTT_INFLIGHT.set(&[], inflight as f64);
+            match event {
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                    mqtt.connected.store(true, Ordering::SeqCst);
+                    metrics::MQTT_CONNECTIONS.inc(&[]);
+                    metrics::MQTT_CONNECTED.set(&[], 1.0);
+                }
                 Ok(Event::Incoming(Incoming::Publish(p))) => {
-                    mqtt.handle_message(p)
-                        .await
-                        .unwrap_or_else(|e| warn!("Error polling event: {:?}", e));
+                    let _ = received.send(p);
                 }
                 Err(e) => {
                     error!("Error received on MQTT poll: {:?}", e);
@@ -66,16 +136,29 @@ impl StaticService for MQTTService {
 }
 
 impl MQTTService {
+    /// With `dry_run` nothing connects to the broker, every publish is printed to stdout
+    /// instead and messages on the leader topic are looped back.
     pub async fn new(
         location: String,
         config: Arc<MQTTConfiguration>,
         plugin_manager: PluginManager,
+        entities: ClusterNodes,
+        dry_run: bool,
     ) -> Result<Self> {
         let cluster_topic = format!("{}/cluster/", config.base_topic);
         let leader_topic = format!("{}leader", cluster_topic);
         let nodes_topic = format!("{}/nodes/", config.base_topic);
         let availability_topic = format!("{}{}/avty", nodes_topic, clean_name(&location));
         let discovery_topic = config.discovery_topic.to_string();
+        let homie = if config.discovery_format.contains(&DiscoveryFormat::Homie) {
+            Some(HomieState::new(
+                config.homie_topic.to_string(),
+                &location,
+                &config.base_topic,
+            ))
+        } else {
+            None
+        };
         let mut mqtt_options =
             MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
         mqtt_options.set_keep_alive(5);
@@ -84,20 +167,91 @@ impl MQTTService {
             QoS::AtLeastOnce,
             "offline",
         ));
+        let homie_will_options = homie.as_ref().map(|homie| {
+            let mut options = MqttOptions::new(
+                format!("{}-homie", config.client_id),
+                config.host.clone(),
+                config.port,
+            );
+            options.set_keep_alive(5);
+            let mut will = LastWill::new(homie.local_state_topic(), QoS::AtLeastOnce, "lost");
+            will.retain = true;
+            options.set_last_will(will);
+            options
+        });
 
         Ok(MQTTService(Arc::new(MQTTServiceData {
             cluster: ClusterState::new(location.clone()),
+            entities,
             client: Arc::new(Mutex::new(None)),
+            dry_run,
+            connected: AtomicBool::new(false),
+            pending: AtomicUsize::new(0),
+            last_poll: parking_lot::Mutex::new(Instant::now()),
+            last_values: Default::default(),
             location,
             discovery_topic,
+            hass: config.discovery_format.contains(&DiscoveryFormat::Hass),
+            homie,
             nodes_topic,
             availability_topic,
             leader_topic,
             mqtt_options,
+            homie_will_options,
+            homie_will: Default::default(),
             plugin_manager,
         })))
     }
 
+    async fn exec_dry_run(&self) -> Result<()> {
+        info!("Dry run, MQTT messages are printed instead of published");
+        self.connected.store(true, Ordering::SeqCst);
+        *self.last_poll.lock() = Instant::now();
+        self.publish(&self.availability_topic, "online", true, QoS::AtLeastOnce)
+            .await?;
+        // Keep the event loop looking alive for the watchdog
+        loop {
+            tokio::time::sleep(Self::DURATION).await;
+            *self.last_poll.lock() = Instant::now();
+        }
+    }
+
+    fn print_publish(&self, topic: &str, message: &str, retain: bool, qos: QoS) {
+        let payload = match serde_json::from_str::<serde_json::Value>(message) {
+            Ok(v) if v.is_object() || v.is_array() => {
+                serde_json::to_string_pretty(&v).unwrap_or_else(|_| message.to_string())
+            }
+            _ => message.to_string(),
+        };
+        println!(
+            "{} (qos {}{})\n{}\n",
+            topic,
+            qos as u8,
+            if retain { ", retained" } else { "" },
+            payload
+        );
+    }
+
+    /// Wait up to `timeout` for queued and unacknowledged messages to reach the broker. The
+    /// pending count is only current once the event loop has run since the call.
+    pub async fn flush(&self, timeout: Duration) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let start = Instant::now();
+        loop {
+            let polled = self.last_poll() < start.elapsed();
+            if polled && self.pending.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            if start.elapsed() > timeout {
+                bail!("Timed out waiting for messages to reach the broker");
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Ok(())
+    }
+
     pub async fn connect(&self) -> Result<EventLoop> {
         info!("Connecting to MQTT broker");
         let (client, eventloop) = AsyncClient::new(self.mqtt_options.clone(), 10);
@@ -107,8 +261,34 @@ impl MQTTService {
         Ok(eventloop)
     }
 
+    /// Open the connection holding the Homie will, replacing an earlier one
+    fn connect_homie_will(&self) {
+        let options = match &self.homie_will_options {
+            Some(options) => options.clone(),
+            None => return,
+        };
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        let task = tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => (),
+                    Err(e) => {
+                        debug!("Homie will connection failed: {:?}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+        if let Some((_, previous)) = self.homie_will.lock().replace((client, task)) {
+            previous.abort();
+        }
+    }
+
     pub async fn disconnect(&self) -> Result<()> {
         warn!("MQTT Disconnected");
+        self.connected.store(false, Ordering::SeqCst);
+        metrics::MQTT_CONNECTED.set(&[], 0.0);
         let mut cli_lock = self.client.lock().await;
         *cli_lock = None;
         Ok(())
@@ -124,6 +304,16 @@ impl MQTTService {
             t if t.starts_with(&self.nodes_topic) => {
                 self.handle_node_update(&t, payload).await?;
             }
+            t if t.ends_with("/set") && self.homie.is_some() => {
+                let device = self.homie.as_ref().and_then(|h| h.settable(&t));
+                match device {
+                    Some(device) => {
+                        debug!("Setting '{}' to '{}'", device.display_name(), payload);
+                        self.plugin_manager.set_property(device, payload).await?;
+                    }
+                    None => debug!("No settable property for '{}'", t),
+                }
+            }
             t => debug!("Unknown topic '{}'", t),
         }
         Ok(())
@@ -131,9 +321,32 @@ impl MQTTService {
 
     async fn handle_node_update(&self, topic: &str, payload: String) -> Result<()> {
         let suffix = topic.trim_start_matches(&self.nodes_topic);
-        let typ = suffix.split('/').last();
+        let node = suffix.split('/').next().unwrap_or_default();
+        let typ = suffix.split('/').next_back();
+        if Some("avty") == typ {
+            self.cluster
+                .node_seen(node, Some(payload == "online"))
+                .await;
+            // The will already covers availability, the leader reports lost Homie devices
+            if let Some(homie) = &self.homie {
+                if payload == "offline" && self.is_leader().await {
+                    self.publish(&homie.state_topic(node), "lost", true, QoS::AtLeastOnce)
+                        .await?;
+                }
+            }
+        } else {
+            self.cluster.node_seen(node, None).await;
+        }
+        if let [node, entity, "stat"] = suffix.split('/').collect::<Vec<_>>()[..] {
+            self.entities
+                .update_stat(node, entity, payload.clone())
+                .await;
+        }
         if Some("attr") == typ {
             let dat: Document = serde_json::from_str(&payload)?;
+            if let [node, entity, _] = suffix.split('/').collect::<Vec<_>>()[..] {
+                self.entities.update_attr(node, entity, dat.clone()).await;
+            }
             let plugin = dat["corvus_plugin"].clone();
             if plugin.is_string() {
                 self.plugin_manager
@@ -152,6 +365,34 @@ impl MQTTService {
         self.cluster.is_leader().await
     }
 
+    /// Whether the broker has acknowledged the current connection
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Time since the event loop last returned, it wakes at least every keep alive interval
+    /// while connected and on every reconnect attempt otherwise
+    pub fn last_poll(&self) -> Duration {
+        self.last_poll.lock().elapsed()
+    }
+
+    pub async fn get_leader(&self) -> Result<Option<String>> {
+        self.cluster.get_leader().await
+    }
+
+    pub async fn get_sid(&self) -> Result<String> {
+        self.cluster.get_sid().await
+    }
+
+    /// Nodes seen on the cluster topics, keyed by their cleaned location
+    pub async fn get_nodes(&self) -> HashMap<String, NodeStatus> {
+        self.cluster.get_nodes().await
+    }
+
+    pub fn last_value(&self, device: &Device) -> Option<DeviceValue> {
+        self.last_values.lock().get(&device.uniq_id()).cloned()
+    }
+
     async fn poll_leader(&self) -> Result<()> {
         if self.cluster.is_leader().await {
             trace!("Rebroadcasting leadership...");
@@ -172,16 +413,40 @@ impl MQTTService {
     }
 
     pub async fn publish(&self, topic: &str, message: &str, retain: bool, qos: QoS) -> Result<()> {
-        match self.client.lock().await.as_ref() {
-            Some(c) => {
-                c.publish(topic, qos, retain, message).await?;
-                Ok(())
+        if self.dry_run {
+            self.print_publish(topic, message, retain, qos);
+            if topic == self.leader_topic {
+                self.cluster.set_leader(message.to_string()).await;
             }
+            metrics::MQTT_PUBLISHES.inc(&[]);
+            return Ok(());
+        }
+        let res = match self.client.lock().await.as_ref() {
+            Some(c) => c
+                .publish(topic, qos, retain, message)
+                .await
+                .map_err(Error::from),
             None => Err(anyhow!("Not connected!")),
+        };
+        match res {
+            Ok(_) => metrics::MQTT_PUBLISHES.inc(&[]),
+            Err(_) => metrics::MQTT_PUBLISH_FAILURES.inc(&[]),
+        }
+        res
+    }
+
+    /// Publish retained messages in order
+    async fn publish_all(&self, messages: Vec<(String, String)>) -> Result<()> {
+        for (topic, message) in messages.iter() {
+            self.publish(topic, message, true, QoS::AtLeastOnce).await?;
         }
+        Ok(())
     }
 
     pub async fn subscribe(&self, topic: &str, qos: QoS) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
         match self.client.lock().await.as_ref() {
             Some(c) => {
                 c.subscribe(topic, qos).await?;
@@ -202,22 +467,71 @@ impl MQTTService {
         Ok(())
     }
 
-    pub async fn add_device(&self, device: &Device) -> Result<()> {
-        let payload = device.to_discovery();
-        let t = format!(
+    fn device_discovery_topic(&self, device: &Device) -> String {
+        format!(
             "{}/{}/{}/{}/config",
             self.discovery_topic,
             device.device_type(),
             crate_name!(),
             device.uniq_id(),
-        );
-        self.publish(
-            &t,
-            &serde_json::to_string(&payload)?,
-            true,
-            QoS::AtLeastOnce,
         )
-        .await?;
+    }
+
+    /// Announce a device in every configured discovery format
+    pub async fn add_device(&self, device: &Device) -> Result<()> {
+        if self.hass {
+            let payload = device.to_discovery();
+            self.publish(
+                &self.device_discovery_topic(device),
+                &serde_json::to_string(&payload)?,
+                true,
+                QoS::AtLeastOnce,
+            )
+            .await?;
+        }
+        if let Some(homie) = &self.homie {
+            self.publish_all(homie.add(device)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_device(&self, device: &Device) -> Result<()> {
+        if self.hass {
+            // An empty retained config removes the entity from Home Assistant
+            self.publish(
+                &self.device_discovery_topic(device),
+                "",
+                true,
+                QoS::AtLeastOnce,
+            )
+            .await?;
+        }
+        if let Some(homie) = &self.homie {
+            self.publish_all(homie.remove(device)).await?;
+        }
+        Ok(())
+    }
+
+    /// Mark the Homie device of this agent as disconnected before exiting
+    pub async fn shutdown(&self, timeout: Duration) -> Result<()> {
+        if let Some(homie) = &self.homie {
+            if self.is_connected() {
+                self.publish(
+                    &homie.local_state_topic(),
+                    "disconnected",
+                    true,
+                    QoS::AtLeastOnce,
+                )
+                .await?;
+                self.flush(timeout).await?;
+            }
+            // Disconnect cleanly so the broker drops the will instead of reporting it lost
+            let will = self.homie_will.lock().take();
+            if let Some((client, task)) = will {
+                client.disconnect().await?;
+                tokio::time::timeout(timeout, task).await??;
+            }
+        }
         Ok(())
     }
 
@@ -230,11 +544,30 @@ impl MQTTService {
                 QoS::AtLeastOnce,
             )
             .await?;
+            if let Some(homie) = &self.homie {
+                if let Some(value) = HomieState::value(device, &d.value) {
+                    self.publish(
+                        &homie.property_topic(device),
+                        &value,
+                        true,
+                        QoS::AtLeastOnce,
+                    )
+                    .await?;
+                }
+            }
 
             let mut attr = d.attr.clone();
             attr["update_timestamp"] = Local::now().to_rfc3339().into();
             attr["corvus_location"] = self.location.clone().into();
             attr["corvus_plugin"] = device.plugin().into();
+            self.last_values.lock().insert(
+                device.uniq_id(),
+                DeviceValue {
+                    value:     d.value.clone(),
+                    attr:      attr.clone(),
+                    timestamp: Local::now(),
+                },
+            );
             self.publish(
                 &device.attr_topic(),
                 &serde_json::to_string(&attr)?,
@@ -0,0 +1,306 @@
+use serde::{Serialize, Serializer};
+use std::{collections::BTreeMap, fmt};
+
+const FLAGS: u8 = 0x01;
+const INCOMPLETE_UUID16: u8 = 0x02;
+const COMPLETE_UUID16: u8 = 0x03;
+const INCOMPLETE_UUID32: u8 = 0x04;
+const COMPLETE_UUID32: u8 = 0x05;
+const INCOMPLETE_UUID128: u8 = 0x06;
+const COMPLETE_UUID128: u8 = 0x07;
+const SHORT_NAME: u8 = 0x08;
+const COMPLETE_NAME: u8 = 0x09;
+const TX_POWER: u8 = 0x0a;
+const SERVICE_DATA_UUID16: u8 = 0x16;
+const SERVICE_DATA_UUID32: u8 = 0x20;
+const SERVICE_DATA_UUID128: u8 = 0x21;
+const MANUFACTURER_DATA: u8 = 0xff;
+
+/// Offset of 16 and 32 bit UUIDs within the Bluetooth base UUID
+const BASE_UUID: u128 = 0x0000_0000_0000_1000_8000_0080_5f9b_34fb;
+
+/// A service UUID, 16 and 32 bit aliases are expanded to the full 128 bit form
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uuid(pub u128);
+
+impl Uuid {
+    pub const fn from_u16(uuid: u16) -> Self {
+        Self::from_u32(uuid as u32)
+    }
+
+    pub const fn from_u32(uuid: u32) -> Self {
+        Uuid(((uuid as u128) << 96) | BASE_UUID)
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let v = self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            (v >> 96) as u32,
+            (v >> 80) as u16,
+            (v >> 64) as u16,
+            (v >> 48) as u16,
+            v & 0xffff_ffff_ffff
+        )
+    }
+}
+
+impl Serialize for Uuid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The fields of an extended inquiry response or LE advertisement that are of interest,
+/// unknown structures are ignored
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Advertisement {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags:             Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_name:        Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_power:          Option<i8>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub service_uuids:     Vec<Uuid>,
+    #[serde(
+        skip_serializing_if = "BTreeMap::is_empty",
+        serialize_with = "hex_values"
+    )]
+    pub service_data:      BTreeMap<Uuid, Vec<u8>>,
+    #[serde(
+        skip_serializing_if = "BTreeMap::is_empty",
+        serialize_with = "hex_values"
+    )]
+    pub manufacturer_data: BTreeMap<u16, Vec<u8>>,
+}
+
+impl Advertisement {
+    /// Parse a sequence of length, type, value structures. Parsing stops at the first zero length
+    /// structure, which starts the padding of a fixed size response, or at truncated data.
+    pub fn parse(data: &[u8]) -> Self {
+        let mut adv = Advertisement::default();
+        let mut rest = data;
+        while let Some((&len, tail)) = rest.split_first() {
+            let len = len as usize;
+            if len == 0 {
+                break;
+            }
+            if tail.len() < len {
+                trace!("Truncated advertising data {:02x?}", data);
+                break;
+            }
+            let (typ, value) = (tail[0], &tail[1..len]);
+            rest = &tail[len..];
+            adv.parse_structure(typ, value);
+        }
+        adv
+    }
+
+    fn parse_structure(&mut self, typ: u8, value: &[u8]) {
+        match typ {
+            FLAGS => self.flags = value.first().copied(),
+            INCOMPLETE_UUID16 | COMPLETE_UUID16 => self
+                .service_uuids
+                .extend(value.chunks_exact(2).map(|c| Uuid::from_u16(le_u16(c)))),
+            INCOMPLETE_UUID32 | COMPLETE_UUID32 => self
+                .service_uuids
+                .extend(value.chunks_exact(4).map(|c| Uuid::from_u32(le_u32(c)))),
+            INCOMPLETE_UUID128 | COMPLETE_UUID128 => self
+                .service_uuids
+                .extend(value.chunks_exact(16).map(|c| Uuid(le_u128(c)))),
+            // A complete name wins over a shortened one regardless of their order
+            SHORT_NAME if self.local_name.is_some() => (),
+            SHORT_NAME | COMPLETE_NAME => {
+                self.local_name = Some(String::from_utf8_lossy(value).trim_end_matches('\0').into())
+            }
+            TX_POWER => self.tx_power = value.first().map(|&p| p as i8),
+            SERVICE_DATA_UUID16 if value.len() >= 2 => {
+                let uuid = Uuid::from_u16(le_u16(value));
+                self.service_data.insert(uuid, value[2..].to_vec());
+            }
+            SERVICE_DATA_UUID32 if value.len() >= 4 => {
+                let uuid = Uuid::from_u32(le_u32(value));
+                self.service_data.insert(uuid, value[4..].to_vec());
+            }
+            SERVICE_DATA_UUID128 if value.len() >= 16 => {
+                let uuid = Uuid(le_u128(value));
+                self.service_data.insert(uuid, value[16..].to_vec());
+            }
+            MANUFACTURER_DATA if value.len() >= 2 => {
+                self.manufacturer_data
+                    .insert(le_u16(value), value[2..].to_vec());
+            }
+            _ => (),
+        }
+    }
+
+    /// Take over the fields of a later advertisement, scan responses only carry part of the data
+    pub fn merge(&mut self, other: Advertisement) {
+        if other.flags.is_some() {
+            self.flags = other.flags;
+        }
+        if other.local_name.is_some() {
+            self.local_name = other.local_name;
+        }
+        if other.tx_power.is_some() {
+            self.tx_power = other.tx_power;
+        }
+        for uuid in other.service_uuids {
+            if !self.service_uuids.contains(&uuid) {
+                self.service_uuids.push(uuid);
+            }
+        }
+        self.service_data.extend(other.service_data);
+        self.manufacturer_data.extend(other.manufacturer_data);
+    }
+}
+
+fn le_u16(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn le_u128(b: &[u8]) -> u128 {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&b[..16]);
+    u128::from_le_bytes(bytes)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Serialize a map of binary values with its keys as strings, company identifiers in the
+/// usual `0x004c` form, and the values hex encoded
+fn hex_values<K, S>(map: &BTreeMap<K, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: HexKey,
+    S: Serializer,
+{
+    serializer.collect_map(map.iter().map(|(k, v)| (k.key(), hex(v))))
+}
+
+trait HexKey {
+    fn key(&self) -> String;
+}
+
+impl HexKey for u16 {
+    fn key(&self) -> String {
+        format!("0x{:04x}", self)
+    }
+}
+
+impl HexKey for Uuid {
+    fn key(&self) -> String {
+        self.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAGS_LE: [u8; 3] = [0x02, FLAGS, 0x06];
+
+    fn parse(structures: &[&[u8]]) -> Advertisement {
+        Advertisement::parse(&structures.concat())
+    }
+
+    #[test]
+    fn stops_at_truncated_structures() {
+        let adv = parse(&[&FLAGS_LE, &[0x05, COMPLETE_NAME, b'a', b'b']]);
+        assert_eq!(adv.flags, Some(0x06));
+        assert_eq!(adv.local_name, None);
+
+        // A length without a type
+        assert_eq!(parse(&[&FLAGS_LE, &[0x01]]).flags, Some(0x06));
+        assert_eq!(Advertisement::parse(&[]), Advertisement::default());
+    }
+
+    #[test]
+    fn stops_at_zero_length_padding() {
+        let adv = parse(&[&FLAGS_LE, &[0x00], &[0x03, COMPLETE_NAME, b'a', b'b']]);
+        assert_eq!(adv.flags, Some(0x06));
+        assert_eq!(adv.local_name, None);
+    }
+
+    #[test]
+    fn ignores_trailing_bytes_of_uuid_lists() {
+        let adv = parse(&[
+            &[0x06, INCOMPLETE_UUID16, 0x0f, 0x18, 0x1a, 0x18, 0xaa],
+            &[0x06, COMPLETE_UUID32, 0x78, 0x56, 0x34, 0x12, 0xbb],
+        ]);
+        assert_eq!(
+            adv.service_uuids,
+            [
+                Uuid::from_u16(0x180f),
+                Uuid::from_u16(0x181a),
+                Uuid::from_u32(0x1234_5678)
+            ]
+        );
+        assert_eq!(
+            adv.service_uuids[0].to_string(),
+            "0000180f-0000-1000-8000-00805f9b34fb"
+        );
+
+        let mut uuid128 = vec![0x12, COMPLETE_UUID128];
+        uuid128.extend(0..16);
+        uuid128.push(0xcc);
+        let adv = Advertisement::parse(&uuid128);
+        assert_eq!(adv.service_uuids.len(), 1);
+        assert_eq!(
+            adv.service_uuids[0].to_string(),
+            "0f0e0d0c-0b0a-0908-0706-050403020100"
+        );
+    }
+
+    #[test]
+    fn prefers_the_complete_name() {
+        let short: &[u8] = &[0x04, SHORT_NAME, b'P', b'h', b'o'];
+        let complete: &[u8] = &[0x06, COMPLETE_NAME, b'P', b'h', b'o', b'n', b'e'];
+        assert_eq!(
+            parse(&[short, complete]).local_name.as_deref(),
+            Some("Phone")
+        );
+        assert_eq!(
+            parse(&[complete, short]).local_name.as_deref(),
+            Some("Phone")
+        );
+        assert_eq!(parse(&[short]).local_name.as_deref(), Some("Pho"));
+    }
+
+    #[test]
+    fn merges_scan_responses() {
+        let mut adv = parse(&[
+            &FLAGS_LE,
+            &[0x03, COMPLETE_UUID16, 0x0f, 0x18],
+            &[0x05, MANUFACTURER_DATA, 0x4c, 0x00, 0x10, 0x05],
+        ]);
+        adv.merge(parse(&[
+            &[0x03, COMPLETE_NAME, b'T', b'v'],
+            &[0x02, TX_POWER, 0xf4],
+            &[0x05, COMPLETE_UUID16, 0x0f, 0x18, 0x1a, 0x18],
+            &[0x05, SERVICE_DATA_UUID16, 0x0f, 0x18, 0x64, 0x01],
+        ]));
+
+        assert_eq!(adv.flags, Some(0x06));
+        assert_eq!(adv.local_name.as_deref(), Some("Tv"));
+        assert_eq!(adv.tx_power, Some(-12));
+        assert_eq!(
+            adv.service_uuids,
+            [Uuid::from_u16(0x180f), Uuid::from_u16(0x181a)]
+        );
+        assert_eq!(
+            adv.service_data.get(&Uuid::from_u16(0x180f)),
+            Some(&vec![0x64, 0x01])
+        );
+        assert_eq!(adv.manufacturer_data.get(&0x004c), Some(&vec![0x10, 0x05]));
+    }
+}
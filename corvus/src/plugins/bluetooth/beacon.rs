@@ -0,0 +1,178 @@
+use super::advertisement::{hex, Advertisement, Uuid};
+use serde::Serialize;
+
+/// Company identifier of Apple, the only one iBeacon frames are sent under
+const APPLE: u16 = 0x004c;
+const EDDYSTONE: Uuid = Uuid::from_u16(0xfeaa);
+
+const EDDYSTONE_UID: u8 = 0x00;
+const EDDYSTONE_URL: u8 = 0x10;
+
+const URL_SCHEMES: &[&str] = &["http://www.", "https://www.", "http://", "https://"];
+const URL_EXPANSIONS: &[&str] = &[
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/", ".com", ".org", ".edu", ".net",
+    ".info", ".biz", ".gov",
+];
+
+/// A beacon frame, measured powers are the RSSI expected at 1m
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "beacon_type", rename_all = "snake_case")]
+pub enum Beacon {
+    #[serde(rename = "ibeacon")]
+    IBeacon {
+        uuid:           Uuid,
+        major:          u16,
+        minor:          u16,
+        measured_power: i8,
+    },
+    EddystoneUid {
+        namespace:      String,
+        instance:       String,
+        measured_power: i8,
+    },
+    EddystoneUrl {
+        url:            String,
+        measured_power: i8,
+    },
+    #[serde(rename = "altbeacon")]
+    AltBeacon {
+        beacon_id:      String,
+        measured_power: i8,
+        manufacturer:   u16,
+    },
+}
+
+impl Beacon {
+    pub fn parse(adv: &Advertisement) -> Option<Self> {
+        if let Some(beacon) = adv.manufacturer_data.get(&APPLE).and_then(|d| ibeacon(d)) {
+            return Some(beacon);
+        }
+        if let Some(beacon) = adv
+            .manufacturer_data
+            .iter()
+            .find_map(|(company, d)| altbeacon(*company, d))
+        {
+            return Some(beacon);
+        }
+        adv.service_data.get(&EDDYSTONE).and_then(|d| eddystone(d))
+    }
+
+    /// Stable identity of the beacon as configured for a device, URL frames are shared by any
+    /// number of beacons and have none
+    pub fn identity(&self) -> Option<String> {
+        match self {
+            Beacon::IBeacon {
+                uuid, major, minor, ..
+            } => Some(format!("ibeacon:{}:{}:{}", uuid, major, minor)),
+            Beacon::EddystoneUid {
+                namespace,
+                instance,
+                ..
+            } => Some(format!("eddystone:{}:{}", namespace, instance)),
+            Beacon::AltBeacon { beacon_id, .. } => Some(format!("altbeacon:{}", beacon_id)),
+            Beacon::EddystoneUrl { .. } => None,
+        }
+    }
+}
+
+fn ibeacon(d: &[u8]) -> Option<Beacon> {
+    if d.len() != 23 || d[0] != 0x02 || d[1] != 0x15 {
+        return None;
+    }
+    let mut uuid = [0u8; 16];
+    uuid.copy_from_slice(&d[2..18]);
+    Some(Beacon::IBeacon {
+        uuid:           Uuid(u128::from_be_bytes(uuid)),
+        major:          u16::from_be_bytes([d[18], d[19]]),
+        minor:          u16::from_be_bytes([d[20], d[21]]),
+        measured_power: d[22] as i8,
+    })
+}
+
+fn altbeacon(company: u16, d: &[u8]) -> Option<Beacon> {
+    if d.len() != 24 || d[0] != 0xbe || d[1] != 0xac {
+        return None;
+    }
+    Some(Beacon::AltBeacon {
+        beacon_id:      hex(&d[2..22]),
+        measured_power: d[22] as i8,
+        manufacturer:   company,
+    })
+}
+
+fn eddystone(d: &[u8]) -> Option<Beacon> {
+    match *d.first()? {
+        EDDYSTONE_UID if d.len() >= 18 => Some(Beacon::EddystoneUid {
+            measured_power: at_one_meter(d[1]),
+            namespace:      hex(&d[2..12]),
+            instance:       hex(&d[12..18]),
+        }),
+        EDDYSTONE_URL if d.len() >= 3 => {
+            let mut url = URL_SCHEMES.get(d[2] as usize)?.to_string();
+            for &b in d[3..].iter() {
+                match URL_EXPANSIONS.get(b as usize) {
+                    Some(expansion) => url.push_str(expansion),
+                    None if (0x21..0x7f).contains(&b) => url.push(b as char),
+                    None => return None,
+                }
+            }
+            Some(Beacon::EddystoneUrl {
+                measured_power: at_one_meter(d[1]),
+                url,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Eddystone calibrates its transmit power at 0m, which loses about 41dB by 1m
+fn at_one_meter(power: u8) -> i8 {
+    (power as i8).saturating_sub(41)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adv(data: &[u8]) -> Advertisement {
+        Advertisement::parse(data)
+    }
+
+    #[test]
+    fn parses_ibeacon() {
+        let mut data = vec![0x1a, 0xff, 0x4c, 0x00, 0x02, 0x15];
+        data.extend_from_slice(&[
+            0xf7, 0x82, 0x6d, 0xa6, 0x4f, 0xa2, 0x4e, 0x98, 0x80, 0x24, 0xbc, 0x5b, 0x71, 0xe0,
+            0x89, 0x3e,
+        ]);
+        data.extend_from_slice(&[0x00, 0x01, 0x00, 0x2a, 0xc5]);
+        let beacon = Beacon::parse(&adv(&data)).unwrap();
+        assert_eq!(
+            beacon.identity().as_deref(),
+            Some("ibeacon:f7826da6-4fa2-4e98-8024-bc5b71e0893e:1:42")
+        );
+        assert!(matches!(
+            beacon,
+            Beacon::IBeacon {
+                measured_power: -59,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn parses_eddystone_url() {
+        let data = [
+            0x0e, 0x16, 0xaa, 0xfe, 0x10, 0xeb, 0x03, b'e', b'x', b'a', b'm', b'p', b'l', b'e',
+            0x07,
+        ];
+        assert_eq!(
+            Beacon::parse(&adv(&data)),
+            Some(Beacon::EddystoneUrl {
+                url:            "https://example.com".into(),
+                measured_power: -62,
+            })
+        );
+    }
+}
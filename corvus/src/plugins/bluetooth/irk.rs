@@ -0,0 +1,90 @@
+use crate::prelude::*;
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use std::fmt;
+
+/// An Identity Resolving Key, used to recognise the resolvable private addresses a device rotates
+/// through as belonging to it
+#[derive(Clone)]
+pub struct Irk(Aes128);
+
+impl fmt::Debug for Irk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Irk(..)")
+    }
+}
+
+impl Irk {
+    /// Parse a key given as 32 hex digits, most significant byte first as in the Bluetooth
+    /// Core specification
+    pub fn parse(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("An identity resolving key must be 32 hex digits");
+        }
+        let mut key = [0u8; 16];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(Irk(Aes128::new(&key.into())))
+    }
+
+    /// The random address hash function `ah`, the lower 24 bits of the key encrypting the
+    /// zero padded `prand`
+    pub fn ah(&self, prand: [u8; 3]) -> [u8; 3] {
+        let mut block = GenericArray::from([0u8; 16]);
+        block[13..].copy_from_slice(&prand);
+        self.0.encrypt_block(&mut block);
+        [block[13], block[14], block[15]]
+    }
+
+    /// Check whether an address, most significant byte first, was generated from this key
+    pub fn resolves(&self, address: &[u8; 6]) -> bool {
+        is_resolvable(address)
+            && self.ah([address[0], address[1], address[2]]) == [address[3], address[4], address[5]]
+    }
+}
+
+/// Resolvable private addresses are random addresses with `01` as their two most significant bits
+pub fn is_resolvable(address: &[u8; 6]) -> bool {
+    address[0] >> 6 == 0b01
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Core specification, Vol 3 Part H, Appendix D.7 "ah Random Address Hash Function"
+    const IRK: &str = "ec0234a357c8ad05341010a60a397d9b";
+    const PRAND: [u8; 3] = [0x70, 0x81, 0x94];
+    const HASH: [u8; 3] = [0x0d, 0xfb, 0xaa];
+
+    #[test]
+    fn ah_matches_sample_data() {
+        let irk = Irk::parse(IRK).unwrap();
+        assert_eq!(irk.ah(PRAND), HASH);
+    }
+
+    #[test]
+    fn resolves_sample_address() {
+        let irk = Irk::parse(IRK).unwrap();
+        assert!(irk.resolves(&[0x70, 0x81, 0x94, 0x0d, 0xfb, 0xaa]));
+        assert!(!irk.resolves(&[0x70, 0x81, 0x94, 0x0d, 0xfb, 0xab]));
+    }
+
+    #[test]
+    fn ignores_addresses_that_are_not_resolvable() {
+        // Same hash, but marked as a static random address
+        let irk = Irk::parse(IRK).unwrap();
+        assert!(!irk.resolves(&[0xf0, 0x81, 0x94, 0x0d, 0xfb, 0xaa]));
+        assert!(!is_resolvable(&[0xc0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert!(Irk::parse("ec0234a357c8ad05").is_err());
+        assert!(Irk::parse("zz0234a357c8ad05341010a60a397d9b").is_err());
+    }
+}
@@ -1,37 +1,81 @@
 use super::*;
+use crate::metrics;
+use advertisement::Advertisement;
+use beacon::Beacon;
 use bluez::{
     client::*,
     interface::{controller::*, event::Event},
+    Address,
 };
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, str::FromStr};
+use irk::Irk;
+use presence::{Location, Room, Track};
+use sensors::{SensorData, SensorKind};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use tokio::time::{sleep, Duration};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+mod advertisement;
+mod beacon;
+mod irk;
+mod presence;
+mod sensors;
+
+#[derive(Clone, Debug, Serialize)]
 struct Reading {
-    rssi:        i8,
-    mac_address: String,
+    /// Key of the device, its beacon identity or address
+    device_id:       String,
+    rssi:            i8,
+    mac_address:     String,
+    address_type:    String,
+    /// Address the reading was received from when it was resolved to `mac_address`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private_address: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    beacon:          Option<Beacon>,
     #[serde(with = "time_format")]
-    timestamp:   DateTime<Utc>,
+    timestamp:       DateTime<Utc>,
+    #[serde(flatten)]
+    advertisement:   Advertisement,
 }
 
-type NodeReadings = HashMap<String, RollingVec<Reading>>;
+/// Filtered signal strength of a device by location
+type NodeReadings = HashMap<String, Track>;
+
+/// Readings older than this count as gone
+const READING_TIMEOUT: i64 = 60;
+/// Locations that haven't heard a device for this long no longer compete for it
+const TRACK_TIMEOUT: i64 = 90;
 
 #[derive(Clone, Debug)]
 pub struct BluetoothPlugin {
-    registry: DeviceRegistry,
-    mqtt:     MQTTService,
-    location: String,
-    readings: SharedRwLock<HashMap<String, Reading>>,
-    nodes:    SharedRwLock<HashMap<String, NodeReadings>>,
-    name:     String,
+    registry:  DeviceRegistry,
+    sinks:     Sinks,
+    location:  String,
+    readings:  SharedRwLock<HashMap<String, Reading>>,
+    nodes:     SharedRwLock<HashMap<String, NodeReadings>>,
+    name:      String,
+    /// Configured devices by their key
+    devices:   Arc<HashMap<String, BluetoothDevice>>,
+    /// Keys of devices last seen through a beacon by address, telemetry and scan responses are
+    /// sent without the beacon identity
+    beacons:   SharedRwLock<HashMap<String, String>>,
+    /// Last packet counter of every passive sensor
+    packets:   SharedRwLock<HashMap<String, u32>>,
+    /// Keys of the devices using resolvable private addresses, with their identity address
+    irks:      Arc<Vec<(String, Irk)>>,
+    /// Room every device was last placed in by the leader
+    locations: SharedRwLock<HashMap<String, Location>>,
+    options:   Arc<BluetoothOptions>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum BTDeviceType {
     Rssi(String),
     Location(String),
+    Tracker(String),
+    Sensor(String, SensorKind),
+    Unknown,
 }
 
 impl BluetoothPlugin {
@@ -39,28 +83,118 @@ impl BluetoothPlugin {
         name: String,
         location: String,
         registry: DeviceRegistry,
-        mqtt: MQTTService,
-    ) -> Self {
-        Self {
+        sinks: Sinks,
+        options: BluetoothOptions,
+    ) -> Result<Self> {
+        let mut irks = vec![];
+        for d in options.devices.iter() {
+            if let (Some(irk), Some(mac)) = (&d.irk, &d.mac) {
+                let irk = Irk::parse(irk).with_context(|| format!("Device '{}'", d.name))?;
+                irks.push((mac.to_lowercase(), irk));
+            }
+        }
+        let devices = options
+            .devices
+            .iter()
+            .filter_map(|d| Some((d.key()?, d.clone())))
+            .collect();
+        Ok(Self {
             readings: Default::default(),
             nodes: Default::default(),
+            beacons: Default::default(),
+            packets: Default::default(),
+            devices: Arc::new(devices),
+            irks: Arc::new(irks),
+            locations: Default::default(),
+            options: Arc::new(options),
             name,
             location,
             registry,
-            mqtt,
+            sinks,
+        })
+    }
+
+    /// Identity address of a device with a known key using a resolvable private address
+    fn resolve(&self, address: &Address, address_type: AddressType) -> Option<&str> {
+        if address_type != AddressType::LERandom || self.irks.is_empty() {
+            return None;
+        }
+        // Addresses are stored least significant byte first
+        let mut bytes = [0u8; 6];
+        bytes.copy_from_slice(address.as_ref());
+        bytes.reverse();
+        if !irk::is_resolvable(&bytes) {
+            return None;
+        }
+        self.irks
+            .iter()
+            .find(|(_, irk)| irk.resolves(&bytes))
+            .map(|(mac, _)| mac.as_str())
+    }
+
+    /// Key of the device an advertisement belongs to, a configured beacon identity, the
+    /// identity address of a resolved private address or the address itself
+    async fn device_key(
+        &self,
+        address: &Address,
+        address_type: AddressType,
+        beacon: Option<&Beacon>,
+    ) -> String {
+        let mac = address.to_string();
+        let identity = beacon
+            .and_then(|b| b.identity())
+            .filter(|id| self.devices.contains_key(id));
+        let mut beacons = self.beacons.write().await;
+        if let Some(id) = identity {
+            beacons.insert(mac, id.to_string());
+            return id;
+        }
+        if let Some(id) = beacons.get(&mac) {
+            return id.to_string();
+        }
+        drop(beacons);
+        match self.resolve(address, address_type) {
+            Some(identity) => {
+                trace!("Resolved {} to {}", address, identity);
+                identity.into()
+            }
+            None => mac,
         }
     }
 
+    /// Friendly name of a device, readings of other agents may include devices that are only
+    /// configured there, those are named after the plugin and their key
+    fn device_name(&self, key: &str) -> String {
+        self.devices
+            .get(&key.to_lowercase())
+            .map(|d| d.name.to_string())
+            .unwrap_or_else(|| format!("{} {}", self.name, key))
+    }
+
     async fn get_device(&self, typ: BTDeviceType) -> Result<Device> {
         let name = match typ {
-            BTDeviceType::Rssi(ref mac) => format!("{} {} {}", self.location, self.name, mac),
-            BTDeviceType::Location(ref mac) => format!("{} {} Location", self.name, mac),
+            BTDeviceType::Rssi(ref key) => format!("{} {}", self.location, self.device_name(key)),
+            BTDeviceType::Location(ref key) => format!("{} Location", self.device_name(key)),
+            BTDeviceType::Tracker(ref key) => self.device_name(key),
+            BTDeviceType::Sensor(ref key, kind) => {
+                format!("{} {}", self.device_name(key), kind.name())
+            }
+            BTDeviceType::Unknown => format!("{} {} Unknown Devices", self.location, self.name),
         };
         let device = self.registry.get_by_name(&name).await;
         if let Some(device) = device {
             Ok(device)
         } else {
-            let device = match typ {
+            let icon = match typ {
+                BTDeviceType::Rssi(ref key)
+                | BTDeviceType::Location(ref key)
+                | BTDeviceType::Tracker(ref key) => self
+                    .devices
+                    .get(&key.to_lowercase())
+                    .and_then(|d| d.icon.clone()),
+                _ => None,
+            };
+            let mut device = match typ {
                 BTDeviceType::Rssi(_) => self
                     .registry
                     .new_device(
@@ -68,8 +202,7 @@ impl BluetoothPlugin {
                         DeviceType::Sensor(SensorDeviceClass::SignalStrength),
                         self.name.to_string(),
                     )
-                    .with_unit_of_measurement("dBm".into())
-                    .build(),
+                    .with_unit_of_measurement("dBm".into()),
                 BTDeviceType::Location(_) => self
                     .registry
                     .new_device(
@@ -77,12 +210,167 @@ impl BluetoothPlugin {
                         DeviceType::Sensor(SensorDeviceClass::None),
                         self.name.to_string(),
                     )
-                    .into_cluster_device()
-                    .build(),
+                    .into_cluster_device(),
+                BTDeviceType::Tracker(_) => self
+                    .registry
+                    .new_device(
+                        name,
+                        DeviceType::DeviceTracker(TrackerSourceType::Bluetooth),
+                        self.name.to_string(),
+                    )
+                    .into_cluster_device(),
+                // Readings don't depend on who received them
+                BTDeviceType::Sensor(_, kind) => self
+                    .registry
+                    .new_device(
+                        name,
+                        DeviceType::Sensor(kind.device_class()),
+                        self.name.to_string(),
+                    )
+                    .with_unit_of_measurement(kind.unit().into())
+                    .into_cluster_device(),
+                BTDeviceType::Unknown => self
+                    .registry
+                    .new_device(
+                        name,
+                        DeviceType::Sensor(SensorDeviceClass::None),
+                        self.name.to_string(),
+                    )
+                    .with_unit_of_measurement("devices".into()),
             };
-            self.registry.register(device).await
+            if let Some(icon) = icon {
+                device = device.with_icon(icon);
+            }
+            self.registry.register(device.build()).await
         }
     }
+
+    /// Publish the RSSI of a found device, classic devices report inquiry results and LE devices
+    /// advertisements or scan responses, which are merged with what was seen before
+    async fn device_found(
+        &self,
+        address: Address,
+        address_type: AddressType,
+        rssi: i8,
+        eir_data: &[u8],
+    ) -> Result<()> {
+        trace!("Bluetooth device found: {}", address);
+        let mut advertisement = Advertisement::parse(eir_data);
+        // Beacons alternate between frames, decode them before merging with previous ones
+        let mut beacon = Beacon::parse(&advertisement);
+        let sensor = SensorData::parse(&advertisement);
+        let key = self
+            .device_key(&address, address_type, beacon.as_ref())
+            .await;
+        let (mac_address, private_address) = if self.irks.iter().any(|(mac, _)| *mac == key) {
+            (key.to_string(), Some(address.to_string()))
+        } else {
+            (address.to_string(), None)
+        };
+
+        let mut r = self.readings.write().await;
+        if let Some(previous) = r.get(&key) {
+            let mut merged = previous.advertisement.clone();
+            merged.merge(advertisement);
+            advertisement = merged;
+            beacon = beacon.or_else(|| previous.beacon.clone());
+        }
+        let reading = Reading {
+            device_id: key.to_string(),
+            rssi,
+            timestamp: Utc::now(),
+            mac_address,
+            address_type: address_type_name(address_type).into(),
+            private_address,
+            beacon,
+            advertisement,
+        };
+        r.insert(key.to_string(), reading.clone());
+        let labels = [("plugin", self.name.as_str())];
+        metrics::BLUETOOTH_DEVICES.set(&labels, r.len() as f64);
+        drop(r);
+
+        if !self.devices.contains_key(&key) {
+            return Ok(());
+        }
+        if let Some(sensor) = sensor {
+            self.publish_sensor(&key, rssi, &sensor).await?;
+        }
+
+        debug!("Updating RSSI for {} to {}", key, rssi);
+        let d = self.get_device(BTDeviceType::Rssi(key)).await?;
+        let mut attr = Document::new(&reading)?;
+        attr["rssi_type"] = "single_reading".into();
+        let dev = DeviceUpdate {
+            device: Some(d),
+            value: rssi.into(),
+            attr,
+        };
+        self.sinks.update_device(&dev).await
+    }
+}
+
+impl BluetoothPlugin {
+    /// Publish the readings of a passive sensor, once per packet and only from the agent hearing
+    /// it best so agents in other rooms don't overwrite them with stale copies
+    async fn publish_sensor(&self, key: &str, rssi: i8, sensor: &SensorData) -> Result<()> {
+        if let Some(packet) = sensor.packet {
+            if self.packets.write().await.insert(key.into(), packet) == Some(packet) {
+                trace!("Skipping repeated packet {} of {}", packet, key);
+                return Ok(());
+            }
+        }
+        let best = match self.nodes.read().await.get(key) {
+            Some(readings) => best_location(readings),
+            None => None,
+        };
+        if let Some((location, best)) = best {
+            if location != self.location && best > rssi as f64 {
+                trace!("{} is heard better in {}", key, location);
+                return Ok(());
+            }
+        }
+
+        let mut attr = Document::new(sensor)?;
+        attr["rssi"] = rssi.into();
+        for (kind, value) in sensor.values() {
+            let dev = DeviceUpdate {
+                device: Some(
+                    self.get_device(BTDeviceType::Sensor(key.into(), kind))
+                        .await?,
+                ),
+                value:  value.into(),
+                attr:   attr.clone(),
+            };
+            self.sinks.update_device(&dev).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Locations that heard a device recently, with its filtered strength there
+fn rooms(readings: &NodeReadings) -> Vec<(String, f64)> {
+    let now = Utc::now();
+    readings
+        .iter()
+        .filter(|(_, track)| !track.expired(now, chrono::Duration::seconds(TRACK_TIMEOUT)))
+        .map(|(location, track)| (location.to_string(), track.rssi))
+        .collect()
+}
+
+/// The location with the strongest filtered signal
+fn best_location(readings: &NodeReadings) -> Option<(String, f64)> {
+    rooms(readings)
+        .into_iter()
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+}
+
+fn address_type_name(address_type: AddressType) -> &'static str {
+    match address_type {
+        AddressType::BREDR => "bredr",
+        AddressType::LEPublic => "le_public",
+        AddressType::LERandom => "le_random",
+    }
 }
 
 #[async_trait]
@@ -90,40 +378,74 @@ impl Plugin for BluetoothPlugin {
     async fn leader_heartbeat(&self, _: String, _: ClusterNodes) -> Result<()> {
         trace!("BluetoothService Leader Heartbeat");
 
-        let mut mac_locations: HashMap<String, String> = Default::default();
-        for (mac, map) in self.nodes.read().await.iter() {
-            let mut loc_readings = ("Unknown", i8::MIN);
-            for (location, readings) in map.iter() {
-                let rssi = readings
-                    .get_all()
-                    .await
-                    .iter()
-                    .rev()
-                    .take(3)
-                    .fold((0, 0), |acc, x| (acc.0 + (x.rssi as i64), acc.1 + 1));
-                if rssi.1 > 0 {
-                    // debug!("{:?}", rssi);
-                    let rssi = (rssi.0 / rssi.1) as i8;
-                    // debug!("{}", rssi);
-                    if rssi > loc_readings.1 {
-                        loc_readings = (location, rssi);
-                    }
-                }
+        let now = Utc::now();
+        let delay = chrono::Duration::seconds(self.options.hysteresis_secs as i64);
+        let consider_home = chrono::Duration::seconds(self.options.consider_home as i64);
+        let mut updates = vec![];
+        let nodes = self.nodes.read().await;
+        let mut locations = self.locations.write().await;
+        for (key, readings) in nodes.iter() {
+            let last_seen = match readings.values().map(|track| track.updated).max() {
+                Some(last_seen) => last_seen,
+                None => continue,
+            };
+            let location = locations.entry(key.to_string()).or_default();
+            // Rooms are only kept while the device is heard by some agent
+            let home = now.signed_duration_since(last_seen) <= consider_home;
+            if !home {
+                *location = Default::default();
             }
-            mac_locations.insert(mac.into(), loc_readings.0.into());
+            let rooms = rooms(readings);
+            let location = location
+                .update(&rooms, self.options.hysteresis_db, delay, now)
+                .map(|l| l.to_string());
+            let details: BTreeMap<_, _> = readings
+                .iter()
+                .filter(|(location, _)| rooms.iter().any(|(room, _)| room == *location))
+                .map(|(location, track)| {
+                    let room = Room {
+                        rssi:     (track.rssi * 100.0).round() / 100.0,
+                        distance: track.distance(self.options.path_loss),
+                    };
+                    (location.to_string(), room)
+                })
+                .collect();
+            updates.push((key.to_string(), home, last_seen, location, details));
         }
-        trace!("{:?}", mac_locations);
+        drop(locations);
+        drop(nodes);
+        trace!("{:?}", updates);
 
-        for (mac, location) in mac_locations.into_iter() {
-            let d = self.get_device(BTDeviceType::Location(mac)).await?;
+        for (key, home, last_seen, location, rooms) in updates.into_iter() {
+            let current = location.as_ref().and_then(|l| rooms.get(l)).cloned();
+            let location = match location {
+                Some(location) if home => location,
+                _ => "not_home".into(),
+            };
             let mut attr = Document::default();
             attr["device_location"] = location.to_string().into();
+            attr["last_seen"] = last_seen.to_rfc3339().into();
+            if let Some(current) = current {
+                attr["rssi"] = current.rssi.into();
+                attr["distance"] = current.distance.into();
+            }
+            attr["rooms"] = Document::new(&rooms)?;
+
+            let d = self.get_device(BTDeviceType::Tracker(key.clone())).await?;
+            let dev = DeviceUpdate {
+                device: Some(d),
+                value:  if home { "home" } else { "not_home" }.into(),
+                attr:   attr.clone(),
+            };
+            self.sinks.update_device(&dev).await?;
+
+            let d = self.get_device(BTDeviceType::Location(key)).await?;
             let dev = DeviceUpdate {
                 device: Some(d),
                 value: location.into(),
                 attr,
             };
-            self.mqtt.update_device(&dev).await?;
+            self.sinks.update_device(&dev).await?;
         }
         Ok(())
     }
@@ -131,10 +453,33 @@ impl Plugin for BluetoothPlugin {
     async fn heartbeat(&self, _: String) -> Result<()> {
         trace!("BluetoothPlugin Heartbeat");
         let n = Utc::now();
+        let stale =
+            |r: &Reading| n.signed_duration_since(r.timestamp).num_seconds() > READING_TIMEOUT;
+        // Unknown devices are only counted, forget about them once they are gone
+        let mut readings = self.readings.write().await;
+        readings.retain(|mac, r| self.devices.contains_key(mac) || !stale(r));
+        let unknown = readings
+            .keys()
+            .filter(|mac| !self.devices.contains_key(*mac))
+            .count();
+        drop(readings);
+        if self.options.track_unknown {
+            let d = self.get_device(BTDeviceType::Unknown).await?;
+            let dev = DeviceUpdate {
+                device: Some(d),
+                value:  (unknown as u64).into(),
+                attr:   Default::default(),
+            };
+            self.sinks.update_device(&dev).await?;
+        }
+
         let readings = self.readings.read().await;
         for (mac, reading) in readings.iter() {
+            if !self.devices.contains_key(mac) {
+                continue;
+            }
             let d = self.get_device(BTDeviceType::Rssi(mac.to_string())).await?;
-            if n.signed_duration_since(reading.timestamp).num_seconds() > 60 {
+            if stale(reading) {
                 let mut r = reading.clone();
                 r.rssi = i8::MIN;
                 let dev = DeviceUpdate {
@@ -142,7 +487,7 @@ impl Plugin for BluetoothPlugin {
                     value:  r.rssi.into(),
                     attr:   Document::new(&r)?,
                 };
-                self.mqtt.update_device(&dev).await?;
+                self.sinks.update_device(&dev).await?;
             }
         }
         Ok(())
@@ -153,25 +498,37 @@ impl Plugin for BluetoothPlugin {
             let location = data["corvus_location"].to_string();
             let rssi = data["rssi"].as_i64().unwrap_or_default();
             let mac_address = data["mac_address"].to_string();
-            let reading = Reading {
-                timestamp:   Utc::now(),
-                rssi:        rssi as i8,
-                mac_address: mac_address.to_string(),
+            // Agents without beacon support key readings by address only
+            let device_id = match &data["device_id"] {
+                id if id.is_unit() => mac_address.to_string(),
+                id => id.to_string(),
             };
+            let offset = self
+                .options
+                .rssi_offsets
+                .get(&location)
+                .copied()
+                .unwrap_or_default();
+            let rssi = rssi as f64 + offset;
+            // Configured power wins over what the device advertises
+            let reference = self
+                .devices
+                .get(&device_id)
+                .and_then(|d| d.tx_power)
+                .or_else(|| data["measured_power"].as_i64().map(|p| p as i8))
+                .unwrap_or(self.options.tx_power) as f64;
+            let filter = &self.options.filter;
+            let timeout = chrono::Duration::seconds(TRACK_TIMEOUT);
             let mut nodes = self.nodes.write().await;
-            if !nodes.contains_key(&mac_address) {
-                nodes.insert(mac_address.clone(), Default::default());
+            let readings = nodes.entry(device_id).or_default();
+            match readings.get_mut(&location) {
+                Some(track) if !track.expired(Utc::now(), timeout) => {
+                    track.update(filter, rssi, reference)
+                }
+                _ => {
+                    readings.insert(location, Track::new(filter, rssi, reference));
+                }
             }
-            let readings = nodes.get_mut(&mac_address).unwrap();
-            let rv = readings.get(&location);
-            let rv = if let Some(rv) = rv {
-                rv
-            } else {
-                let rv = RollingVec::new(Duration::from_secs(90));
-                readings.insert(location.to_string(), rv);
-                readings.get(&location).unwrap()
-            };
-            rv.add(reading).await;
         }
         Ok(())
     }
@@ -210,56 +567,44 @@ impl Plugin for BluetoothPlugin {
 
             loop {
                 let response = client.process().await?;
+                let event = match &response.event {
+                    Event::DeviceFound { .. } => "device_found",
+                    Event::Discovering { .. } => "discovering",
+                    _ => "other",
+                };
+                metrics::BLUETOOTH_EVENTS.inc(&[("plugin", &self.name), ("event", event)]);
 
                 match response.event {
-                        Event::DeviceFound {
-                            address,
-                            address_type,
-                            rssi,
-                            ..
-                            // flags,
-                        } => {
-                            trace!("Bluetooth device found: {}", address);
-                            if address_type == AddressType::BREDR {
-                                debug!("Updating RSSI for {} to {}", address, rssi);
-                                let reading = Reading{rssi, timestamp: Utc::now(), mac_address: address.to_string()};
-                                let d = self.get_device(BTDeviceType::Rssi(address.to_string())).await?;
-                                let mut attr = Document::new(&reading)?;
-                                attr["rssi_type"] = "single_reading".into();
-                                let dev = DeviceUpdate {
-                                    device: Some(d),
-                                    value: rssi.into(),
-                                    attr,
-                                };
-                                self.mqtt.update_device(&dev).await?;
-                                let mut r = self.readings.write().await;
-                                r.insert(address.to_string(), reading);
-                            }
-                        }
-                        Event::Discovering {
-                            discovering,
-                            // address_type,
-                            ..
-                        } => {
-                            // if discovery ended, turn it back on
-                            trace!("Bluetooth discovery phase ended, restarting...");
-                            if !discovering {
-                                client
-                                    .start_discovery(
-                                        controller,
-                                        AddressTypeFlag::BREDR
-                                            | AddressTypeFlag::LEPublic
-                                            | AddressTypeFlag::LERandom,
-                                    )
-                                    .await?;
-                            }
-                        }
-                        e => {
-                            error!("{:?}", e);
+                    Event::DeviceFound {
+                        address,
+                        address_type,
+                        rssi,
+                        eir_data,
+                        ..
+                    } => {
+                        self.device_found(address, address_type, rssi, &eir_data)
+                            .await?
+                    }
+                    Event::Discovering { discovering, .. } => {
+                        // if discovery ended, turn it back on
+                        trace!("Bluetooth discovery phase ended, restarting...");
+                        if !discovering {
+                            client
+                                .start_discovery(
+                                    controller,
+                                    AddressTypeFlag::BREDR
+                                        | AddressTypeFlag::LEPublic
+                                        | AddressTypeFlag::LERandom,
+                                )
+                                .await?;
                         }
                     }
-
-                sleep(Duration::from_millis(50)).await;
+                    e => {
+                        // Back off a little in case the controller keeps reporting the same error
+                        error!("{:?}", e);
+                        sleep(Duration::from_millis(50)).await;
+                    }
+                }
             }
         }
         Ok(())
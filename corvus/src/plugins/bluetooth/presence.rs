@@ -0,0 +1,200 @@
+use crate::config::RssiFilter;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+/// Filtered signal strength of a device as heard by one agent
+#[derive(Clone, Debug)]
+pub struct Track {
+    /// Filtered RSSI in dBm
+    pub rssi:      f64,
+    /// Variance of the estimate, only used by the Kalman filter
+    variance:      f64,
+    /// Expected RSSI at 1m
+    pub reference: f64,
+    pub updated:   DateTime<Utc>,
+}
+
+impl Track {
+    pub fn new(filter: &RssiFilter, rssi: f64, reference: f64) -> Self {
+        Track {
+            variance: match filter {
+                RssiFilter::Kalman {
+                    measurement_noise, ..
+                } => *measurement_noise,
+                _ => 0.0,
+            },
+            updated: Utc::now(),
+            rssi,
+            reference,
+        }
+    }
+
+    pub fn update(&mut self, filter: &RssiFilter, rssi: f64, reference: f64) {
+        match filter {
+            RssiFilter::None => self.rssi = rssi,
+            RssiFilter::Exponential { alpha } => {
+                self.rssi = alpha * rssi + (1.0 - alpha) * self.rssi
+            }
+            RssiFilter::Kalman {
+                process_noise,
+                measurement_noise,
+            } => {
+                let predicted = self.variance + process_noise;
+                let gain = predicted / (predicted + measurement_noise);
+                self.rssi += gain * (rssi - self.rssi);
+                self.variance = (1.0 - gain) * predicted;
+            }
+        }
+        self.reference = reference;
+        self.updated = Utc::now();
+    }
+
+    /// Log-distance path loss estimate in meters
+    pub fn distance(&self, path_loss: f64) -> f64 {
+        let d = 10f64.powf((self.reference - self.rssi) / (10.0 * path_loss));
+        (d * 100.0).round() / 100.0
+    }
+
+    pub fn expired(&self, now: DateTime<Utc>, timeout: Duration) -> bool {
+        now.signed_duration_since(self.updated) > timeout
+    }
+}
+
+/// Filtered strength and distance in one room, as reported with the location
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Room {
+    pub rssi:     f64,
+    pub distance: f64,
+}
+
+/// Room a device is in, only changed once another room has been stronger by a margin for a while
+#[derive(Clone, Debug, Default)]
+pub struct Location {
+    current:    Option<String>,
+    challenger: Option<(String, DateTime<Utc>)>,
+}
+
+impl Location {
+    /// Pick the room given the filtered strength in every room that currently hears the device,
+    /// the last room is kept while none does
+    pub fn update(
+        &mut self,
+        rooms: &[(String, f64)],
+        margin: f64,
+        delay: Duration,
+        now: DateTime<Utc>,
+    ) -> Option<&str> {
+        let best = rooms
+            .iter()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        let current = self
+            .current
+            .as_ref()
+            .and_then(|c| rooms.iter().find(|(room, _)| room == c));
+        match (best, current) {
+            (None, _) => self.challenger = None,
+            // Nothing to hold on to, move right away
+            (Some((room, _)), None) => {
+                self.current = Some(room.to_string());
+                self.challenger = None;
+            }
+            (Some((room, rssi)), Some((_, current))) if *rssi >= current + margin => {
+                let since = match &self.challenger {
+                    Some((challenger, since)) if challenger == room => *since,
+                    _ => now,
+                };
+                if now.signed_duration_since(since) >= delay {
+                    self.current = Some(room.to_string());
+                    self.challenger = None;
+                } else {
+                    self.challenger = Some((room.to_string(), since));
+                }
+            }
+            _ => self.challenger = None,
+        }
+        self.current.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kalman() -> RssiFilter {
+        RssiFilter::Kalman {
+            process_noise:     0.5,
+            measurement_noise: 8.0,
+        }
+    }
+
+    #[test]
+    fn kalman_filter_dampens_outliers() {
+        let filter = kalman();
+        let mut track = Track::new(&filter, -60.0, -59.0);
+        for _ in 0..10 {
+            track.update(&filter, -60.0, -59.0);
+        }
+        track.update(&filter, -90.0, -59.0);
+        assert!(track.rssi > -70.0, "{}", track.rssi);
+    }
+
+    #[test]
+    fn exponential_filter_weights_new_readings() {
+        let filter = RssiFilter::Exponential { alpha: 0.25 };
+        let mut track = Track::new(&filter, -60.0, -59.0);
+        track.update(&filter, -80.0, -59.0);
+        assert_eq!(track.rssi, -65.0);
+    }
+
+    #[test]
+    fn estimates_distance() {
+        let track = Track::new(&RssiFilter::None, -79.0, -59.0);
+        assert_eq!(track.distance(2.0), 10.0);
+        assert_eq!(track.distance(4.0), 3.16);
+    }
+
+    #[test]
+    fn location_changes_after_winning_long_enough() {
+        let start = Utc::now();
+        let delay = Duration::seconds(10);
+        let mut location = Location::default();
+        let rooms = |kitchen: f64| vec![("kitchen".into(), kitchen), ("garage".into(), -60.0)];
+
+        assert_eq!(
+            location.update(&rooms(-70.0), 4.0, delay, start),
+            Some("garage")
+        );
+        // Stronger, but not by enough
+        assert_eq!(
+            location.update(&rooms(-58.0), 4.0, delay, start),
+            Some("garage")
+        );
+        // Enough, but not for long enough
+        assert_eq!(
+            location.update(&rooms(-50.0), 4.0, delay, start),
+            Some("garage")
+        );
+        let later = start + Duration::seconds(5);
+        assert_eq!(
+            location.update(&rooms(-50.0), 4.0, delay, later),
+            Some("garage")
+        );
+        let later = start + Duration::seconds(10);
+        assert_eq!(
+            location.update(&rooms(-50.0), 4.0, delay, later),
+            Some("kitchen")
+        );
+    }
+
+    #[test]
+    fn location_follows_the_device_out_of_range() {
+        let now = Utc::now();
+        let delay = Duration::seconds(10);
+        let mut location = Location::default();
+        let garage = vec![("garage".to_string(), -60.0)];
+        let kitchen = vec![("kitchen".to_string(), -80.0)];
+        assert_eq!(location.update(&garage, 4.0, delay, now), Some("garage"));
+        assert_eq!(location.update(&kitchen, 4.0, delay, now), Some("kitchen"));
+        assert_eq!(location.update(&[], 4.0, delay, now), Some("kitchen"));
+    }
+}
@@ -0,0 +1,370 @@
+use super::advertisement::{Advertisement, Uuid};
+use crate::prelude::SensorDeviceClass;
+use serde::Serialize;
+
+const BTHOME: Uuid = Uuid::from_u16(0xfcd2);
+/// Environmental sensing service, used by the ATC1441 and pvvx firmwares
+const ENVIRONMENTAL: Uuid = Uuid::from_u16(0x181a);
+const EDDYSTONE: Uuid = Uuid::from_u16(0xfeaa);
+const RUUVI: u16 = 0x0499;
+const GOVEE: u16 = 0xec88;
+/// Govee H5101, H5102 and H5177 advertise under the company identifier of Nokia
+const GOVEE_LEGACY: u16 = 0x0001;
+
+/// Readings broadcast by a passive sensor, whatever its format doesn't include is left out
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SensorData {
+    pub format:      &'static str,
+    /// Degrees Celsius
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// Percent relative humidity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity:    Option<f64>,
+    /// Hectopascal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pressure:    Option<f64>,
+    /// Percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery:     Option<f64>,
+    /// Volts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voltage:     Option<f64>,
+    /// Counter sensors increment for every new measurement, repeated advertisements keep it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packet:      Option<u32>,
+}
+
+/// A quantity measured by a passive sensor, each one becomes an entity of its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SensorKind {
+    Temperature,
+    Humidity,
+    Pressure,
+    Battery,
+    Voltage,
+}
+
+impl SensorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SensorKind::Temperature => "Temperature",
+            SensorKind::Humidity => "Humidity",
+            SensorKind::Pressure => "Pressure",
+            SensorKind::Battery => "Battery",
+            SensorKind::Voltage => "Battery Voltage",
+        }
+    }
+
+    pub fn device_class(self) -> SensorDeviceClass {
+        match self {
+            SensorKind::Temperature => SensorDeviceClass::Temperature,
+            SensorKind::Humidity => SensorDeviceClass::Humidity,
+            SensorKind::Pressure => SensorDeviceClass::Pressure,
+            SensorKind::Battery => SensorDeviceClass::Battery,
+            SensorKind::Voltage => SensorDeviceClass::Voltage,
+        }
+    }
+
+    pub fn unit(self) -> &'static str {
+        match self {
+            SensorKind::Temperature => "°C",
+            SensorKind::Humidity | SensorKind::Battery => "%",
+            SensorKind::Pressure => "hPa",
+            SensorKind::Voltage => "V",
+        }
+    }
+}
+
+impl SensorData {
+    pub fn parse(adv: &Advertisement) -> Option<Self> {
+        if let Some(d) = adv.service_data.get(&BTHOME) {
+            return bthome(d);
+        }
+        if let Some(d) = adv.service_data.get(&ENVIRONMENTAL) {
+            return atc(d);
+        }
+        if let Some(d) = adv.manufacturer_data.get(&RUUVI) {
+            return ruuvi(d);
+        }
+        if let Some(d) = adv.manufacturer_data.get(&GOVEE) {
+            return govee(d);
+        }
+        if let Some(d) = adv.manufacturer_data.get(&GOVEE_LEGACY) {
+            return govee_legacy(d);
+        }
+        adv.service_data
+            .get(&EDDYSTONE)
+            .and_then(|d| eddystone_tlm(d))
+    }
+
+    pub fn values(&self) -> Vec<(SensorKind, f64)> {
+        let values = [
+            (SensorKind::Temperature, self.temperature),
+            (SensorKind::Humidity, self.humidity),
+            (SensorKind::Pressure, self.pressure),
+            (SensorKind::Battery, self.battery),
+            (SensorKind::Voltage, self.voltage),
+        ];
+        values
+            .iter()
+            .filter_map(|(k, v)| v.map(|v| (*k, round(v))))
+            .collect()
+    }
+}
+
+/// Drop the noise fixed point conversions leave behind
+fn round(v: f64) -> f64 {
+    (v * 1000.0).round() / 1000.0
+}
+
+fn le(b: &[u8]) -> u32 {
+    b.iter().rev().fold(0, |acc, &b| acc << 8 | b as u32)
+}
+
+/// Size of the value of a BTHome v2 object, objects after an unknown one can't be found
+fn bthome_size(id: u8, rest: &[u8]) -> Option<usize> {
+    Some(match id {
+        0x00 | 0x01 | 0x09 | 0x0f..=0x11 | 0x15..=0x2f | 0x3a | 0x46 | 0x57..=0x59 | 0x60 => 1,
+        0x02 | 0x03 | 0x06..=0x08 | 0x0c..=0x0e | 0x12..=0x14 | 0x3c | 0x3d | 0x3f..=0x41 => 2,
+        0x43..=0x45 | 0x47..=0x4a | 0x51 | 0x52 | 0x56 | 0x5a | 0x5b | 0x5d..=0x5f | 0xf0 => 2,
+        0x04 | 0x05 | 0x0a | 0x0b | 0x42 | 0x4b | 0xf2 => 3,
+        0x3e | 0x4c..=0x50 | 0x55 | 0x5c | 0xf1 => 4,
+        // Text and raw values start with their length
+        0x53 | 0x54 => 1 + *rest.first()? as usize,
+        _ => return None,
+    })
+}
+
+fn bthome(d: &[u8]) -> Option<SensorData> {
+    let (&info, mut rest) = d.split_first()?;
+    // Encrypted payloads would need the bind key, only version 2 is supported
+    if info & 0x01 != 0 || info >> 5 != 2 {
+        return None;
+    }
+    let mut data = SensorData {
+        format: "bthome",
+        ..Default::default()
+    };
+    while let Some((&id, tail)) = rest.split_first() {
+        let size = match bthome_size(id, tail) {
+            Some(size) if size <= tail.len() => size,
+            _ => break,
+        };
+        let (v, tail) = tail.split_at(size);
+        rest = tail;
+        let signed = |v: &[u8]| i16::from_le_bytes([v[0], v[1]]) as f64;
+        match id {
+            0x00 => data.packet = Some(v[0] as u32),
+            0x01 => data.battery = Some(v[0] as f64),
+            0x02 => data.temperature = Some(signed(v) * 0.01),
+            0x45 => data.temperature = Some(signed(v) * 0.1),
+            0x03 => data.humidity = Some(le(v) as f64 * 0.01),
+            0x2e => data.humidity = Some(v[0] as f64),
+            0x04 => data.pressure = Some(le(v) as f64 * 0.01),
+            0x0c => data.voltage = Some(le(v) as f64 * 0.001),
+            0x4a => data.voltage = Some(le(v) as f64 * 0.1),
+            _ => (),
+        }
+    }
+    Some(data)
+}
+
+/// The ATC1441 format, big endian with the address first, and the little endian pvvx format
+fn atc(d: &[u8]) -> Option<SensorData> {
+    match d.len() {
+        13 => Some(SensorData {
+            format: "atc1441",
+            temperature: Some(i16::from_be_bytes([d[6], d[7]]) as f64 * 0.1),
+            humidity: Some(d[8] as f64),
+            battery: Some(d[9] as f64),
+            voltage: Some(u16::from_be_bytes([d[10], d[11]]) as f64 * 0.001),
+            packet: Some(d[12] as u32),
+            ..Default::default()
+        }),
+        15 => Some(SensorData {
+            format: "pvvx",
+            temperature: Some(i16::from_le_bytes([d[6], d[7]]) as f64 * 0.01),
+            humidity: Some(le(&d[8..10]) as f64 * 0.01),
+            voltage: Some(le(&d[10..12]) as f64 * 0.001),
+            battery: Some(d[12] as f64),
+            packet: Some(d[13] as u32),
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+/// RuuviTag data format 5 (RAWv2), fields at their maximum value are not available
+fn ruuvi(d: &[u8]) -> Option<SensorData> {
+    if d.len() < 18 || d[0] != 0x05 {
+        return None;
+    }
+    let be = |i: usize| u16::from_be_bytes([d[i], d[i + 1]]);
+    let temperature = be(1) as i16;
+    let power = be(13) >> 5;
+    let sequence = be(16);
+    Some(SensorData {
+        format: "ruuvi",
+        temperature: Some(temperature)
+            .filter(|&t| t != i16::MIN)
+            .map(|t| t as f64 * 0.005),
+        humidity: Some(be(3))
+            .filter(|&h| h != u16::MAX)
+            .map(|h| h as f64 * 0.0025),
+        pressure: Some(be(5))
+            .filter(|&p| p != u16::MAX)
+            .map(|p| (p as f64 + 50_000.0) / 100.0),
+        voltage: Some(power)
+            .filter(|&v| v != 2047)
+            .map(|v| (v as f64 + 1600.0) / 1000.0),
+        packet: Some(sequence).filter(|&s| s != u16::MAX).map(|s| s as u32),
+        ..Default::default()
+    })
+}
+
+/// Temperature and humidity packed into 24 bits as `temperature * 10000 + humidity * 10`, with
+/// the top bit as the sign of the temperature
+fn govee_packed(b: &[u8], battery: u8) -> SensorData {
+    let raw = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+    let value = raw & 0x7f_ffff;
+    let temperature = (value / 1000) as f64 / 10.0;
+    SensorData {
+        format: "govee",
+        temperature: Some(if raw & 0x80_0000 != 0 {
+            -temperature
+        } else {
+            temperature
+        }),
+        humidity: Some((value % 1000) as f64 / 10.0),
+        battery: Some(battery as f64),
+        ..Default::default()
+    }
+}
+
+/// H5072 and H5075 send the packed format, H5074 little endian values
+fn govee(d: &[u8]) -> Option<SensorData> {
+    match d.len() {
+        6 => Some(govee_packed(&d[1..4], d[4])),
+        7 => Some(SensorData {
+            format: "govee",
+            temperature: Some(i16::from_le_bytes([d[1], d[2]]) as f64 * 0.01),
+            humidity: Some(le(&d[3..5]) as f64 * 0.01),
+            battery: Some(d[5] as f64),
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+fn govee_legacy(d: &[u8]) -> Option<SensorData> {
+    if d.len() != 6 || d[0] != 0x01 || d[1] != 0x01 {
+        return None;
+    }
+    Some(govee_packed(&d[2..5], d[5]))
+}
+
+/// Unencrypted Eddystone TLM frames, the advertisement count serves as packet counter
+fn eddystone_tlm(d: &[u8]) -> Option<SensorData> {
+    if d.len() < 14 || d[0] != 0x20 || d[1] != 0 {
+        return None;
+    }
+    let millivolts = u16::from_be_bytes([d[2], d[3]]);
+    let temperature = i16::from_be_bytes([d[4], d[5]]);
+    Some(SensorData {
+        format: "eddystone_tlm",
+        voltage: Some(millivolts)
+            .filter(|&v| v != 0)
+            .map(|v| v as f64 / 1000.0),
+        // Signed 8.8 fixed point, 0x8000 if there is no sensor
+        temperature: Some(temperature)
+            .filter(|&t| t != i16::MIN)
+            .map(|t| t as f64 / 256.0),
+        packet: Some(u32::from_be_bytes([d[6], d[7], d[8], d[9]])),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn parse(data: &str) -> SensorData {
+        SensorData::parse(&Advertisement::parse(&hex(data))).unwrap()
+    }
+
+    #[test]
+    fn parses_bthome() {
+        let data = parse("0e16d2fc4000a4016402ca0903bf13");
+        assert_eq!(data.packet, Some(0xa4));
+        assert_eq!(
+            data.values(),
+            vec![
+                (SensorKind::Temperature, 25.06),
+                (SensorKind::Humidity, 50.55),
+                (SensorKind::Battery, 100.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_encrypted_bthome() {
+        let adv = Advertisement::parse(&hex("0716d2fc4100a401"));
+        assert_eq!(SensorData::parse(&adv), None);
+    }
+
+    #[test]
+    fn parses_atc1441() {
+        let data = parse("10161a18a4c138aabbcc00e6325a0b5410");
+        assert_eq!(data.format, "atc1441");
+        assert_eq!(data.temperature, Some(23.0));
+        assert_eq!(data.humidity, Some(50.0));
+        assert_eq!(data.battery, Some(90.0));
+        assert_eq!(data.voltage, Some(2.9));
+        assert_eq!(data.packet, Some(0x10));
+    }
+
+    #[test]
+    fn parses_ruuvi_rawv2() {
+        // Valid data test vector of the RuuviTag data format 5 specification
+        let data = parse("1bff99040512fc5394c37c0004fffc040cac364200cdcbb8334c884f");
+        assert_eq!(
+            data.values(),
+            vec![
+                (SensorKind::Temperature, 24.3),
+                (SensorKind::Humidity, 53.49),
+                (SensorKind::Pressure, 1000.44),
+                (SensorKind::Voltage, 2.977),
+            ]
+        );
+        assert_eq!(data.packet, Some(205));
+    }
+
+    #[test]
+    fn parses_govee() {
+        let data = parse("09ff88ec000374a16400");
+        assert_eq!(data.temperature, Some(22.6));
+        assert_eq!(data.humidity, Some(46.5));
+        assert_eq!(data.battery, Some(100.0));
+
+        let data = parse("09ff88ec00800fa06400");
+        assert_eq!(data.temperature, Some(-0.4));
+    }
+
+    #[test]
+    fn parses_eddystone_telemetry() {
+        let data = parse("1116aafe20000bb81580000000640000000a");
+        assert_eq!(
+            data.values(),
+            vec![(SensorKind::Temperature, 21.5), (SensorKind::Voltage, 3.0)]
+        );
+        assert_eq!(data.packet, Some(100));
+    }
+}
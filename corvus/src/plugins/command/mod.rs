@@ -14,21 +14,16 @@ struct CommandPayload {
 
 #[derive(Clone, Debug)]
 pub struct CommandPlugin {
-    mqtt:     MQTTService,
+    sinks:    Sinks,
     registry: DeviceRegistry,
     command:  String,
     args:     Vec<String>,
 }
 
 impl CommandPlugin {
-    pub fn new(
-        mqtt: MQTTService,
-        registry: DeviceRegistry,
-        command: String,
-        args: Vec<String>,
-    ) -> Self {
+    pub fn new(sinks: Sinks, registry: DeviceRegistry, command: String, args: Vec<String>) -> Self {
         Self {
-            mqtt,
+            sinks,
             registry,
             command,
             args,
@@ -58,7 +53,7 @@ impl Plugin for CommandPlugin {
     }
 
     async fn run(&self, name: String) -> Result<()> {
-        let output = Command::new(self.command.to_string())
+        let output = Command::new(&self.command)
             .args(&*self.args.clone())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -79,7 +74,7 @@ impl Plugin for CommandPlugin {
             .into(),
         };
 
-        self.mqtt.update_device(&update).await
+        self.sinks.update_device(&update).await
     }
 }
 
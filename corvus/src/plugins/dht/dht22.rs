@@ -40,20 +40,17 @@ pub struct DHT(Arc<Mutex<DHTInner>>);
 pub struct DHTInner {
     line:      Option<Line>,
     state:     DHTState,
+    #[allow(dead_code)]
     gpio_path: String,
+    #[allow(dead_code)]
     pin_num:   u32,
 }
 
-#[derive(Copy, Clone, Debug)]
-struct Pulse {
-    lo: u8,
-    hi: u8,
-}
-
 #[derive(Debug, Clone)]
 pub struct Reading {
     pub humidity:    f32,
     pub temperature: f32,
+    #[allow(dead_code)]
     pub fahrenheit:  f32,
 }
 
@@ -305,7 +302,7 @@ impl Reading {
             temperature *= -1.0f32;
         }
 
-        if !(0.0..=100.0).contains(&humidity) || temperature < 0.0 || temperature > 60.0 {
+        if !(0.0..=100.0).contains(&humidity) || !(0.0..=60.0).contains(&temperature) {
             // debug!("Values out of range, Next.. {}", i);
             return Err(Errors::Checksum.into());
         }
@@ -1,7 +1,7 @@
 use super::*;
+use crate::metrics;
 use dht22::*;
 use serde::{Deserialize, Serialize};
-use tokio::runtime::Handle;
 use unstructured::Document;
 
 mod dht22;
@@ -15,7 +15,7 @@ struct DHTPayload {
 
 #[derive(Debug, Clone)]
 pub struct DHTPlugin {
-    mqtt:               MQTTService,
+    sinks:              Sinks,
     registry:           DeviceRegistry,
     dht:                DHT,
     temperature_device: String,
@@ -25,18 +25,19 @@ pub struct DHTPlugin {
 impl DHTPlugin {
     pub fn new(
         name: String,
-        mqtt: MQTTService,
+        sinks: Sinks,
         registry: DeviceRegistry,
         device: String,
         channel: u32,
-    ) -> Self {
-        DHTPlugin {
-            dht: DHT::new(&device, channel).unwrap(),
+    ) -> Result<Self> {
+        Ok(DHTPlugin {
+            dht: DHT::new(&device, channel)
+                .with_context(|| format!("Could not open line {} on {}", channel, device))?,
             temperature_device: format!("{} Temperature", name),
             humidity_device: format!("{} Humidity", name),
-            mqtt,
+            sinks,
             registry,
-        }
+        })
     }
 }
 
@@ -74,19 +75,30 @@ impl Plugin for DHTPlugin {
         Ok(())
     }
 
-    async fn run(&self, _: String) -> Result<()> {
+    async fn run(&self, name: String) -> Result<()> {
         let mut zelf = self.clone();
-        match Handle::current()
-            .spawn_blocking(move || {
-                let mut last_result = zelf.dht.get_reading();
-                let mut i = 0;
-                while last_result.is_err() && i < 10 {
-                    last_result = zelf.dht.get_reading();
-                    i += 1;
+        match logging::spawn_blocking(move || {
+            let labels = [("plugin", name.as_str())];
+            let mut read = || {
+                metrics::DHT_READ_ATTEMPTS.inc(&labels);
+                let result = zelf.dht.get_reading();
+                match result.as_ref().map_err(|e| e.downcast_ref::<Errors>()) {
+                    Err(Some(Errors::Checksum)) => metrics::DHT_CHECKSUM_FAILURES.inc(&labels),
+                    Err(Some(Errors::Timeout(_))) => metrics::DHT_TIMEOUTS.inc(&labels),
+                    _ => (),
                 }
-                last_result
-            })
-            .await?
+                result
+            };
+            let mut last_result = read();
+            let mut i = 0;
+            while last_result.is_err() && i < 10 {
+                last_result = read();
+                i += 1;
+            }
+            metrics::DHT_RETRIES.observe(&labels, i as f64);
+            last_result
+        })
+        .await?
         {
             Ok(r) => {
                 let d = self.registry.get_by_name(&self.humidity_device).await;
@@ -95,14 +107,14 @@ impl Plugin for DHTPlugin {
                     value:  r.humidity.into(),
                     attr:   Default::default(),
                 };
-                self.mqtt.update_device(&update).await?;
+                self.sinks.update_device(&update).await?;
                 let d = self.registry.get_by_name(&self.temperature_device).await;
                 let update = DeviceUpdate {
                     device: d,
                     value:  r.temperature.into(),
                     attr:   Default::default(),
                 };
-                self.mqtt.update_device(&update).await?;
+                self.sinks.update_device(&update).await?;
             }
             Err(e) => {
                 warn!("Read device failed due to {:?}", e);
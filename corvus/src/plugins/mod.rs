@@ -1,18 +1,40 @@
-use crate::{config::PluginOptions, prelude::*, triggers::Triggers};
+use crate::{
+    config::{PluginDefinition, PluginOptions},
+    logging,
+    prelude::*,
+    sinks::Sinks,
+    triggers::Triggers,
+};
 use async_trait::async_trait;
 use bluetooth::BluetoothPlugin;
 use command::CommandPlugin;
 use dht::DHTPlugin;
-use std::collections::HashMap;
+use process::ProcessPlugin;
+use script::ScriptPlugin;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use wasm::WasmPlugin;
 
 mod bluetooth;
 mod command;
 mod dht;
+mod process;
+mod registry;
+mod script;
+mod wasm;
+
+pub use registry::{check_registered, register_plugin, PluginContext, PluginType};
 
 #[derive(Debug, Clone, Deref, Default)]
 pub struct PluginManager(Arc<Mutex<HashMap<String, Plugins>>>);
 
 impl PluginManager {
+    pub async fn list_plugins(&self) -> Vec<Plugins> {
+        self.lock().await.values().cloned().collect()
+    }
+
     pub async fn process_update(&self, plugin: &str, data: Document) -> Result<()> {
         let p = self.lock().await.get(plugin).cloned();
         if let Some(plugin) = p {
@@ -21,68 +43,196 @@ impl PluginManager {
         Ok(())
     }
 
+    /// Pass a value received for a settable device to the plugin owning it
+    pub async fn set_property(&self, device: Device, value: String) -> Result<()> {
+        let p = self.lock().await.get(device.plugin()).cloned();
+        match p {
+            Some(plugin) => plugin.set_property(device, value).await,
+            None => bail!("Plugin '{}' is not running", device.plugin()),
+        }
+    }
+
     pub async fn init_plugins(&self, config: &Configuration, app: &App) -> Result<()> {
+        unique_names(config)?;
         let mut svcs = self.lock().await;
         for svc in config.plugins.iter() {
-            let s = Plugins::new(svc.clone(), app.clone());
-            s.start()?;
+            let s = Plugins::new(svc.clone(), app.clone())?;
+            s.start(app);
+            svcs.insert(s.name().into(), s);
+        }
+        Ok(())
+    }
+
+    /// Run every configured plugin a single time, concurrently, without starting triggers.
+    /// Plugins that keep running, such as bluetooth scanning, are stopped after `window` and
+    /// reported with whatever they published until then.
+    pub async fn run_once(
+        &self,
+        config: &Configuration,
+        app: &App,
+        window: Duration,
+    ) -> Result<()> {
+        unique_names(config)?;
+        let mut plugins = vec![];
+        for p in config.plugins.iter() {
+            plugins.push(
+                Plugins::new(p.clone(), app.clone())
+                    .with_context(|| format!("Failed to load plugin '{}'", p.name))?,
+            );
+        }
+        // Updates from other agents and property changes are routed while running
+        let mut svcs = self.lock().await;
+        for p in plugins.iter() {
+            svcs.insert(p.name().into(), p.clone());
+        }
+        drop(svcs);
+
+        let results = futures::future::join_all(plugins.iter().map(|p| async move {
+            p.heartbeat().await?;
+            let partial = match tokio::time::timeout(window, p.run()).await {
+                Ok(result) => result.map(|_| false)?,
+                Err(_) => true,
+            };
+            if app.mqtt.is_leader().await {
+                p.leader_heartbeat(app.cluster_data.clone()).await?;
+            }
+            Ok::<_, Error>(partial)
+        }))
+        .await;
+
+        let mut failed = 0;
+        for (p, result) in plugins.iter().zip(results) {
+            match result {
+                Ok(false) => info!("Plugin '{}' completed", p.name()),
+                Ok(true) => warn!(
+                    "Plugin '{}' was still running after {}s, stopped it with partial results",
+                    p.name(),
+                    window.as_secs()
+                ),
+                Err(e) => {
+                    error!("Plugin '{}' failed: {:?}", p.name(), e);
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            bail!("{} of {} plugins failed", failed, plugins.len());
+        }
+        Ok(())
+    }
+
+    /// Apply the plugin list of a freshly loaded configuration. Plugins are matched by name,
+    /// unchanged plugins keep running and every new plugin is constructed before anything
+    /// running is touched so a bad definition leaves the current state alone.
+    pub async fn reload(&self, config: &Configuration, app: &App) -> Result<()> {
+        let names = unique_names(config)?;
+
+        let mut svcs = self.lock().await;
+        let mut started = vec![];
+        for p in config.plugins.iter() {
+            match svcs.get(&p.name) {
+                Some(running) if running.config() == p => (),
+                _ => started.push(
+                    Plugins::new(p.clone(), app.clone())
+                        .with_context(|| format!("Failed to load plugin '{}'", p.name))?,
+                ),
+            }
+        }
+
+        let removed: Vec<String> = svcs
+            .keys()
+            .filter(|k| !names.contains(k.as_str()))
+            .cloned()
+            .collect();
+        // Nothing below can fail, so a reload is applied either completely or not at all
+        for name in removed.iter() {
+            if let Some(p) = svcs.remove(name) {
+                info!("Stopping removed plugin '{}'", name);
+                p.stop();
+            }
+            unregister(app, name).await;
+        }
+
+        for s in started.into_iter() {
+            if let Some(p) = svcs.remove(s.name()) {
+                info!("Restarting changed plugin '{}'", s.name());
+                p.stop();
+                unregister(app, s.name()).await;
+            } else {
+                info!("Starting new plugin '{}'", s.name());
+            }
+            s.start(app);
             svcs.insert(s.name().into(), s);
         }
         Ok(())
     }
 }
 
+/// Names of the configured plugins, which identify them so they must be unique
+fn unique_names(config: &Configuration) -> Result<HashSet<&str>> {
+    let mut names = HashSet::new();
+    for p in config.plugins.iter() {
+        if !names.insert(p.name.as_str()) {
+            bail!("Duplicate plugin name '{}'", p.name);
+        }
+    }
+    Ok(names)
+}
+
+/// Drop the devices of a stopped plugin, a restarted plugin registers the ones it still has
+async fn unregister(app: &App, name: &str) {
+    if let Err(e) = app.device_registry.unregister_plugin(name).await {
+        warn!("Failed to unregister devices of plugin '{}': {:#}", name, e);
+    }
+}
+
 #[derive(Debug, Clone, Deref)]
 pub struct Plugins(Arc<PluginData>);
 
+#[derive(Debug)]
+pub struct PluginData {
+    name:    String,
+    config:  Arc<PluginConfiguration>,
+    trigger: Triggers,
+    tasks:   parking_lot::Mutex<Vec<ServiceHandle>>,
+    service: PluginService,
+}
+
 macro_rules! plugins {
     ($($name:ident $plugin:ty,)*) => {
         #[derive(Debug)]
-        pub enum PluginData {
-            $($name {
-                name: String,
-                trigger: Triggers,
-                service: $plugin,
-            },)*
+        pub enum PluginService {
+            $($name($plugin),)*
         }
 
-        impl Plugins {
-            pub async fn heartbeat(&self) -> Result<()> {
-                match &***self {
-                    $(PluginData::$name { service, name, .. } => service.heartbeat(name.to_string()).await,)*
-                }
-            }
-
-            pub async fn leader_heartbeat(&self, data: ClusterNodes) -> Result<()> {
-                match &***self {
-                    $(PluginData::$name { service, name, .. } => {
-                        service.leader_heartbeat(name.to_string(), data).await
-                    })*
+        impl PluginService {
+            async fn heartbeat(&self, name: String) -> Result<()> {
+                match self {
+                    $(PluginService::$name(service) => service.heartbeat(name).await,)*
                 }
             }
 
-            pub async fn run(&self) -> Result<()> {
-                match &***self {
-                    $(PluginData::$name { service, name, .. } => service.run(name.to_string()).await,)*
+            async fn leader_heartbeat(&self, name: String, data: ClusterNodes) -> Result<()> {
+                match self {
+                    $(PluginService::$name(service) => service.leader_heartbeat(name, data).await,)*
                 }
             }
 
-            pub fn start(&self) -> Result<()> {
-                let svc = self.clone();
-                match &***self {
-                    $(PluginData::$name { trigger, .. } => trigger.init(svc),)*
+            async fn run(&self, name: String) -> Result<()> {
+                match self {
+                    $(PluginService::$name(service) => service.run(name).await,)*
                 }
             }
 
-            pub async fn process_update(&self, data: Document) -> Result<()> {
-                match &***self {
-                    $(PluginData::$name { service, .. } => service.process_update(data).await,)*
+            async fn process_update(&self, data: Document) -> Result<()> {
+                match self {
+                    $(PluginService::$name(service) => service.process_update(data).await,)*
                 }
             }
 
-            pub fn name(&self) -> &str {
-                match &***self {
-                    $(PluginData::$name { name, .. } => name,)*
+            async fn set_property(&self, device: Device, value: String) -> Result<()> {
+                match self {
+                    $(PluginService::$name(service) => service.set_property(device, value).await,)*
                 }
             }
         }
@@ -93,54 +243,201 @@ plugins! {
     Command CommandPlugin,
     Bluetooth BluetoothPlugin,
     DHT DHTPlugin,
+    Process ProcessPlugin,
+    Script ScriptPlugin,
+    Wasm WasmPlugin,
+    Registered Box<dyn Plugin>,
 }
 
+/// A source of devices. Every method receives the name of the plugin in the configuration.
 #[async_trait]
-pub trait Plugin {
+pub trait Plugin: std::fmt::Debug + Send + Sync {
+    /// Read the devices, called by the trigger of the plugin
     async fn run(&self, name: String) -> Result<()>;
+    /// Register the devices of this plugin, called every 10 seconds
     async fn heartbeat(&self, name: String) -> Result<()>;
+    /// Aggregate the readings of the whole cluster, only called on the leader
     async fn leader_heartbeat(&self, name: String, data: ClusterNodes) -> Result<()>;
+    /// Handle an update published by the plugin of the same name on another agent
     async fn process_update(&self, _: Document) -> Result<()> {
         Ok(())
     }
+    /// Change the state of a settable device, the new state is expected as a device update
+    async fn set_property(&self, device: Device, _: String) -> Result<()> {
+        bail!("'{}' can't be changed", device.display_name())
+    }
 }
 
 impl Plugins {
-    pub fn new(config: Arc<PluginConfiguration>, app: App) -> Self {
+    pub fn new(config: Arc<PluginConfiguration>, app: App) -> Result<Self> {
         let name = config.name.to_string();
         let trigger = Triggers::new(config.trigger.clone());
-        match &*config.plugin {
-            PluginOptions::Command { command, args } => Plugins(Arc::new(PluginData::Command {
-                name,
-                trigger,
-                service: CommandPlugin::new(
-                    app.mqtt.clone(),
+        let service = match &*config.plugin {
+            PluginDefinition::Builtin(PluginOptions::Command { command, args }) => {
+                PluginService::Command(CommandPlugin::new(
+                    app.sinks.clone(),
                     app.device_registry.clone(),
                     command.to_string(),
                     args.clone(),
-                ),
-            })),
-            PluginOptions::Bluetooth { .. } => Plugins(Arc::new(PluginData::Bluetooth {
-                name: name.to_string(),
-                trigger,
-                service: BluetoothPlugin::new(
-                    name,
+                ))
+            }
+            PluginDefinition::Builtin(PluginOptions::Bluetooth(options)) => {
+                PluginService::Bluetooth(BluetoothPlugin::new(
+                    name.to_string(),
                     app.config.node.location.to_string(),
                     app.device_registry.clone(),
-                    app.mqtt.clone(),
-                ),
-            })),
-            PluginOptions::DHT { device, channel } => Plugins(Arc::new(PluginData::DHT {
-                name: name.to_string(),
-                trigger,
-                service: DHTPlugin::new(
-                    name,
-                    app.mqtt.clone(),
+                    app.sinks.clone(),
+                    options.clone(),
+                )?)
+            }
+            PluginDefinition::Builtin(PluginOptions::DHT { device, channel }) => {
+                PluginService::DHT(DHTPlugin::new(
+                    name.to_string(),
+                    app.sinks.clone(),
                     app.device_registry.clone(),
                     device.into(),
                     *channel,
-                ),
-            })),
+                )?)
+            }
+            PluginDefinition::Builtin(PluginOptions::Process {
+                command,
+                args,
+                max_restarts,
+            }) => PluginService::Process(ProcessPlugin::new(
+                name.to_string(),
+                app.device_registry.clone(),
+                app.sinks.clone(),
+                command.to_string(),
+                args.clone(),
+                *max_restarts,
+            )),
+            PluginDefinition::Builtin(PluginOptions::Script {
+                script,
+                path,
+                max_operations,
+                command_timeout,
+            }) => PluginService::Script(ScriptPlugin::new(
+                app.device_registry.clone(),
+                app.sinks.clone(),
+                app.cluster_data.clone(),
+                &PluginOptions::script_source(script, path)?,
+                *max_operations,
+                Duration::from_secs(*command_timeout),
+            )?),
+            PluginDefinition::Builtin(PluginOptions::Wasm {
+                path,
+                fuel,
+                max_memory,
+            }) => PluginService::Wasm(WasmPlugin::new(
+                name.to_string(),
+                app.device_registry.clone(),
+                app.sinks.clone(),
+                path,
+                *fuel,
+                *max_memory,
+            )?),
+            PluginDefinition::Registered(value) => {
+                let context = PluginContext {
+                    name:            name.to_string(),
+                    location:        app.config.node.location.to_string(),
+                    device_registry: app.device_registry.clone(),
+                    sinks:           app.sinks.clone(),
+                    mqtt:            app.mqtt.clone(),
+                };
+                PluginService::Registered(registry::create_registered(
+                    config.plugin.plugin_type(),
+                    context,
+                    value,
+                )?)
+            }
+        };
+        Ok(Plugins(Arc::new(PluginData {
+            tasks: Default::default(),
+            name,
+            config,
+            trigger,
+            service,
+        })))
+    }
+
+    pub async fn heartbeat(&self) -> Result<()> {
+        let f = self.service.heartbeat(self.name.to_string());
+        logging::with_plugin(self.name.to_string(), f).await
+    }
+
+    pub async fn leader_heartbeat(&self, data: ClusterNodes) -> Result<()> {
+        let f = self.service.leader_heartbeat(self.name.to_string(), data);
+        logging::with_plugin(self.name.to_string(), f).await
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let f = self.service.run(self.name.to_string());
+        logging::with_plugin(self.name.to_string(), f).await
+    }
+
+    pub async fn process_update(&self, data: Document) -> Result<()> {
+        let f = self.service.process_update(data);
+        logging::with_plugin(self.name.to_string(), f).await
+    }
+
+    pub async fn set_property(&self, device: Device, value: String) -> Result<()> {
+        let f = self.service.set_property(device, value);
+        logging::with_plugin(self.name.to_string(), f).await
+    }
+
+    /// Start the trigger and heartbeat services for this plugin, their handles are kept so
+    /// the plugin can be stopped again on reload.
+    pub fn start(&self, app: &App) {
+        if let Some(source) = &self.config.source {
+            info!(
+                "Loading plugin '{}' from {}",
+                self.name(),
+                source.to_string_lossy()
+            );
         }
+        let trigger = self.trigger.init(self.clone());
+
+        let svc = self.clone();
+        let app = app.clone();
+        let heartbeat = start_service(
+            Duration::from_secs(10),
+            format!("{} Plugin Heartbeat", self.name()),
+            false,
+            false,
+            move || {
+                let svc = svc.clone();
+                let app = app.clone();
+                async move {
+                    svc.heartbeat().await?;
+                    if app.mqtt.is_leader().await {
+                        svc.leader_heartbeat(app.cluster_data.clone()).await?;
+                    }
+                    Ok(())
+                }
+            },
+        );
+
+        let mut tasks = self.tasks.lock();
+        tasks.push(trigger);
+        tasks.push(heartbeat);
+    }
+
+    /// Run history of the trigger driving this plugin
+    pub fn status(&self) -> Option<ServiceStatus> {
+        self.tasks.lock().first().and_then(|t| t.status())
+    }
+
+    pub fn stop(&self) {
+        for task in self.tasks.lock().drain(..) {
+            task.abort();
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &Arc<PluginConfiguration> {
+        &self.config
     }
 }
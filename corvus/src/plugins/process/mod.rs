@@ -0,0 +1,347 @@
+//! Long running helper programs acting as plugins. The protocol is line delimited JSON, each
+//! message an object with a `type`.
+//!
+//! The helper writes to its stdout:
+//!
+//! - `{"type": "device", "name": "Outside", "device_type": "sensor", "device_class":
+//!   "temperature", "unit": "°C"}` declares a device. `device_type` defaults to `sensor`,
+//!   `device_class` and `unit` are optional.
+//! - `{"type": "state", "name": "Outside", "value": 21.5, "attributes": {...}}` updates a
+//!   declared device, `attributes` is optional.
+//! - `{"type": "log", "level": "warn", "message": "..."}` logs a message. Lines on stderr are
+//!   logged as warnings.
+//!
+//! Corvus writes to its stdin:
+//!
+//! - `{"type": "trigger"}` whenever the trigger of the plugin fires.
+//! - `{"type": "command", "name": "Fan", "value": "ON"}` for a new state received for a
+//!   settable device.
+//! - `{"type": "update", "data": {...}}` for updates published by the plugin of the same name
+//!   on other agents.
+//!
+//! An exit with status 0 is final. Otherwise the helper is restarted with a growing delay,
+//! and given up on after `max_restarts` failures in a row. Its devices are unregistered once
+//! it is not restarted anymore.
+
+use super::*;
+use serde::{Deserialize, Serialize};
+use std::{process::Stdio, time::Instant};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{ChildStdin, Command},
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    task::JoinHandle,
+};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A helper running at least this long resets the delay and failure count
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Messages waiting to be written to the helper, later ones are dropped while it is full
+const INPUT_QUEUE: usize = 100;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, tag = "type", rename_all = "snake_case")]
+enum ProcessMessage {
+    Device {
+        name:         String,
+        #[serde(default = "default_device_type")]
+        device_type:  String,
+        #[serde(default)]
+        device_class: Option<String>,
+        #[serde(default)]
+        unit:         Option<String>,
+    },
+    State {
+        name:       String,
+        value:      Document,
+        #[serde(default)]
+        attributes: Document,
+    },
+    Log {
+        level:   log::Level,
+        message: String,
+    },
+}
+
+fn default_device_type() -> String {
+    "sensor".into()
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum HostMessage {
+    Trigger,
+    Command { name: String, value: String },
+    Update { data: Document },
+}
+
+/// Shared by the plugin and its supervisor task
+#[derive(Debug, Clone)]
+struct Helper {
+    name:         String,
+    command:      String,
+    args:         Vec<String>,
+    max_restarts: u32,
+    registry:     DeviceRegistry,
+    sinks:        Sinks,
+}
+
+#[derive(Debug)]
+pub struct ProcessPlugin {
+    helper:     Helper,
+    sender:     Sender<HostMessage>,
+    receiver:   parking_lot::Mutex<Option<Receiver<HostMessage>>>,
+    supervisor: parking_lot::Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ProcessPlugin {
+    pub fn new(
+        name: String,
+        registry: DeviceRegistry,
+        sinks: Sinks,
+        command: String,
+        args: Vec<String>,
+        max_restarts: u32,
+    ) -> Self {
+        let (sender, receiver) = channel(INPUT_QUEUE);
+        ProcessPlugin {
+            helper: Helper {
+                name,
+                command,
+                args,
+                max_restarts,
+                registry,
+                sinks,
+            },
+            sender,
+            receiver: parking_lot::Mutex::new(Some(receiver)),
+            supervisor: Default::default(),
+        }
+    }
+
+    /// Start the helper the first time the plugin is used
+    fn ensure_started(&self) {
+        let mut supervisor = self.supervisor.lock();
+        if supervisor.is_some() {
+            return;
+        }
+        if let Some(receiver) = self.receiver.lock().take() {
+            let helper = self.helper.clone();
+            let name = helper.name.to_string();
+            *supervisor = Some(tokio::spawn(logging::with_plugin(
+                name,
+                helper.supervise(receiver),
+            )));
+        }
+    }
+
+    /// Queue a message for the helper, dropping it when the helper isn't reading its input
+    fn send(&self, message: HostMessage) -> Result<()> {
+        self.ensure_started();
+        match self.sender.try_send(message) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(message)) => {
+                warn!(
+                    "Helper '{}' is not reading its input, dropping {:?}",
+                    self.helper.command, message
+                );
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => {
+                bail!("Helper '{}' is not running", self.helper.command)
+            }
+        }
+    }
+}
+
+impl Drop for ProcessPlugin {
+    fn drop(&mut self) {
+        // The helper is killed along with the supervisor holding it
+        if let Some(supervisor) = self.supervisor.lock().take() {
+            supervisor.abort();
+        }
+    }
+}
+
+impl Helper {
+    async fn supervise(self, receiver: Receiver<HostMessage>) {
+        // Shared with the input writer of every run of the helper
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut backoff = MIN_BACKOFF;
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            let result = self.run(&receiver).await;
+            if started.elapsed() > STABLE_AFTER {
+                backoff = MIN_BACKOFF;
+                failures = 0;
+            }
+            match result {
+                Ok(status) if status.success() => {
+                    info!("Helper '{}' finished", self.command);
+                    break;
+                }
+                Ok(status) => warn!("Helper '{}' exited with {}", self.command, status),
+                Err(e) => error!("Helper '{}' failed: {:?}", self.command, e),
+            }
+            failures += 1;
+            if failures > self.max_restarts {
+                error!(
+                    "Helper '{}' failed {} times in a row, giving up",
+                    self.command, failures
+                );
+                break;
+            }
+            info!("Restarting helper '{}' in {:?}", self.command, backoff);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+        if let Err(e) = self.registry.unregister_plugin(&self.name).await {
+            warn!(
+                "Failed to unregister the devices of '{}': {:?}",
+                self.name, e
+            );
+        }
+    }
+
+    /// Run the helper once, until it exits
+    async fn run(
+        &self,
+        receiver: &Arc<Mutex<Receiver<HostMessage>>>,
+    ) -> Result<std::process::ExitStatus> {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to start '{}'", self.command))?;
+        info!("Started helper '{}'", self.command);
+        let stdin = child.stdin.take().context("No stdin")?;
+        // Written on a task of its own, a helper not reading its input still gets its output read
+        let writer = tokio::spawn(logging::with_plugin(
+            self.name.to_string(),
+            self.clone().write_input(stdin, receiver.clone()),
+        ));
+        let result = self.read_output(&mut child).await;
+        writer.abort();
+        result
+    }
+
+    async fn read_output(
+        &self,
+        child: &mut tokio::process::Child,
+    ) -> Result<std::process::ExitStatus> {
+        let mut stdout = BufReader::new(child.stdout.take().context("No stdout")?).lines();
+        let mut stderr = BufReader::new(child.stderr.take().context("No stderr")?).lines();
+        let mut stderr_open = true;
+        loop {
+            tokio::select! {
+                line = stdout.next_line() => match line? {
+                    Some(line) => {
+                        if let Err(e) = self.handle(&line).await {
+                            warn!("Invalid message from '{}': {:#}", self.command, e);
+                        }
+                    }
+                    // Output closed, only the exit status is left
+                    None => return Ok(child.wait().await?),
+                },
+                line = stderr.next_line(), if stderr_open => match line? {
+                    Some(line) => warn!("{}: {}", self.command, line),
+                    None => stderr_open = false,
+                },
+            }
+        }
+    }
+
+    async fn write_input(self, mut stdin: ChildStdin, receiver: Arc<Mutex<Receiver<HostMessage>>>) {
+        let mut receiver = receiver.lock().await;
+        while let Some(message) = receiver.recv().await {
+            let mut line = match serde_json::to_string(&message) {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Could not encode {:?}: {}", message, e);
+                    continue;
+                }
+            };
+            line.push('\n');
+            // A helper that closed its input still gets to finish its output
+            if let Err(e) = stdin.write_all(line.as_bytes()).await {
+                debug!("Could not write to '{}': {}", self.command, e);
+            }
+        }
+    }
+
+    async fn handle(&self, line: &str) -> Result<()> {
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        match serde_json::from_str(line)? {
+            ProcessMessage::Device {
+                name,
+                device_type: typ,
+                device_class,
+                unit,
+            } => {
+                let typ = DeviceType::parse(&typ, device_class.as_deref())?;
+                let mut device = self.registry.new_device(name, typ, self.name.to_string());
+                if let Some(unit) = unit {
+                    device = device.with_unit_of_measurement(unit);
+                }
+                self.registry.register(device.build()).await?;
+            }
+            ProcessMessage::State {
+                name,
+                value,
+                attributes,
+            } => {
+                let device = self.registry.get_by_name(&name).await;
+                if !matches!(&device, Some(d) if d.plugin() == self.name) {
+                    bail!("'{}' was not declared", name);
+                }
+                self.sinks
+                    .update_device(&DeviceUpdate {
+                        device,
+                        value,
+                        attr: attributes,
+                    })
+                    .await?;
+            }
+            ProcessMessage::Log { level, message } => {
+                log!(level, "{}: {}", self.command, message)
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Plugin for ProcessPlugin {
+    async fn run(&self, _: String) -> Result<()> {
+        self.send(HostMessage::Trigger)
+    }
+
+    async fn heartbeat(&self, _: String) -> Result<()> {
+        self.ensure_started();
+        Ok(())
+    }
+
+    async fn leader_heartbeat(&self, _: String, _: ClusterNodes) -> Result<()> {
+        Ok(())
+    }
+
+    async fn process_update(&self, data: Document) -> Result<()> {
+        self.send(HostMessage::Update { data })
+    }
+
+    async fn set_property(&self, device: Device, value: String) -> Result<()> {
+        self.send(HostMessage::Command {
+            name: device.display_name().to_string(),
+            value,
+        })
+    }
+}
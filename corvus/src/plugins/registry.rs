@@ -0,0 +1,109 @@
+use super::*;
+use serde::de::DeserializeOwned;
+use std::any::TypeId;
+
+/// What a registered plugin is constructed with
+#[derive(Debug, Clone)]
+pub struct PluginContext {
+    /// Name of the plugin in the configuration
+    pub name:            String,
+    /// Location of this agent
+    pub location:        String,
+    pub device_registry: DeviceRegistry,
+    /// Destinations for device updates, MQTT included
+    pub sinks:           Sinks,
+    pub mqtt:            MQTTService,
+}
+
+/// A plugin type compiled into a program embedding corvus, made available to plugin
+/// definitions with [`CorvusBuilder::register_plugin`]
+pub trait PluginType: Plugin + Sized + 'static {
+    /// Read from the `definition` table of the plugin, without its `type`
+    type Options: DeserializeOwned;
+
+    fn new(context: PluginContext, options: Self::Options) -> Result<Self>;
+}
+
+#[derive(Debug)]
+struct RegisteredType {
+    id:     TypeId,
+    check:  fn(&toml::Value) -> Result<()>,
+    create: fn(PluginContext, &toml::Value) -> Result<Box<dyn Plugin>>,
+}
+
+lazy_static! {
+    static ref PLUGIN_TYPES: parking_lot::RwLock<HashMap<String, RegisteredType>> =
+        Default::default();
+}
+
+fn options<P: PluginType>(value: &toml::Value) -> Result<P::Options> {
+    let mut value = value.clone();
+    if let Some(table) = value.as_table_mut() {
+        table.remove("type");
+    }
+    Ok(value.try_into()?)
+}
+
+fn check<P: PluginType>(value: &toml::Value) -> Result<()> {
+    options::<P>(value).map(|_| ())
+}
+
+fn create<P: PluginType>(context: PluginContext, value: &toml::Value) -> Result<Box<dyn Plugin>> {
+    let options = options::<P>(value)?;
+    Ok(Box::new(P::new(context, options)?))
+}
+
+/// Make `P` available as `type = "<name>"`. Registering the same type again is allowed so
+/// several apps can be built in one process.
+pub fn register_plugin<P: PluginType>(name: &str) -> Result<()> {
+    if PluginOptions::TYPES.contains(&name) {
+        bail!("'{}' is the type of a built in plugin", name);
+    }
+    let mut types = PLUGIN_TYPES.write();
+    match types.get(name) {
+        Some(t) if t.id != TypeId::of::<P>() => {
+            bail!("Plugin type '{}' is already registered", name)
+        }
+        Some(_) => Ok(()),
+        None => {
+            types.insert(
+                name.to_string(),
+                RegisteredType {
+                    id:     TypeId::of::<P>(),
+                    check:  check::<P>,
+                    create: create::<P>,
+                },
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Check a definition against the options of its registered type
+pub fn check_registered(name: &str, value: &toml::Value) -> Result<()> {
+    let types = PLUGIN_TYPES.read();
+    match types.get(name) {
+        Some(t) => (t.check)(value),
+        None => {
+            let mut known = PluginOptions::TYPES.to_vec();
+            known.extend(types.keys().map(String::as_str));
+            bail!(
+                "unknown plugin type `{}`, expected one of `{}`",
+                name,
+                known.join("`, `")
+            )
+        }
+    }
+}
+
+pub(super) fn create_registered(
+    name: &str,
+    context: PluginContext,
+    value: &toml::Value,
+) -> Result<Box<dyn Plugin>> {
+    let create = match PLUGIN_TYPES.read().get(name) {
+        Some(t) => t.create,
+        None => bail!("Plugin type '{}' is not registered", name),
+    };
+    create(context, value)
+}
@@ -0,0 +1,331 @@
+//! Small Rhai scripts for glue that doesn't deserve a program of its own. Every run of the
+//! trigger evaluates the script on the blocking pool with these functions available:
+//!
+//! - `device(name)` and `device(name, #{ type: "sensor", class: "temperature", unit: "°C" })`
+//!   declare a device, a sensor without a class by default.
+//! - `publish(name, value)` and `publish(name, value, attributes)` update a declared device.
+//! - `value(node, entity)` and `attributes(node, entity)` return the latest state and
+//!   attributes of any entity seen on the cluster, or `()`. `entity` is the unique ID, like
+//!   `kitchen_climate_temperature`.
+//! - `read_file(path)` returns the contents of a file of up to 1 MiB.
+//! - `command(program, args)` runs a program and returns `#{ status, stdout, stderr }`. It is
+//!   killed after `command_timeout` seconds, and its output is cut off at 1 MiB.
+//! - `print` and `debug` log at info and debug level.
+//!
+//! Inline scripts are not expanded like other configuration values, `${...}` in them is Rhai's
+//! string interpolation.
+//!
+//! Scripts are stopped after `max_operations`, but this is not a sandbox: `read_file` and
+//! `command` can read any file and run any program the agent itself can.
+
+use super::*;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, ImmutableString, Map, Scope, AST};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    process::{Command, Stdio},
+    time::Instant,
+};
+
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+/// Largest file or program output a script is handed, matches the engine's string limit
+const MAX_OUTPUT: u64 = 1024 * 1024;
+
+/// Latest state and attributes of an entity on the cluster
+type Snapshot = HashMap<(String, String), (Option<String>, Document)>;
+
+#[derive(Debug, Clone)]
+struct Declaration {
+    typ:  DeviceType,
+    unit: Option<String>,
+}
+
+/// What a run of the script asked for, applied once it completes
+#[derive(Debug, Default)]
+struct Effects {
+    devices: Vec<(String, Declaration)>,
+    updates: Vec<(String, Document, Document)>,
+}
+
+#[derive(Debug)]
+pub struct ScriptPlugin {
+    ast:             Arc<AST>,
+    max_operations:  u64,
+    command_timeout: Duration,
+    registry:        DeviceRegistry,
+    sinks:           Sinks,
+    cluster:         ClusterNodes,
+    devices:         parking_lot::Mutex<BTreeMap<String, Declaration>>,
+}
+
+fn script_error(e: impl std::fmt::Display) -> Box<EvalAltResult> {
+    e.to_string().into()
+}
+
+fn to_document(value: &Dynamic) -> ScriptResult<Document> {
+    rhai::serde::from_dynamic(value)
+}
+
+/// Run a program for `command`, killing it once `timeout` passes. Its output is read on threads
+/// of its own so a chatty program can't block on a full pipe while it is waited for.
+fn run_command(program: &str, args: &[String], timeout: Duration) -> Result<Map> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let reader = |pipe: Option<Box<dyn Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut out = vec![];
+            if let Some(pipe) = pipe {
+                // Keep draining past the limit so the program isn't blocked writing
+                let mut pipe = pipe;
+                let _ = pipe.by_ref().take(MAX_OUTPUT).read_to_end(&mut out);
+                let _ = std::io::copy(&mut pipe, &mut std::io::sink());
+            }
+            out
+        })
+    };
+    let stdout = reader(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = reader(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            bail!("Timed out after {}s", timeout.as_secs());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    let text = |t: std::thread::JoinHandle<Vec<u8>>| {
+        let out = t.join().unwrap_or_default();
+        String::from_utf8_lossy(&out).trim().to_string()
+    };
+    let mut result = Map::new();
+    result.insert("status".into(), (status.code().unwrap_or(-1) as i64).into());
+    result.insert("stdout".into(), text(stdout).into());
+    result.insert("stderr".into(), text(stderr).into());
+    Ok(result)
+}
+
+impl ScriptPlugin {
+    pub fn new(
+        registry: DeviceRegistry,
+        sinks: Sinks,
+        cluster: ClusterNodes,
+        script: &str,
+        max_operations: u64,
+        command_timeout: Duration,
+    ) -> Result<Self> {
+        let ast = Engine::new()
+            .compile(script)
+            .map_err(|e| anyhow!("Invalid script: {}", e))?;
+        Ok(ScriptPlugin {
+            ast: Arc::new(ast),
+            max_operations,
+            command_timeout,
+            registry,
+            sinks,
+            cluster,
+            devices: Default::default(),
+        })
+    }
+
+    /// Copy the cluster state, scripts run synchronously and can't wait for its locks
+    async fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        let nodes = self.cluster.read().await;
+        for (node, entities) in nodes.iter() {
+            let entities = entities.read().await;
+            for (entity, data) in entities.iter() {
+                let data = data.read().await;
+                let stat = data.stat.clone();
+                snapshot.insert(
+                    (node.to_string(), entity.to_string()),
+                    (stat, data.attr.clone()),
+                );
+            }
+        }
+        snapshot
+    }
+
+    fn engine(
+        max_operations: u64,
+        command_timeout: Duration,
+        snapshot: Arc<Snapshot>,
+        effects: Arc<parking_lot::Mutex<Effects>>,
+    ) -> Engine {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(max_operations)
+            .set_max_call_levels(32)
+            .set_max_string_size(MAX_OUTPUT as usize)
+            .set_max_array_size(10_000)
+            .set_max_map_size(10_000)
+            .on_print(|s| info!("{}", s))
+            .on_debug(|s, _, _| debug!("{}", s));
+
+        let declared = effects.clone();
+        engine.register_fn("device", move |name: ImmutableString| {
+            let typ = DeviceType::Sensor(SensorDeviceClass::None);
+            let declaration = Declaration { typ, unit: None };
+            declared
+                .lock()
+                .devices
+                .push((name.to_string(), declaration));
+        });
+        let declared = effects.clone();
+        engine.register_fn(
+            "device",
+            move |name: ImmutableString, options: Map| -> ScriptResult<()> {
+                let option = |key: &str| options.get(key).map(|v| v.to_string());
+                let typ = option("type").unwrap_or_else(|| "sensor".into());
+                let typ =
+                    DeviceType::parse(&typ, option("class").as_deref()).map_err(script_error)?;
+                let declaration = Declaration {
+                    typ,
+                    unit: option("unit"),
+                };
+                declared
+                    .lock()
+                    .devices
+                    .push((name.to_string(), declaration));
+                Ok(())
+            },
+        );
+
+        let published = effects.clone();
+        engine.register_fn(
+            "publish",
+            move |name: ImmutableString, value: Dynamic| -> ScriptResult<()> {
+                let value = to_document(&value)?;
+                let update = (name.to_string(), value, Document::Unit);
+                published.lock().updates.push(update);
+                Ok(())
+            },
+        );
+        let published = effects;
+        engine.register_fn(
+            "publish",
+            move |name: ImmutableString, value: Dynamic, attributes: Map| -> ScriptResult<()> {
+                let value = to_document(&value)?;
+                let attributes = to_document(&attributes.into())?;
+                published
+                    .lock()
+                    .updates
+                    .push((name.to_string(), value, attributes));
+                Ok(())
+            },
+        );
+
+        let values = snapshot.clone();
+        engine.register_fn("value", move |node: &str, entity: &str| -> Dynamic {
+            match values.get(&(node.to_string(), entity.to_string())) {
+                Some((Some(stat), _)) => stat.clone().into(),
+                _ => Dynamic::UNIT,
+            }
+        });
+        engine.register_fn(
+            "attributes",
+            move |node: &str, entity: &str| -> ScriptResult<Dynamic> {
+                match snapshot.get(&(node.to_string(), entity.to_string())) {
+                    Some((_, attr)) => rhai::serde::to_dynamic(attr),
+                    None => Ok(Dynamic::UNIT),
+                }
+            },
+        );
+
+        engine.register_fn("read_file", |path: &str| -> ScriptResult<String> {
+            let error = |e: std::io::Error| script_error(format!("{}: {}", path, e));
+            let file = std::fs::File::open(path).map_err(error)?;
+            if file.metadata().map_err(error)?.len() > MAX_OUTPUT {
+                return Err(script_error(format!("{} is larger than 1 MiB", path)));
+            }
+            let mut contents = String::new();
+            file.take(MAX_OUTPUT)
+                .read_to_string(&mut contents)
+                .map_err(error)?;
+            Ok(contents)
+        });
+        engine.register_fn(
+            "command",
+            move |program: &str, args: Array| -> ScriptResult<Map> {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                run_command(program, &args, command_timeout)
+                    .map_err(|e| script_error(format!("{}: {:#}", program, e)))
+            },
+        );
+        engine
+    }
+
+    async fn register(&self, name: &str, plugin: &str, declaration: &Declaration) -> Result<()> {
+        let mut device = self.registry.new_device(
+            name.to_string(),
+            declaration.typ.clone(),
+            plugin.to_string(),
+        );
+        if let Some(unit) = &declaration.unit {
+            device = device.with_unit_of_measurement(unit.to_string());
+        }
+        self.registry.register(device.build()).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Plugin for ScriptPlugin {
+    async fn run(&self, name: String) -> Result<()> {
+        let snapshot = Arc::new(self.snapshot().await);
+        let effects: Arc<parking_lot::Mutex<Effects>> = Default::default();
+        let engine = Self::engine(
+            self.max_operations,
+            self.command_timeout,
+            snapshot,
+            effects.clone(),
+        );
+        let ast = self.ast.clone();
+        logging::spawn_blocking(move || engine.run_ast_with_scope(&mut Scope::new(), &ast))
+            .await?
+            .map_err(|e| anyhow!("Script failed: {}", e))?;
+
+        let effects = std::mem::take(&mut *effects.lock());
+        for (device, declaration) in effects.devices.iter() {
+            self.register(device, &name, declaration).await?;
+            self.devices
+                .lock()
+                .insert(device.to_string(), declaration.clone());
+        }
+        for (device, value, attr) in effects.updates.into_iter() {
+            let device = match self.registry.get_by_name(&device).await {
+                Some(d) if d.plugin() == name => d,
+                _ => bail!("Device '{}' was not declared", device),
+            };
+            let update = DeviceUpdate {
+                device: Some(device),
+                value,
+                attr,
+            };
+            self.sinks.update_device(&update).await?;
+        }
+        Ok(())
+    }
+
+    async fn heartbeat(&self, name: String) -> Result<()> {
+        let devices = self.devices.lock().clone();
+        for (device, declaration) in devices.iter() {
+            self.register(device, &name, declaration).await?;
+        }
+        Ok(())
+    }
+
+    async fn leader_heartbeat(&self, _: String, _: ClusterNodes) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use super::*;
+use bindings::corvus::plugin::host::{self, Level};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use wasmtime::{
+    component::{Component, Linker},
+    Config, Engine, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+mod bindings {
+    wasmtime::component::bindgen!({ path: "wit/plugin.wit", world: "plugin" });
+}
+
+/// Longest pause a plugin can ask for at once
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref ENGINE: Engine = {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).expect("Failed to create the WebAssembly engine")
+    };
+}
+
+/// Something a call asked for, applied by the async side while the call is still running
+#[derive(Debug)]
+enum Effect {
+    Device(String, DeviceType, Option<String>),
+    Update(String, Document, Document),
+}
+
+struct State {
+    plugin:  String,
+    limits:  StoreLimits,
+    /// Where the effects of the current call go, only set during a call
+    effects: Option<UnboundedSender<Effect>>,
+}
+
+impl State {
+    fn emit(&self, effect: Effect) {
+        if let Some(effects) = &self.effects {
+            effects.send(effect).ok();
+        }
+    }
+}
+
+impl host::Host for State {
+    fn register_device(
+        &mut self,
+        name: String,
+        device_type: String,
+        device_class: Option<String>,
+        unit: Option<String>,
+    ) -> std::result::Result<(), String> {
+        let typ = DeviceType::parse(&device_type.replace("-", "_"), device_class.as_deref())
+            .map_err(|e| e.to_string())?;
+        self.emit(Effect::Device(name, typ, unit));
+        Ok(())
+    }
+
+    fn publish(
+        &mut self,
+        name: String,
+        value: String,
+        attributes: String,
+    ) -> std::result::Result<(), String> {
+        let parse = |s: &str| serde_json::from_str::<Document>(s).map_err(|e| e.to_string());
+        self.emit(Effect::Update(name, parse(&value)?, parse(&attributes)?));
+        Ok(())
+    }
+
+    fn log(&mut self, level: Level, message: String) {
+        let level = match level {
+            Level::Error => log::Level::Error,
+            Level::Warn => log::Level::Warn,
+            Level::Info => log::Level::Info,
+            Level::Debug => log::Level::Debug,
+            Level::Trace => log::Level::Trace,
+        };
+        log!(level, "{}: {}", self.plugin, message);
+    }
+
+    fn sleep(&mut self, millis: u32) {
+        std::thread::sleep(Duration::from_millis(millis.into()).min(MAX_SLEEP));
+    }
+}
+
+struct Instance {
+    store:  Store<State>,
+    plugin: bindings::Plugin,
+}
+
+/// The compiled component, instantiated on first use and after traps
+struct Module {
+    name:       String,
+    component:  Component,
+    linker:     Linker<State>,
+    fuel:       u64,
+    max_memory: usize,
+    instance:   Option<Instance>,
+}
+
+impl Module {
+    fn instantiate(&self) -> Result<Instance> {
+        let state = State {
+            plugin:  self.name.to_string(),
+            limits:  StoreLimitsBuilder::new()
+                .memory_size(self.max_memory)
+                .instances(16)
+                .build(),
+            effects: None,
+        };
+        let mut store = Store::new(&ENGINE, state);
+        store.limiter(|s| &mut s.limits);
+        let plugin = bindings::Plugin::instantiate(&mut store, &self.component, &self.linker)
+            .context("Failed to instantiate")?;
+        Ok(Instance { store, plugin })
+    }
+
+    /// Call an export with a fresh allowance of fuel, sending its effects to `effects`
+    fn call<F>(&mut self, effects: UnboundedSender<Effect>, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Store<State>, &bindings::Plugin) -> wasmtime::Result<Result<(), String>>,
+    {
+        if self.instance.is_none() {
+            self.instance = Some(self.instantiate()?);
+        }
+        let instance = self.instance.as_mut().expect("instantiated above");
+        instance.store.data_mut().effects = Some(effects);
+        let result = instance
+            .store
+            .set_fuel(self.fuel)
+            .and_then(|_| f(&mut instance.store, &instance.plugin));
+        // Closes the channel once the effects sent so far are applied
+        instance.store.data_mut().effects = None;
+        match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(message)) => Err(anyhow!("{}", message)),
+            Err(trap) => {
+                // Instances can't be entered again after a trap
+                self.instance = None;
+                Err(anyhow!("Trapped: {:?}", trap))
+            }
+        }
+    }
+}
+
+pub struct WasmPlugin {
+    name:     String,
+    path:     String,
+    module:   Arc<std::sync::Mutex<Module>>,
+    registry: DeviceRegistry,
+    sinks:    Sinks,
+}
+
+impl std::fmt::Debug for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmPlugin")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl WasmPlugin {
+    pub fn new(
+        name: String,
+        registry: DeviceRegistry,
+        sinks: Sinks,
+        path: &str,
+        fuel: u64,
+        max_memory: usize,
+    ) -> Result<Self> {
+        let component = Component::from_file(&ENGINE, path)
+            .with_context(|| format!("Failed to load component {}", path))?;
+        let mut linker = Linker::new(&ENGINE);
+        bindings::Plugin::add_to_linker(&mut linker, |state: &mut State| state)?;
+        let module = Module {
+            name: name.to_string(),
+            component,
+            linker,
+            fuel,
+            max_memory,
+            instance: None,
+        };
+        Ok(WasmPlugin {
+            name,
+            path: path.to_string(),
+            module: Arc::new(std::sync::Mutex::new(module)),
+            registry,
+            sinks,
+        })
+    }
+
+    /// Call into the component on the blocking pool and apply what it asks for as it goes, so
+    /// updates published before a sleep show up right away
+    async fn call<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Store<State>, &bindings::Plugin) -> wasmtime::Result<Result<(), String>>
+            + Send
+            + 'static,
+    {
+        let module = self.module.clone();
+        let (effects, mut requested) = unbounded_channel();
+        let call = logging::spawn_blocking(move || {
+            let mut module = module.lock().unwrap_or_else(|e| e.into_inner());
+            module.call(effects, f)
+        });
+
+        let mut failed = None;
+        while let Some(effect) = requested.recv().await {
+            if let Err(e) = self.apply(effect).await {
+                failed.get_or_insert(e);
+            }
+        }
+        let result = call.await?;
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        result.with_context(|| format!("Plugin {} failed", self.path))
+    }
+
+    async fn apply(&self, effect: Effect) -> Result<()> {
+        match effect {
+            Effect::Device(name, typ, unit) => {
+                let mut device = self.registry.new_device(name, typ, self.name.to_string());
+                if let Some(unit) = unit {
+                    device = device.with_unit_of_measurement(unit);
+                }
+                self.registry.register(device.build()).await?;
+            }
+            Effect::Update(name, value, attr) => {
+                let device = match self.registry.get_by_name(&name).await {
+                    Some(d) if d.plugin() == self.name => d,
+                    _ => bail!("Device '{}' was not declared", name),
+                };
+                let update = DeviceUpdate {
+                    device: Some(device),
+                    value,
+                    attr,
+                };
+                self.sinks.update_device(&update).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Plugin for WasmPlugin {
+    async fn run(&self, _: String) -> Result<()> {
+        self.call(|store, plugin| plugin.call_run(store)).await
+    }
+
+    async fn heartbeat(&self, _: String) -> Result<()> {
+        self.call(|store, plugin| plugin.call_heartbeat(store))
+            .await
+    }
+
+    async fn leader_heartbeat(&self, _: String, _: ClusterNodes) -> Result<()> {
+        Ok(())
+    }
+
+    async fn process_update(&self, data: Document) -> Result<()> {
+        let data = serde_json::to_string(&data)?;
+        self.call(move |store, plugin| plugin.call_process_update(store, &data))
+            .await
+    }
+
+    async fn set_property(&self, device: Device, value: String) -> Result<()> {
+        let name = device.display_name().to_string();
+        self.call(move |store, plugin| plugin.call_process_command(store, &name, &value))
+            .await
+    }
+}
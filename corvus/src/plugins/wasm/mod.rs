@@ -0,0 +1,16 @@
+//! Plugins compiled to WebAssembly components implementing the `plugin` world of
+//! `wit/plugin.wit`, so one build runs on every platform. Each call into the component gets
+//! `fuel` to spend and the component's memory is capped at `max_memory` bytes. A component
+//! that traps is instantiated again for the next call.
+
+use super::*;
+
+#[cfg(target_pointer_width = "64")]
+mod host;
+#[cfg(not(target_pointer_width = "64"))]
+mod unsupported;
+
+#[cfg(target_pointer_width = "64")]
+pub use host::WasmPlugin;
+#[cfg(not(target_pointer_width = "64"))]
+pub use unsupported::WasmPlugin;
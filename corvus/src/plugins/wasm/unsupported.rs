@@ -0,0 +1,26 @@
+use super::*;
+
+/// Wasmtime needs a 64 bit platform, elsewhere loading a plugin fails
+#[derive(Debug)]
+pub struct WasmPlugin;
+
+impl WasmPlugin {
+    pub fn new(_: String, _: DeviceRegistry, _: Sinks, _: &str, _: u64, _: usize) -> Result<Self> {
+        bail!("WebAssembly plugins are only supported on 64 bit platforms")
+    }
+}
+
+#[async_trait]
+impl Plugin for WasmPlugin {
+    async fn run(&self, _: String) -> Result<()> {
+        Ok(())
+    }
+
+    async fn heartbeat(&self, _: String) -> Result<()> {
+        Ok(())
+    }
+
+    async fn leader_heartbeat(&self, _: String, _: ClusterNodes) -> Result<()> {
+        Ok(())
+    }
+}
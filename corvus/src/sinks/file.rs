@@ -0,0 +1,68 @@
+use super::*;
+use crate::config::SinkFileFormat;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+/// Appends every update to a local CSV or newline delimited JSON file
+#[derive(Debug)]
+pub struct FileSink {
+    format: SinkFileFormat,
+    file:   parking_lot::Mutex<File>,
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf, format: SinkFileFormat) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+        if format == SinkFileFormat::CSV && file.metadata()?.len() == 0 {
+            writeln!(file, "timestamp,location,plugin,device,value")?;
+        }
+        Ok(FileSink {
+            file: parking_lot::Mutex::new(file),
+            format,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    async fn send(&self, device: &Device, update: &DeviceUpdate) -> Result<()> {
+        let record = record(device, update)?;
+        let line = match self.format {
+            SinkFileFormat::NDJSON => record.to_string(),
+            SinkFileFormat::CSV => {
+                let value = match &record["value"] {
+                    serde_json::Value::String(s) => s.to_string(),
+                    v => v.to_string(),
+                };
+                [
+                    record["timestamp"].as_str().unwrap_or_default(),
+                    device.location(),
+                    device.plugin(),
+                    device.display_name(),
+                    &value,
+                ]
+                .iter()
+                .map(|f| csv_field(f))
+                .collect::<Vec<_>>()
+                .join(",")
+            }
+        };
+        writeln!(self.file.lock(), "{}", line)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use super::*;
+use serde_json::Value;
+use std::{
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::net::{lookup_host, UdpSocket};
+
+#[derive(Debug)]
+enum Target {
+    Http(String, ureq::Agent),
+    /// The socket is bound on first use and again after it fails
+    Udp(String, Mutex<Option<(UdpSocket, SocketAddr)>>),
+}
+
+/// Writes updates in InfluxDB line protocol, over HTTP to a write endpoint or over UDP
+#[derive(Debug)]
+pub struct InfluxDBSink {
+    target:      Target,
+    token:       Option<String>,
+    measurement: String,
+}
+
+/// Escape commas, equals signs and spaces in measurements and tags
+fn escape_key(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn escape_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl InfluxDBSink {
+    pub fn new(url: &str, token: Option<String>, measurement: &str) -> Result<Self> {
+        let target = if let Some(addr) = url.strip_prefix("udp://") {
+            Target::Udp(addr.trim_end_matches('/').to_string(), Default::default())
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Target::Http(url.to_string(), agent())
+        } else {
+            bail!(
+                "InfluxDB url '{}' must start with http://, https:// or udp://",
+                url
+            );
+        };
+        Ok(InfluxDBSink {
+            measurement: measurement.to_string(),
+            target,
+            token,
+        })
+    }
+
+    /// Numbers are always written as floats so a sensor reporting whole numbers doesn't
+    /// conflict with the field type of earlier readings
+    fn field(value: &Value) -> Option<String> {
+        match value {
+            Value::Null => None,
+            Value::Number(n) => n.as_f64().map(|f| format!("{:?}", f)),
+            Value::Bool(b) => Some(b.to_string()),
+            Value::String(s) => Some(escape_string(s)),
+            v => Some(escape_string(&v.to_string())),
+        }
+    }
+
+    fn line(&self, device: &Device, update: &DeviceUpdate) -> Result<Option<String>> {
+        let value = match Self::field(&serde_json::to_value(&update.value)?) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        Ok(Some(format!(
+            "{},device={},name={},plugin={},location={} value={} {}",
+            escape_key(&self.measurement),
+            escape_key(&device.uniq_id()),
+            escape_key(device.display_name()),
+            escape_key(device.plugin()),
+            escape_key(device.location()),
+            value,
+            timestamp
+        )))
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxDBSink {
+    async fn send(&self, device: &Device, update: &DeviceUpdate) -> Result<()> {
+        let line = match self.line(device, update)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        trace!("InfluxDB line: {}", line);
+        match &self.target {
+            Target::Http(url, agent) => {
+                let headers = self
+                    .token
+                    .iter()
+                    .map(|t| ("Authorization".to_string(), format!("Token {}", t)))
+                    .collect();
+                post(
+                    agent,
+                    url.to_string(),
+                    "text/plain; charset=utf-8",
+                    headers,
+                    line,
+                )
+                .await
+            }
+            Target::Udp(addr, socket) => {
+                let mut socket = socket.lock().await;
+                if socket.is_none() {
+                    *socket = Some(udp_socket(addr).await?);
+                }
+                let (udp, target) = socket.as_ref().unwrap();
+                if let Err(e) = udp.send_to(line.as_bytes(), target).await {
+                    *socket = None;
+                    return Err(e.into());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn udp_socket(addr: &str) -> Result<(UdpSocket, SocketAddr)> {
+    let target = lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("Could not resolve {}", addr))?;
+    let bind = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    Ok((UdpSocket::bind(bind).await?, target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str, location: &str) -> Device {
+        DeviceData::new(
+            name.into(),
+            DeviceType::Sensor(SensorDeviceClass::None),
+            location.into(),
+            "corvus".into(),
+            "sensors".into(),
+        )
+        .build()
+    }
+
+    fn update(value: impl serde::Serialize) -> DeviceUpdate {
+        DeviceUpdate {
+            device: None,
+            value:  Document::new(value).unwrap(),
+            attr:   Default::default(),
+        }
+    }
+
+    /// The line without its timestamp
+    fn line(sink: &InfluxDBSink, device: &Device, update: DeviceUpdate) -> Option<String> {
+        let line = sink.line(device, &update).unwrap()?;
+        let (line, timestamp) = line.rsplit_once(' ').unwrap();
+        assert!(timestamp.parse::<u128>().is_ok(), "{}", timestamp);
+        Some(line.to_string())
+    }
+
+    #[test]
+    fn escapes_measurement_and_tags() {
+        let sink = InfluxDBSink::new("udp://localhost:8089", None, "corvus env").unwrap();
+        let device = device("Living room, east=1", "my home");
+        assert_eq!(
+            line(&sink, &device, update(21)).unwrap(),
+            r"corvus\ env,device=my_home_living_room\,_east\=1,name=Living\ room\,\ east\=1,plugin=sensors,location=my\ home value=21.0"
+        );
+    }
+
+    #[test]
+    fn writes_numbers_as_floats_and_quotes_strings() {
+        let sink = InfluxDBSink::new("http://localhost:8086/write", None, "corvus").unwrap();
+        let device = device("Door", "hall");
+        let value =
+            |v| line(&sink, &device, v).map(|l| l.split_once(" value=").unwrap().1.to_string());
+        assert_eq!(value(update(21)).unwrap(), "21.0");
+        assert_eq!(value(update(21.5)).unwrap(), "21.5");
+        assert_eq!(value(update(true)).unwrap(), "true");
+        assert_eq!(
+            value(update(r#"open "wide" \ now"#)).unwrap(),
+            r#""open \"wide\" \\ now""#
+        );
+        assert_eq!(value(update(())), None);
+    }
+
+    #[test]
+    fn rejects_unknown_schemes() {
+        assert!(InfluxDBSink::new("ftp://localhost", None, "corvus").is_err());
+    }
+}
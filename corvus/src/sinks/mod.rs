@@ -0,0 +1,219 @@
+use crate::{config::SinkOptions, logging, metrics, prelude::*};
+use file::FileSink;
+use glob::Pattern;
+use influxdb::InfluxDBSink;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, error::TrySendError, Sender};
+use webhook::WebhookSink;
+
+mod file;
+mod influxdb;
+mod webhook;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Updates waiting for a configured sink, later ones are dropped while it is full
+const SINK_QUEUE: usize = 100;
+
+/// A destination for device updates
+#[async_trait]
+pub trait Sink: std::fmt::Debug + Send + Sync {
+    async fn send(&self, device: &Device, update: &DeviceUpdate) -> Result<()>;
+}
+
+#[async_trait]
+impl Sink for MQTTService {
+    async fn send(&self, _: &Device, update: &DeviceUpdate) -> Result<()> {
+        self.update_device(update).await
+    }
+}
+
+/// Glob patterns selecting the devices a sink receives, by display name or plugin
+#[derive(Debug, Default)]
+struct SinkFilter {
+    include_devices: Vec<Pattern>,
+    exclude_devices: Vec<Pattern>,
+    include_plugins: Vec<Pattern>,
+    exclude_plugins: Vec<Pattern>,
+}
+
+fn patterns(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|p| Pattern::new(p).with_context(|| format!("Invalid pattern '{}'", p)))
+        .collect()
+}
+
+impl SinkFilter {
+    fn new(config: &SinkConfiguration) -> Result<Self> {
+        Ok(SinkFilter {
+            include_devices: patterns(&config.include_devices)?,
+            exclude_devices: patterns(&config.exclude_devices)?,
+            include_plugins: patterns(&config.include_plugins)?,
+            exclude_plugins: patterns(&config.exclude_plugins)?,
+        })
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        let any = |patterns: &[Pattern], s: &str| patterns.iter().any(|p| p.matches(s));
+        let included = |patterns: &[Pattern], s: &str| patterns.is_empty() || any(patterns, s);
+        included(&self.include_devices, device.display_name())
+            && included(&self.include_plugins, device.plugin())
+            && !any(&self.exclude_devices, device.display_name())
+            && !any(&self.exclude_plugins, device.plugin())
+    }
+}
+
+/// A sink from the configuration, written to by a task of its own so a slow destination
+/// doesn't hold up the plugins. The task ends once the sink is dropped on reload.
+#[derive(Debug)]
+struct ConfiguredSink {
+    name:   String,
+    filter: SinkFilter,
+    queue:  Sender<(Device, DeviceUpdate)>,
+}
+
+impl ConfiguredSink {
+    fn new(config: &SinkConfiguration) -> Result<Self> {
+        let sink: Box<dyn Sink> = match &config.sink {
+            SinkOptions::InfluxDB {
+                url,
+                token,
+                measurement,
+            } => Box::new(InfluxDBSink::new(url, token.clone(), measurement)?),
+            SinkOptions::File { path, format } => Box::new(FileSink::new(path.into(), *format)?),
+            SinkOptions::Webhook { url, headers } => {
+                Box::new(WebhookSink::new(url.into(), headers.clone()))
+            }
+        };
+        let filter = SinkFilter::new(config)?;
+        let name = config.name.to_string();
+        let (queue, mut updates) = channel::<(Device, DeviceUpdate)>(SINK_QUEUE);
+        let task_name = name.to_string();
+        tokio::spawn(async move {
+            while let Some((device, update)) = updates.recv().await {
+                let labels = [("sink", task_name.as_str())];
+                match sink.send(&device, &update).await {
+                    Ok(_) => metrics::SINK_WRITES.inc(&labels),
+                    Err(e) => {
+                        metrics::SINK_ERRORS.inc(&labels);
+                        warn!("Sink '{}' failed: {:?}", task_name, e);
+                    }
+                }
+            }
+        });
+        Ok(ConfiguredSink {
+            name,
+            filter,
+            queue,
+        })
+    }
+
+    /// Queue an update, dropping it when the sink has fallen behind
+    fn queue(&self, device: &Device, update: &DeviceUpdate) {
+        match self.queue.try_send((device.clone(), update.clone())) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => {
+                metrics::SINK_DROPS.inc(&[("sink", self.name.as_str())]);
+                warn!("Sink '{}' is falling behind, dropping an update", self.name);
+            }
+            Err(TrySendError::Closed(_)) => warn!("Sink '{}' has stopped", self.name),
+        }
+    }
+}
+
+/// Sinks loaded from a configuration, not receiving updates until passed to `Sinks::replace`
+#[derive(Debug)]
+pub struct LoadedSinks(Vec<ConfiguredSink>);
+
+/// Every destination device updates are sent to. MQTT always receives every update, the
+/// sinks from the configuration only those passing their filters.
+#[derive(Debug, Clone)]
+pub struct Sinks {
+    mqtt:       MQTTService,
+    configured: Arc<parking_lot::RwLock<Arc<Vec<ConfiguredSink>>>>,
+}
+
+impl Sinks {
+    pub fn new(mqtt: MQTTService, config: &Configuration) -> Result<Self> {
+        let sinks = Sinks {
+            mqtt,
+            configured: Default::default(),
+        };
+        sinks.replace(Self::load(config)?);
+        Ok(sinks)
+    }
+
+    /// Construct the sinks of a configuration, failing if any of them fails to load
+    pub fn load(config: &Configuration) -> Result<LoadedSinks> {
+        let sinks = config
+            .sinks
+            .iter()
+            .map(|s| {
+                ConfiguredSink::new(s).with_context(|| format!("Failed to load sink '{}'", s.name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(LoadedSinks(sinks))
+    }
+
+    /// Send updates to a new set of sinks, the current ones finish what they have queued
+    pub fn replace(&self, sinks: LoadedSinks) {
+        for s in sinks.0.iter() {
+            info!("Sending device updates to sink '{}'", s.name);
+        }
+        *self.configured.write() = Arc::new(sinks.0);
+    }
+
+    /// Send an update to every sink. Configured sinks only have it queued, their failures are
+    /// logged and counted without failing the plugin, MQTT errors are returned as before.
+    pub async fn update_device(&self, update: &DeviceUpdate) -> Result<()> {
+        let device = match &update.device {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        let configured = self.configured.read().clone();
+        for s in configured.iter().filter(|s| s.filter.matches(device)) {
+            s.queue(device, update);
+        }
+        self.mqtt.send(device, update).await
+    }
+}
+
+/// JSON representation of an update, shared by the file and webhook sinks
+fn record(device: &Device, update: &DeviceUpdate) -> Result<serde_json::Value> {
+    Ok(serde_json::json!({
+        "timestamp": chrono::Local::now().to_rfc3339(),
+        "location": device.location(),
+        "plugin": device.plugin(),
+        "device": device.display_name(),
+        "id": device.uniq_id(),
+        "value": serde_json::to_value(&update.value)?,
+        "attributes": serde_json::to_value(&update.attr)?,
+    }))
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(HTTP_TIMEOUT).build()
+}
+
+/// POST a body on the blocking pool, ureq has no async interface
+async fn post(
+    agent: &ureq::Agent,
+    url: String,
+    content_type: &'static str,
+    headers: Vec<(String, String)>,
+    body: String,
+) -> Result<()> {
+    let agent = agent.clone();
+    logging::spawn_blocking(move || {
+        let mut request = agent.post(&url).set("Content-Type", content_type);
+        for (k, v) in headers.iter() {
+            request = request.set(k, v);
+        }
+        request
+            .send_string(&body)
+            .with_context(|| format!("Request to {} failed", url))?;
+        Ok(())
+    })
+    .await?
+}
@@ -0,0 +1,118 @@
+use super::*;
+use std::collections::BTreeMap;
+
+/// POSTs every update as JSON to a URL
+#[derive(Debug)]
+pub struct WebhookSink {
+    url:     String,
+    headers: Vec<(String, String)>,
+    agent:   ureq::Agent,
+}
+
+impl WebhookSink {
+    pub fn new(url: String, headers: BTreeMap<String, String>) -> Self {
+        WebhookSink {
+            headers: headers.into_iter().collect(),
+            agent: agent(),
+            url,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    async fn send(&self, device: &Device, update: &DeviceUpdate) -> Result<()> {
+        let body = record(device, update)?.to_string();
+        post(
+            &self.agent,
+            self.url.to_string(),
+            "application/json",
+            self.headers.clone(),
+            body,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    /// Accept one request, answer 204 and return its headers and body
+    fn receive(listener: TcpListener) -> (Vec<String>, String) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            headers.push(line.trim().to_lowercase());
+        }
+        let len: usize = headers
+            .iter()
+            .find_map(|h| h.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        reader
+            .into_inner()
+            .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        (headers, String::from_utf8(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn posts_updates_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || receive(listener));
+
+        let headers = vec![("X-Token".to_string(), "secret".to_string())];
+        let sink = WebhookSink::new(url, headers.into_iter().collect());
+        let device = DeviceData::new(
+            "Attic Temperature".into(),
+            DeviceType::Sensor(SensorDeviceClass::Temperature),
+            "Loft".into(),
+            "corvus".into(),
+            "attic".into(),
+        )
+        .build();
+        let mut attr = Document::new(serde_json::json!({ "battery": 80 })).unwrap();
+        attr["source"] = "w1".into();
+        let update = DeviceUpdate {
+            device: Some(device.clone()),
+            value: Document::new(21.5).unwrap(),
+            attr,
+        };
+        sink.send(&device, &update).await.unwrap();
+
+        let (headers, body) = server.join().unwrap();
+        assert!(headers[0].starts_with("post /hook "), "{:?}", headers);
+        assert!(headers.contains(&"content-type: application/json".to_string()));
+        assert!(headers.contains(&"x-token: secret".to_string()));
+        let mut body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let timestamp = body["timestamp"].take();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp.as_str().unwrap()).is_ok());
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "timestamp": null,
+                "location": "Loft",
+                "plugin": "attic",
+                "device": "Attic Temperature",
+                "id": "loft_attic_temperature",
+                "value": 21.5,
+                "attributes": { "battery": 80, "source": "w1" },
+            })
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use crate::prelude::*;
+use std::{
+    ffi::OsStr,
+    os::unix::{ffi::OsStrExt, io::AsRawFd, net::UnixDatagram},
+    time::Duration,
+};
+
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// Whether the process was started by a service manager expecting notifications
+pub fn enabled() -> bool {
+    std::env::var_os(NOTIFY_SOCKET).is_some()
+}
+
+/// Send to a socket in the abstract namespace, which `UnixDatagram::send_to` can't address
+fn send_abstract(socket: &UnixDatagram, name: &[u8], msg: &[u8]) -> Result<()> {
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if name.len() >= addr.sun_path.len() {
+        bail!("{} is too long", NOTIFY_SOCKET);
+    }
+    for (dst, src) in addr.sun_path[1..].iter_mut().zip(name.iter()) {
+        *dst = *src as libc::c_char;
+    }
+    let len = std::mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+    let res = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            msg.as_ptr() as *const libc::c_void,
+            msg.len(),
+            0,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Send a state change such as `READY=1` to the service manager, does nothing when
+/// `NOTIFY_SOCKET` is unset
+pub fn notify(state: &str) -> Result<()> {
+    let path = match std::env::var_os(NOTIFY_SOCKET) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    // A leading '@' refers to the abstract namespace
+    match path.as_bytes().split_first() {
+        Some((b'@', name)) => send_abstract(&socket, name, state.as_bytes()),
+        _ => socket
+            .send_to(state.as_bytes(), OsStr::new(&path))
+            .map(|_| ())
+            .map_err(Error::from),
+    }
+    .with_context(|| format!("Failed to notify service manager of '{}'", state))
+}
+
+/// Time systemd waits for a watchdog ping before restarting the service, if the watchdog
+/// is enabled for this process
+pub fn watchdog_timeout() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match std::env::var("WATCHDOG_PID") {
+        Ok(pid) if pid.parse::<u32>().ok()? != std::process::id() => None,
+        _ => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+    fn received(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    // One test, NOTIFY_SOCKET is process wide
+    #[test]
+    fn notifies_path_and_abstract_sockets() {
+        std::env::remove_var(NOTIFY_SOCKET);
+        assert!(!enabled());
+        notify("READY=1").unwrap();
+
+        let path = std::env::temp_dir().join(format!("corvus-notify-{}", std::process::id()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        std::env::set_var(NOTIFY_SOCKET, &path);
+        assert!(enabled());
+        notify("READY=1").unwrap();
+        assert_eq!(received(&socket), "READY=1");
+        notify("WATCHDOG=1").unwrap();
+        assert_eq!(received(&socket), "WATCHDOG=1");
+        std::fs::remove_file(&path).unwrap();
+
+        let name = format!("corvus-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        std::env::set_var(NOTIFY_SOCKET, format!("@{}", name));
+        notify("WATCHDOG=1").unwrap();
+        assert_eq!(received(&socket), "WATCHDOG=1");
+
+        std::env::set_var(NOTIFY_SOCKET, &path);
+        assert!(notify("READY=1").is_err());
+        std::env::remove_var(NOTIFY_SOCKET);
+    }
+}
@@ -12,7 +12,7 @@ impl IntervalTrigger {
 }
 
 impl Trigger for IntervalTrigger {
-    fn init(&self, service: Plugins) -> Result<()> {
+    fn init(&self, service: Plugins) -> ServiceHandle {
         info!(
             "Starting service '{}' with interval trigger every {} seconds.",
             service.name(),
@@ -27,7 +27,6 @@ impl Trigger for IntervalTrigger {
                 let service = service.clone();
                 async move { service.run().await }
             },
-        )?;
-        Ok(())
+        )
     }
 }
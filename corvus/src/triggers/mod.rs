@@ -6,13 +6,14 @@ mod interval;
 mod on_start;
 
 pub trait Trigger {
-    fn init(&self, service: Plugins) -> Result<()>;
+    fn init(&self, service: Plugins) -> ServiceHandle;
 }
 
 #[derive(Debug, Clone)]
 pub enum Triggers {
     Interval(IntervalTrigger),
     OnStart(OnStartTrigger),
+    #[allow(dead_code)]
     MQTT(IntervalTrigger),
 }
 
@@ -27,7 +28,7 @@ impl Triggers {
         }
     }
 
-    pub fn init(&self, service: Plugins) -> Result<()> {
+    pub fn init(&self, service: Plugins) -> ServiceHandle {
         match self {
             Triggers::Interval(trigger) => trigger.init(service),
             Triggers::MQTT(trigger) => trigger.init(service),
@@ -10,7 +10,7 @@ impl OnStartTrigger {
 }
 
 impl Trigger for OnStartTrigger {
-    fn init(&self, service: Plugins) -> Result<()> {
+    fn init(&self, service: Plugins) -> ServiceHandle {
         info!("Starting service '{}'", service.name());
         start_service(
             Duration::from_secs(2),
@@ -21,7 +21,6 @@ impl Trigger for OnStartTrigger {
                 let service = service.clone();
                 async move { service.run().await }
             },
-        )?;
-        Ok(())
+        )
     }
 }
@@ -1,59 +1,121 @@
-use crate::prelude::*;
+use crate::{logging, metrics, prelude::*};
 pub use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use rand::Rng;
-use std::time::Duration;
-use tokio::time::sleep;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::{task::JoinHandle, time::sleep};
 
-#[derive(Clone)]
-pub struct ServiceData<T, F>
-where
-    T: Clone + Send + Sync + Fn() -> F,
-    F: 'static + Clone + Send + std::future::Future<Output = Result<()>>,
-{
-    pub name:              String,
-    pub start_immediately: bool,
-    pub add_jitter:        bool,
-    pub duration:          Duration,
-    pub service_method:    T,
+lazy_static! {
+    /// Status of every service by name, along with the id of the task owning the entry
+    static ref SERVICE_STATUS: parking_lot::Mutex<HashMap<String, (u64, ServiceStatus)>> =
+        Default::default();
 }
 
-#[async_trait]
-pub trait StaticService: Send + Sync + Clone {
-    const NAME: &'static str;
-    const START_IMMEDIATELY: bool;
-    const ADD_JITTER: bool;
-    const DURATION: Duration;
-    async fn exec_service(zelf: Self) -> Result<()>;
+static NEXT_SERVICE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Run history of a service started with `start_service`
+#[derive(Debug, Clone, Default)]
+pub struct ServiceStatus {
+    pub running:              bool,
+    pub runs:                 u64,
+    pub failures:             u64,
+    pub consecutive_failures: u64,
+    pub last_run:             Option<DateTime<Utc>>,
+    pub next_run:             Option<DateTime<Utc>>,
+    pub last_error:           Option<String>,
 }
 
-#[async_trait]
-impl<T, F> Service for ServiceData<T, F>
-where
-    T: Clone + Send + Sync + Fn() -> F,
-    F: 'static + Clone + Send + std::future::Future<Output = Result<()>>,
-{
-    fn name(&self) -> &str {
-        &self.name
-    }
+/// Snapshot of the status of every running service
+pub fn service_status() -> HashMap<String, ServiceStatus> {
+    SERVICE_STATUS
+        .lock()
+        .iter()
+        .map(|(name, (_, status))| (name.to_string(), status.clone()))
+        .collect()
+}
 
-    fn start_immediately(&self) -> bool {
-        self.start_immediately
+/// Services that are idle but missed their next run by more than `grace`, which means the
+/// loop driving them is wedged
+pub fn stalled_services(grace: Duration) -> Vec<String> {
+    let now = Utc::now();
+    SERVICE_STATUS
+        .lock()
+        .iter()
+        .filter(|(_, (_, s))| {
+            let deadline = s.next_run.and_then(|t| after(t, grace));
+            !s.running && matches!(deadline, Some(t) if t < now)
+        })
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+fn after(time: DateTime<Utc>, dur: Duration) -> Option<DateTime<Utc>> {
+    chrono::Duration::from_std(dur)
+        .ok()
+        .and_then(|d| time.checked_add_signed(d))
+}
+
+/// Update the status of service `id`, unless a newer service of the same name replaced it
+fn update_status(name: &str, id: u64, f: impl FnOnce(&mut ServiceStatus)) {
+    let mut status = SERVICE_STATUS.lock();
+    let (owner, status) = status
+        .entry(name.to_string())
+        .or_insert_with(|| (id, Default::default()));
+    if *owner == id {
+        f(status)
     }
+}
 
-    fn add_jitter(&self) -> bool {
-        self.add_jitter
+/// Held by a service task, removes its status once the task is dropped. An aborted task
+/// can't update its status after that.
+struct StatusGuard {
+    name: String,
+    id:   u64,
+}
+
+impl Drop for StatusGuard {
+    fn drop(&mut self) {
+        let mut status = SERVICE_STATUS.lock();
+        if matches!(status.get(&self.name), Some((owner, _)) if *owner == self.id) {
+            status.remove(&self.name);
+        }
     }
+}
 
-    fn duration(&self) -> Duration {
-        self.duration
+#[derive(Debug)]
+pub struct ServiceHandle {
+    name:   String,
+    id:     u64,
+    handle: JoinHandle<()>,
+}
+
+impl ServiceHandle {
+    pub fn status(&self) -> Option<ServiceStatus> {
+        match SERVICE_STATUS.lock().get(&self.name) {
+            Some((owner, status)) if *owner == self.id => Some(status.clone()),
+            _ => None,
+        }
     }
 
-    async fn exec_service(self) -> Result<()> {
-        let fut = (self.service_method)();
-        fut.await
+    /// Stop the service, its status is removed once the task has stopped
+    pub fn abort(&self) {
+        self.handle.abort();
     }
 }
 
+#[async_trait]
+pub trait StaticService: Send + Sync + Clone {
+    const NAME: &'static str;
+    const START_IMMEDIATELY: bool;
+    const ADD_JITTER: bool;
+    const DURATION: Duration;
+    async fn exec_service(zelf: Self) -> Result<()>;
+}
+
 #[async_trait]
 impl<T: StaticService> Service for T {
     fn name(&self) -> &str {
@@ -99,7 +161,7 @@ pub trait Service: Send + Sync + Clone {
                 let zelf = zelf.clone();
                 async move { zelf.clone().exec_service().await }
             },
-        )?;
+        );
         Ok(())
     }
 }
@@ -110,12 +172,25 @@ pub fn start_service<T, F>(
     immediate: bool,
     jitter: bool,
     f: T,
-) -> Result<()>
+) -> ServiceHandle
 where
     T: Fn() -> F + Send + 'static,
     F: std::future::Future<Output = Result<()>> + Send,
 {
-    tokio::spawn(async move {
+    let id = NEXT_SERVICE_ID.fetch_add(1, Ordering::Relaxed);
+    let delay = if immediate { Duration::default() } else { dur };
+    let status = ServiceStatus {
+        next_run: after(Utc::now(), delay),
+        ..Default::default()
+    };
+    SERVICE_STATUS.lock().insert(name.clone(), (id, status));
+    let guard = StatusGuard {
+        name: name.clone(),
+        id,
+    };
+    let handle_name = name.clone();
+    let handle = tokio::spawn(logging::with_service(name.clone(), async move {
+        let _guard = guard;
         info!("Starting service {}", name);
         if !immediate {
             sleep(dur).await;
@@ -129,14 +204,129 @@ where
             };
             let tsk = f();
             debug!("Running {}", name);
-            match tsk.await {
-                Ok(_) => sleep(dur).await,
-                Err(e) => {
-                    error!("Task {} failure! {:?}", name, e);
-                    sleep(dur).await;
+            update_status(&name, id, |s| {
+                s.running = true;
+                s.last_run = Some(Utc::now());
+            });
+            let started = Instant::now();
+            let res = tsk.await;
+            let labels = [("service", name.as_str())];
+            metrics::SERVICE_RUNS.inc(&labels);
+            metrics::SERVICE_DURATION.observe(&labels, started.elapsed().as_secs_f64());
+            if res.is_err() {
+                metrics::SERVICE_ERRORS.inc(&labels);
+            }
+            update_status(&name, id, |s| {
+                s.running = false;
+                s.runs += 1;
+                match &res {
+                    Ok(_) => s.consecutive_failures = 0,
+                    Err(e) => {
+                        s.failures += 1;
+                        s.consecutive_failures += 1;
+                        s.last_error = Some(format!("{:#}", e));
+                    }
                 }
+            });
+            if let Err(e) = res {
+                error!("Task {} failure! {:?}", name, e);
             }
+            update_status(&name, id, |s| {
+                s.next_run = after(Utc::now(), dur);
+            });
+            sleep(dur).await;
         }
-    });
-    Ok(())
+    }));
+    ServiceHandle {
+        name: handle_name,
+        id,
+        handle,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str) -> ServiceHandle {
+        start_service(
+            Duration::from_millis(10),
+            name.into(),
+            true,
+            false,
+            || async { Ok(()) },
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn aborted_services_leave_their_replacement_alone() {
+        let old = service("util-test-service");
+        sleep(Duration::from_millis(50)).await;
+        assert!(old.status().unwrap().runs > 0);
+
+        old.abort();
+        let new = service("util-test-service");
+        sleep(Duration::from_millis(50)).await;
+        assert!(old.status().is_none());
+        assert!(new.status().unwrap().runs > 0);
+
+        new.abort();
+        sleep(Duration::from_millis(50)).await;
+        assert!(!service_status().contains_key("util-test-service"));
+    }
+
+    // The paused clock jumps straight to the next timer whenever the runtime is idle, so
+    // these sleeps take no real time and timers always fire in order.
+
+    #[tokio::test]
+    async fn services_run_on_their_interval() {
+        tokio::time::pause();
+        let start = tokio::time::Instant::now();
+        let runs: Arc<parking_lot::Mutex<Vec<Duration>>> = Default::default();
+        let recorded = runs.clone();
+        let handle = start_service(
+            Duration::from_secs(10),
+            "Timing test interval".into(),
+            false,
+            false,
+            move || {
+                let recorded = recorded.clone();
+                async move {
+                    recorded.lock().push(start.elapsed());
+                    Ok(())
+                }
+            },
+        );
+
+        sleep(Duration::from_secs(25)).await;
+        let secs = |d: &Duration| d.as_secs();
+        assert_eq!(runs.lock().iter().map(secs).collect::<Vec<_>>(), [10, 20]);
+        assert_eq!(handle.status().unwrap().runs, 2);
+
+        handle.abort();
+        sleep(Duration::from_secs(30)).await;
+        assert_eq!(runs.lock().len(), 2);
+        assert!(handle.status().is_none());
+    }
+
+    #[tokio::test]
+    async fn service_failures_are_counted() {
+        tokio::time::pause();
+        let handle = start_service(
+            Duration::from_secs(5),
+            "Timing test failure".into(),
+            true,
+            false,
+            || async { Err(anyhow::anyhow!("sensor unplugged")) },
+        );
+
+        // Immediate runs at 0s and 5s
+        sleep(Duration::from_secs(7)).await;
+        let status = handle.status().unwrap();
+        assert_eq!(status.runs, 2);
+        assert_eq!(status.failures, 2);
+        assert_eq!(status.consecutive_failures, 2);
+        assert_eq!(status.last_error.as_deref(), Some("sensor unplugged"));
+        assert!(!status.running);
+    }
 }
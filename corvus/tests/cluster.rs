@@ -0,0 +1,192 @@
+mod common;
+
+use common::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn elects_a_single_leader() {
+    let port = broker().await;
+    let kitchen = start_app(config("kitchen", port, "")).await;
+    let garage = start_app(config("garage", port, "")).await;
+
+    kitchen.mqtt.heartbeat().await.unwrap();
+    let sid = kitchen.mqtt.get_sid().await.unwrap();
+    eventually("the garage to see the leader", || async {
+        garage.mqtt.get_leader().await.unwrap().as_ref() == Some(&sid)
+    })
+    .await;
+
+    // A current leader is respected by the other nodes
+    garage.mqtt.heartbeat().await.unwrap();
+    assert!(kitchen.mqtt.is_leader().await);
+    assert!(!garage.mqtt.is_leader().await);
+    assert_eq!(kitchen.mqtt.get_leader().await.unwrap(), Some(sid));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tracks_cluster_nodes() {
+    let port = broker().await;
+    let kitchen = start_app(config("kitchen", port, "")).await;
+    let _garage = start_app(config("garage", port, "")).await;
+
+    eventually("the kitchen to see both nodes", || async {
+        let nodes = kitchen.mqtt.get_nodes().await;
+        nodes.get("garage").map(|n| n.online) == Some(true) && nodes.contains_key("kitchen")
+    })
+    .await;
+}
+
+const BLUETOOTH: &str = r#"
+[[plugin]]
+name = "ble"
+# Scanning needs a controller, the readings come from fake agents instead
+trigger = { interval = 3600 }
+
+[plugin.definition]
+type = "bluetooth"
+# Move as soon as another room is stronger by the margin
+hysteresis_secs = 0
+"#;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn leader_aggregates_bluetooth_locations() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let leader = start_app(config("office", port, BLUETOOTH)).await;
+    leader.mqtt.heartbeat().await.unwrap();
+    eventually("leadership", || async { leader.mqtt.is_leader().await }).await;
+
+    let mac = "AA:BB:CC:DD:EE:FF";
+    let topic = "corvus/cluster/ble_aabbccddeeff_location/stat";
+    let aggregate = || async {
+        for plugin in leader.plugin_manager.list_plugins().await {
+            plugin
+                .leader_heartbeat(leader.cluster_data.clone())
+                .await
+                .unwrap();
+        }
+    };
+
+    recorder.fake_rssi("kitchen", "ble", mac, -70).await;
+    recorder.fake_rssi("garage", "ble", mac, -50).await;
+    eventually("the garage location", || async {
+        aggregate().await;
+        recorder.last(topic).as_deref() == Some("garage")
+    })
+    .await;
+
+    // A few strong readings get through the filter and win the room
+    for _ in 0..3 {
+        recorder.fake_rssi("kitchen", "ble", mac, -30).await;
+    }
+    eventually("the kitchen location", || async {
+        aggregate().await;
+        recorder.last(topic).as_deref() == Some("kitchen")
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn names_locations_after_configured_devices() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let extra = format!(
+        "{}{}",
+        BLUETOOTH,
+        r#"devices = [{ mac = "aa:bb:cc:dd:ee:ff", name = "Phone", icon = "mdi:cellphone" }]"#
+    );
+    let leader = start_app(config("office", port, &extra)).await;
+    leader.mqtt.heartbeat().await.unwrap();
+    eventually("leadership", || async { leader.mqtt.is_leader().await }).await;
+
+    recorder
+        .fake_rssi("kitchen", "ble", "AA:BB:CC:DD:EE:FF", -60)
+        .await;
+    eventually("the phone location", || async {
+        for plugin in leader.plugin_manager.list_plugins().await {
+            plugin
+                .leader_heartbeat(leader.cluster_data.clone())
+                .await
+                .unwrap();
+        }
+        recorder
+            .last("corvus/cluster/phone_location/stat")
+            .as_deref()
+            == Some("kitchen")
+    })
+    .await;
+
+    let discovery = recorder
+        .wait_for("homeassistant/sensor/corvus/phone_location/config")
+        .await;
+    assert!(discovery.contains("mdi:cellphone"), "{}", discovery);
+
+    assert_eq!(recorder.wait_for("corvus/cluster/phone/stat").await, "home");
+    let discovery = recorder
+        .wait_for("homeassistant/device_tracker/corvus/phone/config")
+        .await;
+    assert!(
+        discovery.contains(r#""src_type":"bluetooth""#),
+        "{}",
+        discovery
+    );
+    let attr = recorder.wait_for("corvus/cluster/phone/attr").await;
+    assert!(attr.contains(r#""device_location":"kitchen""#), "{}", attr);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_devices_away_after_consider_home() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let extra = format!(
+        "{}{}",
+        BLUETOOTH,
+        r#"consider_home = 0
+devices = [{ mac = "aa:bb:cc:dd:ee:ff", name = "Phone" }]"#
+    );
+    let leader = start_app(config("office", port, &extra)).await;
+    leader.mqtt.heartbeat().await.unwrap();
+    eventually("leadership", || async { leader.mqtt.is_leader().await }).await;
+
+    recorder
+        .fake_rssi("kitchen", "ble", "AA:BB:CC:DD:EE:FF", -60)
+        .await;
+    eventually("the phone away", || async {
+        for plugin in leader.plugin_manager.list_plugins().await {
+            plugin
+                .leader_heartbeat(leader.cluster_data.clone())
+                .await
+                .unwrap();
+        }
+        recorder.last("corvus/cluster/phone/stat").as_deref() == Some("not_home")
+    })
+    .await;
+    assert_eq!(
+        recorder
+            .last("corvus/cluster/phone_location/stat")
+            .as_deref(),
+        Some("not_home")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn leader_reports_many_lost_nodes_at_once() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let leader = start_app(config("office", port, "discovery_format = [\"homie\"]")).await;
+    leader.mqtt.heartbeat().await.unwrap();
+    eventually("leadership", || async { leader.mqtt.is_leader().await }).await;
+
+    // Far more replies than fit in the client's request queue
+    let nodes: Vec<String> = (0..50).map(|i| format!("node{}", i)).collect();
+    for node in nodes.iter() {
+        recorder
+            .publish(&format!("corvus/nodes/{}/avty", node), "offline")
+            .await;
+    }
+    for node in nodes.iter() {
+        recorder
+            .wait_for_payload(&format!("homie/{}/$state", node), "lost")
+            .await;
+    }
+    leader.mqtt.heartbeat().await.unwrap();
+}
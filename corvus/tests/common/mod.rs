@@ -0,0 +1,200 @@
+//! Shared harness for the integration tests. Every test gets its own embedded broker on a
+//! free port, apps are started against it from a TOML snippet and a `Recorder` client
+//! captures everything published. Remote agents are faked by publishing what their plugins
+//! would.
+
+#![allow(dead_code)]
+
+use corvus::{App, Broker, Configuration, MQTTConfiguration};
+use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
+use std::{
+    future::Future,
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("no free port")
+        .port()
+}
+
+/// Start an embedded broker and return its port
+pub async fn broker() -> u16 {
+    let port = free_port();
+    let config = MQTTConfiguration {
+        port,
+        ..Default::default()
+    };
+    Broker::default().start(&config).await.unwrap();
+    port
+}
+
+/// Configuration of an agent at `location`. `extra` follows the `[mqtt]` table, so it may
+/// start with further MQTT settings.
+pub fn config(location: &str, port: u16, extra: &str) -> Arc<Configuration> {
+    Configuration::from_toml(&format!(
+        r#"
+[node]
+location = "{location}"
+
+[mqtt]
+client_id = "{location}"
+host = "127.0.0.1"
+port = {port}
+
+{extra}
+"#,
+        location = location,
+        port = port,
+        extra = extra
+    ))
+    .unwrap()
+}
+
+/// Start every service of an app and wait for its broker connection
+pub async fn start_app(config: Arc<Configuration>) -> App {
+    let app = App::from_configuration(config).await.unwrap();
+    app.start_services().await.unwrap();
+    let mqtt = app.mqtt.clone();
+    eventually("the app to connect", || {
+        let mqtt = mqtt.clone();
+        async move { mqtt.is_connected() }
+    })
+    .await;
+    app
+}
+
+/// Run the plugin heartbeats, which register their devices, instead of waiting for them
+pub async fn register_devices(app: &App) {
+    for plugin in app.plugin_manager.list_plugins().await {
+        plugin.heartbeat().await.unwrap();
+    }
+}
+
+/// Poll `f` until it returns true, panicking after `TIMEOUT`
+pub async fn eventually<F, Fut>(what: &str, mut f: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let start = Instant::now();
+    while !f().await {
+        if start.elapsed() > TIMEOUT {
+            panic!("Timed out waiting for {}", what);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// MQTT client recording every message on the broker
+#[derive(Clone)]
+pub struct Recorder {
+    client:   AsyncClient,
+    messages: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl Recorder {
+    pub async fn connect(port: u16) -> Self {
+        let id = format!("recorder-{}", CLIENTS.fetch_add(1, Ordering::SeqCst));
+        let (client, mut eventloop) =
+            AsyncClient::new(MqttOptions::new(id, "127.0.0.1", port), 100);
+        let messages: Arc<Mutex<Vec<(String, String)>>> = Default::default();
+        let subscribed = Arc::new(AtomicUsize::new(0));
+        let (recorded, acked) = (messages.clone(), subscribed.clone());
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Incoming::Publish(p))) => {
+                        let payload = String::from_utf8_lossy(&p.payload).to_string();
+                        recorded.lock().unwrap().push((p.topic, payload));
+                    }
+                    Ok(Event::Incoming(Incoming::SubAck(_))) => {
+                        acked.fetch_add(1, Ordering::SeqCst);
+                    }
+                    Ok(_) => (),
+                    Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+                }
+            }
+        });
+        client.subscribe("#", QoS::AtLeastOnce).await.unwrap();
+        eventually("the recorder to subscribe", || {
+            let subscribed = subscribed.clone();
+            async move { subscribed.load(Ordering::SeqCst) > 0 }
+        })
+        .await;
+        Recorder { client, messages }
+    }
+
+    pub async fn publish(&self, topic: &str, payload: &str) {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await
+            .unwrap();
+    }
+
+    /// Most recent payload seen on `topic`
+    pub fn last(&self, topic: &str) -> Option<String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(t, _)| t == topic)
+            .map(|(_, p)| p.to_string())
+    }
+
+    /// Every payload seen on `topic`, oldest first
+    pub fn all(&self, topic: &str) -> Vec<String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(t, _)| t == topic)
+            .map(|(_, p)| p.to_string())
+            .collect()
+    }
+
+    /// Wait for a message on `topic` and return its payload
+    pub async fn wait_for(&self, topic: &str) -> String {
+        eventually(topic, || async move { self.last(topic).is_some() }).await;
+        self.last(topic).unwrap()
+    }
+
+    /// Wait until the latest message on `topic` is `payload`
+    pub async fn wait_for_payload(&self, topic: &str, payload: &str) {
+        let what = format!("'{}' on {}", payload, topic);
+        eventually(&what, || async move {
+            self.last(topic).as_deref() == Some(payload)
+        })
+        .await;
+    }
+
+    /// Pretend to be the bluetooth plugin `plugin` of the agent at `location` reporting a
+    /// reading for `mac`
+    pub async fn fake_rssi(&self, location: &str, plugin: &str, mac: &str, rssi: i8) {
+        let id = format!("{} {} {}", location, plugin, mac)
+            .to_lowercase()
+            .replace(":", "")
+            .replace(" ", "_");
+        let topic = format!("corvus/nodes/{}/{}_{}/attr", location, location, id);
+        let attr = serde_json::json!({
+            "rssi": rssi,
+            "mac_address": mac,
+            "timestamp": "2020-01-01T00:00:00Z",
+            "rssi_type": "single_reading",
+            "corvus_location": location,
+            "corvus_plugin": plugin,
+        });
+        self.publish(&topic, &attr.to_string()).await;
+    }
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use common::*;
+use serde_json::Value;
+
+const ANSWER: &str = r#"
+[[plugin]]
+name = "answer"
+trigger = { on_start = true }
+
+[plugin.definition]
+type = "command"
+command = "echo"
+args = ["42"]
+"#;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishes_hass_discovery() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let app = start_app(config("kitchen", port, ANSWER)).await;
+    register_devices(&app).await;
+
+    let payload = recorder
+        .wait_for("homeassistant/sensor/corvus/kitchen_answer/config")
+        .await;
+    let discovery: Value = serde_json::from_str(&payload).unwrap();
+    assert_eq!(discovery["name"], "answer");
+    assert_eq!(discovery["uniq_id"], "kitchen_answer");
+    assert_eq!(discovery["~"], "corvus/nodes/kitchen/kitchen_answer/");
+    assert_eq!(discovery["stat_t"], "~stat");
+    assert_eq!(discovery["json_attr_t"], "~attr");
+    assert_eq!(discovery["avty_t"], "corvus/nodes/kitchen/avty");
+    assert_eq!(discovery["dev"]["name"], "kitchen");
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/avty", "online")
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishes_state_and_attributes() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let app = start_app(config("kitchen", port, ANSWER)).await;
+    register_devices(&app).await;
+
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_answer/stat", "42")
+        .await;
+    let attr = recorder
+        .wait_for("corvus/nodes/kitchen/kitchen_answer/attr")
+        .await;
+    let attr: Value = serde_json::from_str(&attr).unwrap();
+    assert_eq!(attr["corvus_plugin"], "answer");
+    assert_eq!(attr["corvus_location"], "kitchen");
+    assert!(attr["update_timestamp"].is_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishes_homie_devices() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let extra = format!("discovery_format = [\"homie\"]\n{}", ANSWER);
+    let app = start_app(config("kitchen", port, &extra)).await;
+    register_devices(&app).await;
+
+    recorder
+        .wait_for_payload("homie/kitchen/answer/answer", "42")
+        .await;
+    assert_eq!(recorder.last("homie/kitchen/$homie").unwrap(), "4.0.0");
+    assert_eq!(recorder.last("homie/kitchen/$nodes").unwrap(), "answer");
+    assert_eq!(
+        recorder.last("homie/kitchen/answer/$properties").unwrap(),
+        "answer"
+    );
+    assert_eq!(
+        recorder
+            .last("homie/kitchen/answer/answer/$datatype")
+            .unwrap(),
+        "string"
+    );
+    recorder
+        .wait_for_payload("homie/kitchen/$state", "ready")
+        .await;
+    assert!(recorder
+        .last("homeassistant/sensor/corvus/kitchen_answer/config")
+        .is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn homie_devices_stay_ready_when_devices_are_added() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let second = ANSWER.replace("answer", "second");
+    let extra = format!("discovery_format = [\"homie\"]\n{}{}", ANSWER, second);
+    let app = start_app(config("kitchen", port, &extra)).await;
+    register_devices(&app).await;
+
+    recorder
+        .wait_for_payload("homie/kitchen/$nodes", "answer,second")
+        .await;
+    assert_eq!(recorder.all("homie/kitchen/$state"), ["init", "ready"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn homie_devices_are_lost_with_their_agent() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let path = std::env::temp_dir().join(format!("corvus-homie-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        format!(
+            "[node]\nlocation = \"kitchen\"\n[mqtt]\nhost = \"127.0.0.1\"\nport = {}\n\
+             discovery_format = [\"homie\"]\n{}",
+            port, ANSWER
+        ),
+    )
+    .unwrap();
+    let mut agent = tokio::process::Command::new(env!("CARGO_BIN_EXE_corvus"))
+        .arg("-c")
+        .arg(&path)
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    // Devices are only announced with the first plugin heartbeat, 10 seconds in
+    let state = "homie/kitchen/$state";
+    let ready = async {
+        while recorder.last(state).as_deref() != Some("ready") {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    };
+    tokio::time::timeout(TIMEOUT * 2, ready)
+        .await
+        .expect("the agent did not announce its devices");
+    agent.kill().await.unwrap();
+    recorder.wait_for_payload(state, "lost").await;
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn homie_devices_are_disconnected_on_shutdown() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let extra = format!("discovery_format = [\"homie\"]\n{}", ANSWER);
+    let app = start_app(config("kitchen", port, &extra)).await;
+    register_devices(&app).await;
+    recorder
+        .wait_for_payload("homie/kitchen/$state", "ready")
+        .await;
+
+    app.mqtt.shutdown(TIMEOUT).await.unwrap();
+    recorder
+        .wait_for_payload("homie/kitchen/$state", "disconnected")
+        .await;
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    assert_eq!(
+        recorder.last("homie/kitchen/$state").as_deref(),
+        Some("disconnected")
+    );
+}
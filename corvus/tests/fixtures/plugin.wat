@@ -0,0 +1,91 @@
+;; Minimal component for the `plugin` world. `heartbeat` declares the sensor "Answer",
+;; `run` publishes 42, `process-command` publishes the value it receives and sleeps for a
+;; second and `process-update` never returns.
+(component
+  (import "corvus:plugin/host@0.1.0" (instance $host
+    (export "register-device" (func
+      (param "name" string) (param "device-type" string)
+      (param "device-class" (option string)) (param "unit" (option string))
+      (result (result (error string)))))
+    (export "publish" (func
+      (param "name" string) (param "value" string) (param "attributes" string)
+      (result (result (error string)))))
+    (export "sleep" (func (param "millis" u32)))
+  ))
+
+  (core module $libc
+    (memory (export "memory") 1)
+    (global $bump (mut i32) (i32.const 4096))
+    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+      (local $ptr i32)
+      (local.set $ptr
+        (i32.and
+          (i32.add (global.get $bump) (i32.sub (local.get 2) (i32.const 1)))
+          (i32.sub (i32.const 0) (local.get 2))))
+      (global.set $bump (i32.add (local.get $ptr) (local.get 3)))
+      (local.get $ptr))
+  )
+  (core instance $libc (instantiate $libc))
+
+  (core func $register (canon lower (func $host "register-device")
+    (memory (core memory $libc "memory")) (realloc (core func $libc "realloc"))))
+  (core func $publish (canon lower (func $host "publish")
+    (memory (core memory $libc "memory")) (realloc (core func $libc "realloc"))))
+  (core func $sleep (canon lower (func $host "sleep")))
+
+  (core module $main
+    (import "libc" "memory" (memory 1))
+    (import "host" "register-device"
+      (func $register (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32)))
+    (import "host" "publish" (func $publish (param i32 i32 i32 i32 i32 i32 i32)))
+    (import "host" "sleep" (func $sleep (param i32)))
+    (data (i32.const 0) "Answer")
+    (data (i32.const 16) "sensor")
+    (data (i32.const 32) "x")
+    (data (i32.const 48) "42")
+    (data (i32.const 64) "{}")
+    ;; Host results share the layout of our own, so they are returned as is
+    (func (export "heartbeat") (result i32)
+      (call $register
+        (i32.const 0) (i32.const 6) (i32.const 16) (i32.const 6)
+        (i32.const 0) (i32.const 0) (i32.const 0)
+        (i32.const 1) (i32.const 32) (i32.const 1)
+        (i32.const 1024))
+      (i32.const 1024))
+    (func (export "run") (result i32)
+      (call $publish
+        (i32.const 0) (i32.const 6) (i32.const 48) (i32.const 2) (i32.const 64) (i32.const 2)
+        (i32.const 1024))
+      (i32.const 1024))
+    (func (export "process-update") (param i32 i32) (result i32)
+      (loop $spin (br $spin))
+      (i32.const 1024))
+    (func (export "process-command") (param i32 i32 i32 i32) (result i32)
+      (call $publish
+        (local.get 0) (local.get 1) (local.get 2) (local.get 3) (i32.const 64) (i32.const 2)
+        (i32.const 1024))
+      (call $sleep (i32.const 1000))
+      (i32.const 1024))
+  )
+  (core instance $main (instantiate $main
+    (with "libc" (instance $libc))
+    (with "host" (instance
+      (export "register-device" (func $register))
+      (export "publish" (func $publish))
+      (export "sleep" (func $sleep))))
+  ))
+
+  (func (export "run") (result (result (error string)))
+    (canon lift (core func $main "run")
+      (memory (core memory $libc "memory")) (realloc (core func $libc "realloc"))))
+  (func (export "heartbeat") (result (result (error string)))
+    (canon lift (core func $main "heartbeat")
+      (memory (core memory $libc "memory")) (realloc (core func $libc "realloc"))))
+  (func (export "process-update") (param "data" string) (result (result (error string)))
+    (canon lift (core func $main "process-update")
+      (memory (core memory $libc "memory")) (realloc (core func $libc "realloc"))))
+  (func (export "process-command") (param "name" string) (param "value" string)
+    (result (result (error string)))
+    (canon lift (core func $main "process-command")
+      (memory (core memory $libc "memory")) (realloc (core func $libc "realloc"))))
+)
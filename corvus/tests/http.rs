@@ -0,0 +1,92 @@
+mod common;
+
+use common::*;
+use corvus::App;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Start an agent serving the status API and return its address
+async fn start() -> (App, String) {
+    let port = broker().await;
+    let bind = format!("127.0.0.1:{}", free_port());
+    let extra = format!("[http]\nbind = \"{}\"\n", bind);
+    (start_app(config("kitchen", port, &extra)).await, bind)
+}
+
+/// Send `request` as is and return the status code, headers and body of the response
+async fn request(addr: &str, request: &[u8]) -> (u16, String, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, head.to_string(), body.to_string())
+}
+
+async fn get(addr: &str, path: &str) -> (u16, String, String) {
+    let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    request(addr, req.as_bytes()).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_health() {
+    let (_app, addr) = start().await;
+    let (status, head, body) = get(&addr, "/health").await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(head.contains("Content-Type: application/json"), "{}", head);
+    let health: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(health["healthy"], true);
+    assert_eq!(health["mqtt_connected"], true);
+    assert_eq!(health["failing_services"], Value::Array(vec![]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn serves_prometheus_metrics() {
+    let (_app, addr) = start().await;
+    let (status, head, body) = get(&addr, "/metrics").await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(
+        head.contains("Content-Type: text/plain; version=0.0.4"),
+        "{}",
+        head
+    );
+    assert!(
+        body.contains("# TYPE corvus_mqtt_connected gauge\ncorvus_mqtt_connected 1\n"),
+        "{}",
+        body
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rejects_bad_requests() {
+    let (_app, addr) = start().await;
+
+    let (status, _, body) = get(&addr, "/nothing").await;
+    assert_eq!(status, 404, "{}", body);
+
+    let (status, ..) = request(&addr, b"DELETE /health HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 405);
+
+    for line in [
+        "nonsense",
+        "GET /health",
+        "GET health HTTP/1.1",
+        "GET / HTTP/1.1 x",
+    ]
+    .iter()
+    {
+        let (status, _, body) = request(&addr, format!("{}\r\n\r\n", line).as_bytes()).await;
+        assert_eq!(status, 400, "{}: {}", line, body);
+    }
+
+    // Just over the limit without ending the headers, so all of it is read
+    let mut oversized = b"GET /health HTTP/1.1\r\nX-Padding: ".to_vec();
+    oversized.resize(8193, b'a');
+    let (status, _, body) = request(&addr, &oversized).await;
+    assert_eq!(status, 431, "{}", body);
+}
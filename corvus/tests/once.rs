@@ -0,0 +1,116 @@
+mod common;
+
+use common::*;
+use std::{path::PathBuf, process::Output};
+use tokio::process::Command;
+
+const PLUGINS: &str = r#"
+[[plugin]]
+name = "answer"
+
+[plugin.definition]
+type = "command"
+command = "echo"
+args = ["42"]
+"#;
+
+/// Run the corvus binary with `args` against a configuration file holding `config`
+async fn corvus(name: &str, config: &str, args: &[&str]) -> Output {
+    corvus_with_env(name, config, args, &[]).await
+}
+
+async fn corvus_with_env(name: &str, config: &str, args: &[&str], env: &[(&str, &str)]) -> Output {
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("corvus-{}-{}.toml", name, std::process::id()));
+    std::fs::write(&path, config).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_corvus"))
+        .args(args)
+        .arg("-c")
+        .arg(&path)
+        .envs(env.iter().cloned())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(TIMEOUT * 3, output)
+        .await
+        .expect("corvus did not exit")
+        .unwrap();
+    std::fs::remove_file(&path).ok();
+    output
+}
+
+fn toml(port: u16) -> String {
+    format!(
+        "[node]\nlocation = \"kitchen\"\n[mqtt]\nhost = \"127.0.0.1\"\nport = {}\n{}",
+        port, PLUGINS
+    )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn once_publishes_and_exits() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let output = corvus("once", &toml(port), &["--once"]).await;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_answer/stat", "42")
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn once_fails_when_a_plugin_fails() {
+    let port = broker().await;
+    let config = toml(port).replace("\"echo\"", "\"/nonexistent/corvus-test\"");
+    let output = corvus("failing", &config, &["--once"]).await;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("1 of 1 plugins failed"), "{}", stderr);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dry_run_prints_instead_of_publishing() {
+    // Nothing listens on this port, a dry run never connects
+    let output = corvus("dry-run", &toml(1), &["--dry-run", "--once"]).await;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("corvus/nodes/kitchen/kitchen_answer/stat (qos 1"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("\n42\n"), "{}", stdout);
+    assert!(
+        stdout.contains("homeassistant/sensor/corvus/kitchen_answer/config"),
+        "{}",
+        stdout
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn warnings_while_loading_the_configuration_are_logged() {
+    let output = corvus_with_env(
+        "unknown-override",
+        &toml(1),
+        &["--dry-run", "--once"],
+        &[("CORVUS_NODE_LOCATOIN", "hall")],
+    )
+    .await;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Ignoring CORVUS_NODE_LOCATOIN"),
+        "{}",
+        stderr
+    );
+}
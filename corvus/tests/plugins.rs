@@ -0,0 +1,248 @@
+mod common;
+
+use common::*;
+use corvus::*;
+use serde::Deserialize;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConstantOptions {
+    value: String,
+}
+
+/// Reports a configured value
+#[derive(Debug)]
+struct Constant {
+    context: PluginContext,
+    value:   String,
+}
+
+impl PluginType for Constant {
+    type Options = ConstantOptions;
+
+    fn new(context: PluginContext, options: ConstantOptions) -> Result<Self> {
+        Ok(Constant {
+            context,
+            value: options.value,
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for Constant {
+    async fn run(&self, name: String) -> Result<()> {
+        let update = DeviceUpdate {
+            device: self.context.device_registry.get_by_name(&name).await,
+            value:  self.value.as_str().into(),
+            attr:   Document::Unit,
+        };
+        self.context.sinks.update_device(&update).await
+    }
+
+    async fn heartbeat(&self, name: String) -> Result<()> {
+        let registry = &self.context.device_registry;
+        let device = registry
+            .new_device(
+                name.to_string(),
+                DeviceType::Sensor(SensorDeviceClass::None),
+                name,
+            )
+            .build();
+        registry.register(device).await?;
+        Ok(())
+    }
+
+    async fn leader_heartbeat(&self, _: String, _: ClusterNodes) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Updates received by every `Scanner`
+static SCANNED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScannerOptions {}
+
+/// Scans until stopped, like the bluetooth plugin
+#[derive(Debug)]
+struct Scanner;
+
+impl PluginType for Scanner {
+    type Options = ScannerOptions;
+
+    fn new(_: PluginContext, _: ScannerOptions) -> Result<Self> {
+        Ok(Scanner)
+    }
+}
+
+#[async_trait]
+impl Plugin for Scanner {
+    async fn run(&self, _: String) -> Result<()> {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn heartbeat(&self, _: String) -> Result<()> {
+        Ok(())
+    }
+
+    async fn leader_heartbeat(&self, _: String, _: ClusterNodes) -> Result<()> {
+        Ok(())
+    }
+
+    async fn process_update(&self, _: Document) -> Result<()> {
+        SCANNED.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+fn register() {
+    Corvus::builder()
+        .register_plugin::<Constant>("constant")
+        .register_plugin::<Scanner>("scanner")
+        .register()
+        .unwrap();
+}
+
+const CONSTANT: &str = r#"
+[[plugin]]
+name = "answer"
+trigger = { on_start = true }
+
+[plugin.definition]
+type = "constant"
+value = "42"
+"#;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn runs_registered_plugins() {
+    register();
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let app = start_app(config("kitchen", port, CONSTANT)).await;
+    register_devices(&app).await;
+
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_answer/stat", "42")
+        .await;
+}
+
+fn constants(names: &[&str]) -> String {
+    names
+        .iter()
+        .map(|name| CONSTANT.replace("\"answer\"", &format!("\"{}\"", name)))
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_unregisters_removed_plugins() {
+    register();
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let app = start_app(config("kitchen", port, &constants(&["answer", "extra"]))).await;
+    register_devices(&app).await;
+    let topic = "homeassistant/sensor/corvus/kitchen_extra/config";
+    recorder.wait_for(topic).await;
+
+    let changed = config("kitchen", port, &constants(&["answer"]));
+    app.plugin_manager.reload(&changed, &app).await.unwrap();
+
+    recorder.wait_for_payload(topic, "").await;
+    assert!(app.device_registry.get_by_name("extra").await.is_none());
+    assert!(app.device_registry.get_by_name("answer").await.is_some());
+    let plugins = app.plugin_manager.list_plugins().await;
+    let names: Vec<&str> = plugins.iter().map(|p| p.name()).collect();
+    assert_eq!(names, ["answer"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn duplicate_plugin_names_are_rejected() {
+    register();
+    let port = broker().await;
+    let config = config("kitchen", port, &constants(&["answer", "answer"]));
+    let app = App::from_configuration(config.clone()).await.unwrap();
+    for result in [
+        app.plugin_manager.init_plugins(&config, &app).await,
+        app.plugin_manager.reload(&config, &app).await,
+    ] {
+        let err = result.unwrap_err();
+        assert!(
+            err.to_string().contains("Duplicate plugin name 'answer'"),
+            "{}",
+            err
+        );
+    }
+    assert!(app.plugin_manager.list_plugins().await.is_empty());
+}
+
+#[test]
+fn rejects_invalid_definitions() {
+    register();
+    let parse = |definition: &str| {
+        let toml = format!(
+            "[node]\nlocation = \"kitchen\"\n[mqtt]\n[[plugin]]\nname = \"p\"\n\
+             [plugin.definition]\n{}",
+            definition
+        );
+        Configuration::from_toml(&toml).map_err(|e| format!("{:#}", e))
+    };
+
+    assert!(parse("type = \"constant\"\nvalue = \"1\"").is_ok());
+    let unknown = parse("type = \"constantt\"").unwrap_err();
+    assert!(
+        unknown.contains("unknown plugin type `constantt`"),
+        "{}",
+        unknown
+    );
+    assert!(unknown.contains("`constant`"), "{}", unknown);
+    let invalid = parse("type = \"constant\"\nvalue = \"1\"\ncolor = \"red\"").unwrap_err();
+    assert!(invalid.contains("color"), "{}", invalid);
+    assert!(Corvus::builder()
+        .register_plugin::<Constant>("command")
+        .register()
+        .is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn run_once_stops_plugins_that_keep_running() {
+    register();
+    let port = broker().await;
+    // Nothing is started with the app, the scanner only runs once
+    let app = start_app(config("kitchen", port, "")).await;
+    let config = config(
+        "kitchen",
+        port,
+        r#"
+[[plugin]]
+name = "scan"
+
+[plugin.definition]
+type = "scanner"
+"#,
+    );
+
+    let recorder = Recorder::connect(port).await;
+    let window = Duration::from_secs(2);
+    let run = app.plugin_manager.run_once(&config, &app, window);
+    let scanned = async {
+        // Updates from other agents reach the plugins of a single run
+        recorder
+            .fake_rssi("hall", "scan", "AA:BB:CC:DD:EE:FF", -60)
+            .await;
+        eventually("the scanner to receive the update", || async {
+            SCANNED.load(Ordering::SeqCst) > 0
+        })
+        .await;
+    };
+    let (result, _) = tokio::time::timeout(TIMEOUT, futures::future::join(run, scanned))
+        .await
+        .expect("run_once waited for the scanner");
+    result.unwrap();
+    assert_eq!(SCANNED.load(Ordering::SeqCst), 1);
+}
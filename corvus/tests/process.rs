@@ -0,0 +1,120 @@
+mod common;
+
+use common::*;
+
+/// A helper with a switch echoing commands back as its state, and a counter of triggers
+const HELPER: &str = r#"
+echo '{"type": "device", "name": "Fan", "device_type": "switch"}'
+echo '{"type": "device", "name": "Runs", "unit": "x"}'
+echo '{"type": "state", "name": "Fan", "value": "OFF"}'
+runs=0
+while read -r line; do
+    case "$line" in
+        *'"type":"trigger"'*)
+            runs=$((runs + 1))
+            echo "{\"type\": \"state\", \"name\": \"Runs\", \"value\": $runs}"
+            ;;
+        *'"type":"command"'*)
+            value=$(echo "$line" | sed 's/.*"value":"\([^"]*\)".*/\1/')
+            echo "{\"type\": \"state\", \"name\": \"Fan\", \"value\": \"$value\"}"
+            ;;
+    esac
+done
+"#;
+
+fn plugin(script: &str, max_restarts: u32) -> String {
+    format!(
+        r#"
+[[plugin]]
+name = "helper"
+trigger = {{ on_start = true }}
+
+[plugin.definition]
+type = "process"
+command = "sh"
+args = ["-c", '''{}''']
+max_restarts = {}
+"#,
+        script, max_restarts
+    )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn helpers_declare_devices_and_receive_commands() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let app = start_app(config("kitchen", port, &plugin(HELPER, 5))).await;
+
+    recorder
+        .wait_for("homeassistant/switch/corvus/kitchen_fan/config")
+        .await;
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_fan/stat", "OFF")
+        .await;
+    // The on start trigger fires every 2 seconds once the helper is up
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_runs/stat", "2")
+        .await;
+
+    let fan = app.device_registry.get_by_name("Fan").await.unwrap();
+    app.plugin_manager
+        .set_property(fan, "ON".into())
+        .await
+        .unwrap();
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_fan/stat", "ON")
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_helpers_are_given_up_on() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let script = r#"echo '{"type": "device", "name": "Flaky"}'; exit 3"#;
+    let app = start_app(config("kitchen", port, &plugin(script, 0))).await;
+
+    let topic = "homeassistant/sensor/corvus/kitchen_flaky/config";
+    recorder.wait_for(topic).await;
+    recorder.wait_for_payload(topic, "").await;
+    assert!(app.device_registry.get_by_name("Flaky").await.is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn helpers_not_reading_input_are_still_heard() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let script = r#"sleep 1
+echo '{"type": "device", "name": "Deaf"}'
+echo '{"type": "state", "name": "Deaf", "value": "here"}'
+exec sleep 60"#;
+    let app = start_app(config("kitchen", port, &plugin(script, 0))).await;
+
+    // Far more than fits in the pipe to its input
+    let helper = app.plugin_manager.list_plugins().await.remove(0);
+    for _ in 0..5000 {
+        helper.run().await.unwrap();
+    }
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_deaf/stat", "here")
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn changed_helpers_drop_their_old_devices() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let old = r#"echo '{"type": "device", "name": "Old"}'; exec sleep 60"#;
+    let app = start_app(config("kitchen", port, &plugin(old, 0))).await;
+    let topic = "homeassistant/sensor/corvus/kitchen_old/config";
+    recorder.wait_for(topic).await;
+
+    let new = r#"echo '{"type": "device", "name": "New"}'; exec sleep 60"#;
+    let changed = config("kitchen", port, &plugin(new, 0));
+    app.plugin_manager.reload(&changed, &app).await.unwrap();
+
+    recorder.wait_for_payload(topic, "").await;
+    recorder
+        .wait_for("homeassistant/sensor/corvus/kitchen_new/config")
+        .await;
+    assert!(app.device_registry.get_by_name("Old").await.is_none());
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use common::*;
+
+const PLUGINS: &str = r#"
+[[plugin]]
+name = "answer"
+trigger = { on_start = true }
+
+[plugin.definition]
+type = "command"
+command = "echo"
+args = ["42"]
+
+[[plugin]]
+name = "glue"
+trigger = { on_start = true }
+
+[plugin.definition]
+type = "script"
+script = '''
+device("Doubled", #{ class: "temperature", unit: "°C" });
+let answer = value("kitchen", "kitchen_answer");
+if answer != () {
+    publish("Doubled", parse_int(answer) * 2, #{ source: answer });
+}
+device("Greeting");
+publish("Greeting", command("echo", ["hello"]).stdout);
+'''
+"#;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scripts_combine_readings() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let app = start_app(config("kitchen", port, PLUGINS)).await;
+    register_devices(&app).await;
+
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_greeting/stat", "hello")
+        .await;
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_doubled/stat", "84")
+        .await;
+    let attr = recorder
+        .wait_for("corvus/nodes/kitchen/kitchen_doubled/attr")
+        .await;
+    assert!(attr.contains(r#""source":"42""#), "{}", attr);
+    let config = recorder
+        .wait_for("homeassistant/sensor/corvus/kitchen_doubled/config")
+        .await;
+    assert!(config.contains(r#""dev_cla":"temperature""#), "{}", config);
+}
+
+#[test]
+fn scripts_are_checked_when_loaded() {
+    let config = config(
+        "kitchen",
+        1883,
+        r##"
+[[plugin]]
+name = "broken"
+[plugin.definition]
+type = "script"
+script = "publish("
+"##,
+    );
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let error = runtime
+        .block_on(async {
+            let app = corvus::App::from_configuration(config).await?;
+            app.start_services().await
+        })
+        .unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("Invalid script"),
+        "{:#}",
+        error
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn commands_are_killed_after_their_timeout() {
+    let port = broker().await;
+    let app = start_app(config(
+        "kitchen",
+        port,
+        r#"
+[[plugin]]
+name = "stuck"
+trigger = { interval = 3600 }
+
+[plugin.definition]
+type = "script"
+command_timeout = 1
+script = 'command("sleep", ["30"]);'
+"#,
+    ))
+    .await;
+
+    let script = app.plugin_manager.list_plugins().await.remove(0);
+    let started = std::time::Instant::now();
+    let error = tokio::time::timeout(TIMEOUT, script.run())
+        .await
+        .expect("the command was not killed")
+        .unwrap_err();
+    assert!(started.elapsed() < TIMEOUT);
+    assert!(
+        format!("{:#}", error).contains("sleep: Timed out after 1s"),
+        "{:#}",
+        error
+    );
+}
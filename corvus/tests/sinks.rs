@@ -0,0 +1,97 @@
+mod common;
+
+use common::*;
+use corvus::*;
+use std::{
+    net::TcpListener,
+    time::{Duration, Instant},
+};
+
+async fn device(app: &App) -> Device {
+    let registry = &app.device_registry;
+    let device = registry
+        .new_device(
+            "Answer".into(),
+            DeviceType::Sensor(SensorDeviceClass::None),
+            "test".into(),
+        )
+        .build();
+    registry.register(device).await.unwrap()
+}
+
+fn update(device: &Device, value: &str) -> DeviceUpdate {
+    DeviceUpdate {
+        device: Some(device.clone()),
+        value:  value.into(),
+        attr:   Document::Unit,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_sinks_do_not_delay_mqtt() {
+    // Accepts connections and never answers them
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let sink = format!(
+        r#"
+[[sink]]
+name = "slow"
+
+[sink.definition]
+type = "webhook"
+url = "http://{}/"
+"#,
+        listener.local_addr().unwrap()
+    );
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let app = start_app(config("kitchen", port, &sink)).await;
+    let device = device(&app).await;
+
+    let start = Instant::now();
+    for value in ["1", "2", "3"].iter() {
+        app.sinks
+            .update_device(&update(&device, value))
+            .await
+            .unwrap();
+    }
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "{:?}",
+        start.elapsed()
+    );
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_answer/stat", "3")
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn file_sinks_receive_updates() {
+    let path = std::env::temp_dir().join(format!("corvus-sink-{}.ndjson", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let sink = format!(
+        r#"
+[[sink]]
+name = "log"
+
+[sink.definition]
+type = "file"
+path = "{}"
+"#,
+        path.display()
+    );
+    let port = broker().await;
+    let app = start_app(config("kitchen", port, &sink)).await;
+    let device = device(&app).await;
+    app.sinks
+        .update_device(&update(&device, "42"))
+        .await
+        .unwrap();
+
+    eventually("the update in the file", || async {
+        std::fs::read_to_string(&path)
+            .map(|s| s.contains(r#""value":"42""#))
+            .unwrap_or_default()
+    })
+    .await;
+    std::fs::remove_file(&path).ok();
+}
@@ -0,0 +1,26 @@
+//! Expiry against tokio's paused clock
+
+use corvus::RollingVec;
+use std::time::Duration;
+use tokio::time::{pause, sleep};
+
+// The paused clock jumps straight to the next timer whenever the runtime is idle, so these
+// sleeps take no real time and timers always fire in order.
+
+#[tokio::test]
+async fn rolling_vec_expires_entries() {
+    pause();
+    let readings = RollingVec::new(Duration::from_secs(60));
+    readings.add(1).await;
+    sleep(Duration::from_secs(30)).await;
+    readings.add(2).await;
+    assert_eq!(readings.get_all().await.len(), 2);
+
+    sleep(Duration::from_secs(31)).await;
+    let remaining = readings.get_all().await;
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(*remaining[0], 2);
+
+    sleep(Duration::from_secs(30)).await;
+    assert!(readings.get_latest().await.is_none());
+}
@@ -0,0 +1,66 @@
+#![cfg(target_pointer_width = "64")]
+
+mod common;
+
+use common::*;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+fn plugin() -> String {
+    format!(
+        r#"
+[[plugin]]
+name = "component"
+trigger = {{ interval = 3600 }}
+
+[plugin.definition]
+type = "wasm"
+path = "{}/tests/fixtures/plugin.wat"
+fuel = 100000
+"#,
+        env!("CARGO_MANIFEST_DIR")
+    )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn components_publish_through_the_host() {
+    let port = broker().await;
+    let recorder = Recorder::connect(port).await;
+    let app = start_app(config("kitchen", port, &plugin())).await;
+    register_devices(&app).await;
+
+    let discovery = recorder
+        .wait_for("homeassistant/sensor/corvus/kitchen_answer/config")
+        .await;
+    assert!(discovery.contains(r#""unit_of_meas":"x""#), "{}", discovery);
+
+    let component = app.plugin_manager.list_plugins().await.remove(0);
+    component.run().await.unwrap();
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_answer/stat", "42")
+        .await;
+
+    // Running out of fuel traps, the next call gets a new instance
+    let error = component
+        .process_update(Default::default())
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", error).contains("fuel"), "{:#}", error);
+    // Published before the component sleeps, so it shows up before the call returns
+    let answer = app.device_registry.get_by_name("Answer").await.unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let call = tokio::spawn({
+        let done = done.clone();
+        async move {
+            component.set_property(answer, "7".into()).await.unwrap();
+            done.store(true, Ordering::SeqCst);
+        }
+    });
+    recorder
+        .wait_for_payload("corvus/nodes/kitchen/kitchen_answer/stat", "7")
+        .await;
+    assert!(!done.load(Ordering::SeqCst));
+    call.await.unwrap();
+}